@@ -0,0 +1,211 @@
+// the admin socket: what an operator changes on a running server.
+//
+// a unix socket that takes one command per line and answers each with one line, `ok ...` or
+// `error ...`. who may use it is up to the file's permissions; it is created 0600 and is off
+// unless MyConfig::admin_socket names it.
+//
+//   diag on <filter>    qlog and keylog for the clients the filter matches, see diag::Filter
+//   diag off <filter>
+//   diag list
+//   altsvc                 what tls clients are told about quic, see quic::altsvc
+//   altsvc withdraw        stop advertising quic, and tell clients to forget it
+//   altsvc restore
+//   altsvc max-age <secs>  how long clients remember it; 0 stops sending the header
+//
+// e.g. `echo 'diag on addr 192.0.2.7' | nc -U admin.sock`
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{fs::PermissionsExt, net::UnixListener, net::UnixStream},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use log::*;
+
+use crate::diag::{Diagnostics, Filter};
+use crate::quic::altsvc::AltSvc;
+
+pub struct Admin {
+    diagnostics: Arc<Diagnostics>,
+    alt_svc: Arc<AltSvc>,
+}
+
+impl Admin {
+    pub fn new(diagnostics: Arc<Diagnostics>, alt_svc: Arc<AltSvc>) -> Self {
+        Self {
+            diagnostics,
+            alt_svc,
+        }
+    }
+
+    // one command, and what to answer
+    pub fn command(&self, line: &str) -> Result<String, String> {
+        let line = line.trim();
+        let (what, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let (verb, rest) = rest.trim().split_once(' ').unwrap_or((rest.trim(), ""));
+        match (what, verb) {
+            ("diag", "on" | "off") => {
+                let filter = Filter::parse(rest).ok_or_else(|| format!("bad filter: {}", rest))?;
+                match verb {
+                    "on" => self.diagnostics.enable(filter),
+                    _ => self.diagnostics.disable(&filter),
+                }
+                Ok(String::new())
+            }
+            ("diag", "list") if rest.is_empty() => {
+                let filters = self.diagnostics.filters();
+                Ok(filters
+                    .iter()
+                    .map(|f| format!("[{}]", f))
+                    .collect::<Vec<_>>()
+                    .join(" "))
+            }
+            ("altsvc", "") => Ok(self.alt_svc.state()),
+            ("altsvc", "withdraw") if rest.is_empty() => {
+                self.alt_svc.withdraw();
+                Ok(self.alt_svc.state())
+            }
+            ("altsvc", "restore") if rest.is_empty() => {
+                self.alt_svc.restore();
+                Ok(self.alt_svc.state())
+            }
+            ("altsvc", "max-age") => {
+                let secs = rest.parse().map_err(|_| format!("bad max-age: {}", rest))?;
+                self.alt_svc.set_max_age(Duration::from_secs(secs));
+                Ok(self.alt_svc.state())
+            }
+            _ => Err(format!("unknown command: {}", line)),
+        }
+    }
+
+    // takes connections on a thread of its own, one at a time
+    pub fn serve(self: Arc<Self>, path: &Path) -> io::Result<std::thread::JoinHandle<()>> {
+        // left over from a server that didn't shut down cleanly
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        info!("admin socket at {}", path.display());
+        std::thread::Builder::new()
+            .name("admin".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let r = stream.and_then(|s| self.session(s));
+                    if let Err(e) = r {
+                        warn!("admin socket: {}", e);
+                    }
+                }
+            })
+    }
+
+    fn session(&self, stream: UnixStream) -> io::Result<()> {
+        let mut out = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let answer = match self.command(&line) {
+                Ok(s) if s.is_empty() => "ok".to_string(),
+                Ok(s) => format!("ok {}", s),
+                Err(e) => format!("error {}", e),
+            };
+            writeln!(out, "{}", answer)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::*;
+
+    fn admin() -> (Arc<Diagnostics>, Admin) {
+        let diagnostics = Arc::new(Diagnostics::new(std::env::temp_dir()));
+        let alt_svc = Arc::new(AltSvc::default());
+        (diagnostics.clone(), Admin::new(diagnostics, alt_svc))
+    }
+
+    #[test]
+    fn diag_commands() {
+        let (diagnostics, admin) = admin();
+        let ip = "192.0.2.7".parse::<IpAddr>().unwrap();
+        assert_eq!(admin.command("diag on addr 192.0.2.7"), Ok(String::new()));
+        assert_eq!(admin.command(" diag on  cid 0a0b \n"), Ok(String::new()));
+        assert_eq!(
+            diagnostics.filters(),
+            [Filter::Addr(ip), Filter::Cid(vec![10, 11])]
+        );
+        assert_eq!(
+            admin.command("diag list"),
+            Ok("[addr 192.0.2.7] [cid 0a0b]".to_string())
+        );
+        assert_eq!(admin.command("diag off addr 192.0.2.7"), Ok(String::new()));
+        assert_eq!(diagnostics.filters(), [Filter::Cid(vec![10, 11])]);
+        for bad in [
+            "",
+            "diag",
+            "diag on",
+            "diag on addr x",
+            "diag list all",
+            "reboot",
+        ] {
+            assert!(admin.command(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn altsvc_commands() {
+        let diagnostics = Arc::new(Diagnostics::new(std::env::temp_dir()));
+        let alt_svc = Arc::new(AltSvc::default());
+        let admin = Admin::new(diagnostics, alt_svc.clone());
+        let _l = alt_svc.listening(4433);
+        let ok = |s: &str| Ok(s.to_string());
+        assert_eq!(admin.command("altsvc"), ok("h3=\":4433\"; ma=86400"));
+        assert_eq!(
+            admin.command("altsvc max-age 60"),
+            ok("h3=\":4433\"; ma=60")
+        );
+        assert_eq!(alt_svc.header(), "Alt-Svc: h3=\":4433\"; ma=60\r\n");
+        assert_eq!(admin.command("altsvc withdraw"), ok("clear (withdrawn)"));
+        assert_eq!(alt_svc.header(), "Alt-Svc: clear\r\n");
+        assert_eq!(admin.command("altsvc restore"), ok("h3=\":4433\"; ma=60"));
+        for bad in [
+            "altsvc max-age",
+            "altsvc max-age -1",
+            "altsvc withdraw now",
+            "altsvc on",
+        ] {
+            assert!(admin.command(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn socket() {
+        let (diagnostics, admin) = admin();
+        let path =
+            std::env::temp_dir().join(format!("simpleweb-admin-{}.sock", std::process::id()));
+        Arc::new(admin).serve(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client
+            .write_all(b"diag on all\n\nbogus\ndiag list\n")
+            .unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let lines = BufReader::new(client)
+            .lines()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(lines, ["ok", "error unknown command: bogus", "ok [all]"]);
+        assert_eq!(diagnostics.filters(), [Filter::All]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+// the parameter block's integer encodings against each other.
+//
+//   cargo run --release --bin intbench [count]
+//
+// for a few spreads of values: the bytes each encoding takes, and how long encoding and
+// decoding a run of `count` of them takes, per value.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use simpleweb::param::{int, IntEncoding};
+
+const ENCODINGS: [IntEncoding; 3] = [IntEncoding::Fixed, IntEncoding::Varint, IntEncoding::Group];
+
+pub fn main() {
+    let count = std::env::args()
+        .nth(1)
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(4096);
+
+    let spreads: [(&str, fn(&mut Rng) -> u64); 4] = [
+        // lengths of short strings, small ids
+        ("small", |r| r.next() % 200),
+        // mostly small with the odd big one, like lengths with a blob among them
+        ("mixed", |r| match r.next() % 8 {
+            0 => r.next() >> 20,
+            _ => r.next() % 5000,
+        }),
+        // every size equally likely
+        ("bits", |r| r.next() >> (r.next() % 64)),
+        // hashes, floats
+        ("large", |r| r.next() | 1 << 63),
+    ];
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+
+    println!(
+        "{:<6} {:<7} {:>10} {:>12} {:>12}",
+        "values", "enc", "bytes", "encode ns", "decode ns"
+    );
+    for (name, spread) in spreads {
+        let values = (0..count).map(|_| spread(&mut rng)).collect::<Vec<_>>();
+        for enc in ENCODINGS {
+            let mut buf = Vec::new();
+            int::encode(enc, &values, &mut buf);
+            let mut out = Vec::with_capacity(count);
+
+            let encode = time(|| {
+                buf.clear();
+                int::encode(enc, black_box(&values), &mut buf);
+                black_box(&buf);
+            });
+            let decode = time(|| {
+                out.clear();
+                int::decode(enc, black_box(&buf), count, &mut out).unwrap();
+                black_box(&out);
+            });
+            assert_eq!(out, values);
+
+            println!(
+                "{:<6} {:<7} {:>10} {:>12.2} {:>12.2}",
+                name,
+                format!("{:?}", enc),
+                buf.len(),
+                encode.as_nanos() as f64 / count as f64,
+                decode.as_nanos() as f64 / count as f64,
+            );
+        }
+    }
+}
+
+// xorshift, the same values every run
+struct Rng(u64);
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+// the best of a few rounds of enough runs to take a while
+fn time(mut f: impl FnMut()) -> Duration {
+    let mut runs = 1;
+    loop {
+        let start = Instant::now();
+        for _ in 0..runs {
+            f();
+        }
+        if start.elapsed() > Duration::from_millis(20) {
+            break;
+        }
+        runs *= 2;
+    }
+    (0..5)
+        .map(|_| {
+            let start = Instant::now();
+            for _ in 0..runs {
+                f();
+            }
+            start.elapsed() / runs
+        })
+        .min()
+        .unwrap()
+}
@@ -3,7 +3,8 @@ use mio::net::UdpSocket;
 use mio::{Events, Interest, Poll, Token};
 use quiche::{Connection, ConnectionId};
 use ring::rand::SystemRandom;
-use simpleweb::quiche::{mint_token, validate_token, ClientIdMap};
+use simpleweb::quic::token::{TokenKeys, Tokens};
+use simpleweb::quiche::ClientIdMap;
 use std::{collections::HashMap, net::SocketAddr, time::Instant};
 use quiche::h3::NameValue;
 
@@ -234,6 +235,7 @@ fn main() {
         ring::hmac::Key::generate(ring::hmac::HMAC_SHA256, &rng).unwrap();
 
     let mut clients = ClientMap::new();
+    let mut tokens = Tokens::new(std::sync::Arc::new(TokenKeys::generate().unwrap()));
 
     let local_addr = socket.local_addr().unwrap();
 
@@ -339,7 +341,7 @@ fn main() {
                 if token.is_empty() {
                     warn!("Doing stateless retry");
 
-                    let new_token = mint_token(&hdr, &from);
+                    let new_token = tokens.mint_retry(&hdr.dcid, &from);
 
                     let len = quiche::retry(
                         &hdr.scid,
@@ -364,14 +366,15 @@ fn main() {
                     continue 'read;
                 }
 
-                let odcid = validate_token(&from, token);
-
                 // The token was not valid, meaning the retry failed, so
                 // drop the packet.
-                if odcid.is_none() {
-                    error!("Invalid address validation token");
-                    continue 'read;
-                }
+                let odcid = match tokens.validate(token, &from) {
+                    Ok(odcid) => Some(odcid),
+                    Err(e) => {
+                        error!("Invalid address validation token: {}", e.as_str());
+                        continue 'read;
+                    }
+                };
 
                 if scid.len() != hdr.dcid.len() {
                     error!("Invalid destination connection ID");
@@ -1,2 +1,484 @@
+// the blobs of a parameter block, as they come in.
+//
+// blobs may be written to disk depending on memory pressure. stored blobs are eager: they are
+// headed for storage anyway, so they are written out as they arrive and never held. temp
+// blobs are lazy: the procedure computes from them (a temp table, say) and they are gone
+// after, so they stay in memory while the connection and the thread have budget for them,
+// and spill to a temp file when either runs out. procedures read both the same way, see Blob.
+//
+// an eager blob is only stored once its procedure says so, see Blob::commit. until then its
+// file goes with the parameter block, so a statement that fails or never runs (the stream
+// was reset, the block was bad) leaves nothing behind in storage.
+//
+// a DbThread has one Pool; everything here is owned by its thread, so the budgets are plain
+// cells.
 
+use std::{
+    cell::Cell,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
+use log::*;
+
+use crate::param::{BlobSink, Section};
+
+pub struct BlobConfig {
+    // where eager blobs go. until there is a storage engine it's just a directory.
+    pub storage_dir: PathBuf,
+    // where lazy blobs spill
+    pub spill_dir: PathBuf,
+    // lazy bytes in memory, for each connection and for the whole thread
+    pub connection_budget: usize,
+    pub thread_budget: usize,
+    // the largest blob a client may send
+    pub max_blob: u64,
+}
+
+impl Default for BlobConfig {
+    fn default() -> Self {
+        Self {
+            storage_dir: std::env::temp_dir(),
+            spill_dir: std::env::temp_dir(),
+            connection_budget: 4 * 1024 * 1024,
+            thread_budget: 64 * 1024 * 1024,
+            max_blob: 1 << 30,
+        }
+    }
+}
+
+// bytes that may be in memory, and how many are
+#[derive(Clone)]
+pub struct Budget(Rc<BudgetInner>);
+
+struct BudgetInner {
+    limit: usize,
+    used: Cell<usize>,
+}
+
+impl Budget {
+    pub fn new(limit: usize) -> Self {
+        Self(Rc::new(BudgetInner {
+            limit,
+            used: Cell::new(0),
+        }))
+    }
+
+    pub fn used(&self) -> usize {
+        self.0.used.get()
+    }
+
+    pub fn left(&self) -> usize {
+        self.0.limit.saturating_sub(self.used())
+    }
+
+    fn take(&self, n: usize) {
+        self.0.used.set(self.used() + n);
+    }
+
+    fn give(&self, n: usize) {
+        self.0.used.set(self.used() - n);
+    }
+}
+
+pub struct Pool {
+    config: BlobConfig,
+    budget: Budget,
+}
+
+impl Pool {
+    pub fn new(config: BlobConfig) -> Self {
+        let budget = Budget::new(config.thread_budget);
+        Self { config, budget }
+    }
+
+    pub fn config(&self) -> &BlobConfig {
+        &self.config
+    }
+
+    // the thread's
+    pub fn budget(&self) -> &Budget {
+        &self.budget
+    }
+
+    // a budget for a connection, by the config
+    pub fn connection_budget(&self) -> Budget {
+        Budget::new(self.config.connection_budget)
+    }
+
+    // takes the blobs of one parameter block
+    pub fn spool(&self, connection: &Budget) -> Spool {
+        Spool {
+            config: SpoolConfig {
+                storage_dir: self.config.storage_dir.clone(),
+                spill_dir: self.config.spill_dir.clone(),
+                max_blob: self.config.max_blob,
+            },
+            budgets: [self.budget.clone(), connection.clone()],
+            blobs: Vec::new(),
+            received: 0,
+            error: None,
+        }
+    }
+}
+
+// what a Spool needs of the config, so it doesn't borrow the pool
+struct SpoolConfig {
+    storage_dir: PathBuf,
+    spill_dir: PathBuf,
+    max_blob: u64,
+}
+
+// a parameter block's blobs, in wire order: the stored ones then the temp ones.
+pub struct Blob {
+    eager: bool,
+    len: u64,
+    data: Data,
+}
+
+enum Data {
+    // lazy, charged to the budgets until dropped
+    Memory { buf: Vec<u8>, charge: Charge },
+    File(Spilled),
+}
+
+impl Blob {
+    // written out as it arrived, see the top
+    pub fn is_eager(&self) -> bool {
+        self.eager
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // the bytes, when they are in memory
+    pub fn bytes(&self) -> Option<&[u8]> {
+        match &self.data {
+            Data::Memory { buf, .. } => Some(buf),
+            Data::File(_) => None,
+        }
+    }
+
+    // the file, when it has one
+    pub fn path(&self) -> Option<&Path> {
+        match &self.data {
+            Data::Memory { .. } => None,
+            Data::File(f) => Some(&f.path),
+        }
+    }
+
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        match &self.data {
+            Data::Memory { buf: data, .. } => {
+                let start = (offset.min(data.len() as u64)) as usize;
+                let n = buf.len().min(data.len() - start);
+                buf[..n].copy_from_slice(&data[start..start + n]);
+                Ok(n)
+            }
+            Data::File(f) => f.file.read_at(buf, offset),
+        }
+    }
+
+    pub fn reader(&self) -> BlobReader<'_> {
+        BlobReader { blob: self, pos: 0 }
+    }
+
+    pub fn to_vec(&self) -> io::Result<Vec<u8>> {
+        let mut v = Vec::with_capacity(self.len as usize);
+        self.reader().read_to_end(&mut v)?;
+        Ok(v)
+    }
+
+    // the blob's file stays when the blob is dropped; the procedure calls this when it has
+    // stored the blob, e.g. its table refers to the path. None for a temp blob that didn't
+    // spill, which has no file to keep.
+    pub fn commit(&self) -> Option<&Path> {
+        match &self.data {
+            Data::Memory { .. } => None,
+            Data::File(f) => {
+                f.keep.set(true);
+                Some(&f.path)
+            }
+        }
+    }
+
+    pub fn is_committed(&self) -> bool {
+        matches!(&self.data, Data::File(f) if f.keep.get())
+    }
+}
+
+pub struct BlobReader<'a> {
+    blob: &'a Blob,
+    pos: u64,
+}
+
+impl Read for BlobReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.blob.read_at(self.pos, buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+// memory charged to the thread and the connection
+struct Charge {
+    budgets: [Budget; 2],
+    bytes: usize,
+}
+
+impl Charge {
+    fn add(&mut self, n: usize) {
+        self.budgets.iter().for_each(|b| b.take(n));
+        self.bytes += n;
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.budgets.iter().for_each(|b| b.give(self.bytes));
+    }
+}
+
+// a blob's file, removed with it unless kept. procedures only get the blob shared, so the
+// flag is a cell.
+struct Spilled {
+    file: File,
+    path: PathBuf,
+    keep: Cell<bool>,
+}
+
+impl Spilled {
+    fn create(dir: &Path) -> io::Result<Self> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let name = format!(
+            "simpleweb-{}-{}.blob",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = dir.join(name);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(Self {
+            file,
+            path,
+            keep: Cell::new(false),
+        })
+    }
+}
+
+impl Drop for Spilled {
+    fn drop(&mut self) {
+        if !self.keep.get() {
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                warn!("can't remove blob {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+// takes the blobs from param::Parser as they arrive. writing can fail where the parser can't
+// hear of it, so the first error is kept for the caller to take.
+pub struct Spool {
+    config: SpoolConfig,
+    // the thread's and the connection's
+    budgets: [Budget; 2],
+    blobs: Vec<Blob>,
+    // bytes of the last blob so far
+    received: u64,
+    error: Option<io::Error>,
+}
+
+impl Spool {
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    pub fn into_blobs(self) -> Box<[Blob]> {
+        self.blobs.into()
+    }
+
+    // what the tighter budget has left
+    fn left(&self) -> usize {
+        self.budgets.iter().map(|b| b.left()).min().unwrap_or(0)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        let left = self.left();
+        let Some(blob) = self.blobs.last_mut() else {
+            return Ok(());
+        };
+        if let Data::Memory { buf: data, charge } = &mut blob.data {
+            if buf.len() <= left {
+                charge.add(buf.len());
+                data.extend_from_slice(buf);
+                return Ok(());
+            }
+            // out of budget: this one goes to disk, what it had included
+            debug!("spilling a {} byte blob", blob.len);
+            let mut f = Spilled::create(&self.config.spill_dir)?;
+            f.file.write_all(data)?;
+            blob.data = Data::File(f);
+        }
+        match &mut blob.data {
+            Data::File(f) => f.file.write_all(buf),
+            Data::Memory { .. } => unreachable!(),
+        }
+    }
+}
+
+impl BlobSink for Spool {
+    fn start(&mut self, section: Section, _index: usize, len: u64) {
+        if self.error.is_some() {
+            return;
+        }
+        if len > self.config.max_blob {
+            self.error = Some(io::Error::new(
+                io::ErrorKind::FileTooLarge,
+                format!("{} byte blob", len),
+            ));
+            return;
+        }
+        self.received = 0;
+        let eager = section == Section::Stored;
+        // a lazy blob that can't fit goes to disk from the start
+        let dir = match eager {
+            true => Some(&self.config.storage_dir),
+            false => Some(&self.config.spill_dir).filter(|_| len > self.left() as u64),
+        };
+        let data = match dir {
+            Some(dir) => match Spilled::create(dir) {
+                Ok(f) => Data::File(f),
+                Err(e) => {
+                    self.error = Some(e);
+                    return;
+                }
+            },
+            None => Data::Memory {
+                buf: Vec::new(),
+                charge: Charge {
+                    budgets: self.budgets.clone(),
+                    bytes: 0,
+                },
+            },
+        };
+        self.blobs.push(Blob { eager, len, data });
+    }
+
+    fn middle(&mut self, _section: Section, _index: usize, buf: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+        self.received += buf.len() as u64;
+        if let Err(e) = self.write(buf) {
+            self.error = Some(e);
+        }
+    }
+
+    // the parser hands over what the length said, but a blob that is short or long here
+    // would be stored as it is, so it's checked rather than trusted
+    fn finish(&mut self, section: Section, index: usize) {
+        if self.error.is_some() {
+            return;
+        }
+        let Some(blob) = self.blobs.last() else {
+            return;
+        };
+        if self.received != blob.len {
+            self.error = Some(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} blob {} is {} bytes, {} were sent",
+                    section, index, blob.len, self.received
+                ),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(dir: &Path) -> Pool {
+        Pool::new(BlobConfig {
+            storage_dir: dir.to_path_buf(),
+            spill_dir: dir.to_path_buf(),
+            connection_budget: 8,
+            ..Default::default()
+        })
+    }
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("simpleweb-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn files(dir: &Path) -> usize {
+        std::fs::read_dir(dir).unwrap().count()
+    }
+
+    #[test]
+    fn commit() {
+        let dir = dir("commit");
+        let pool = pool(&dir);
+        let mut spool = pool.spool(&pool.connection_budget());
+        for (section, bytes) in [
+            (Section::Stored, &b"kept"[..]),
+            (Section::Stored, b"dropped"),
+            (Section::Temp, b"small"),
+            (Section::Temp, b"over the budget"),
+        ] {
+            spool.start(section, 0, bytes.len() as u64);
+            spool.middle(section, 0, bytes);
+            spool.finish(section, 0);
+        }
+        assert!(spool.take_error().is_none());
+        let blobs = spool.into_blobs();
+        assert_eq!(files(&dir), 3);
+        assert_eq!(blobs[2].bytes(), Some(&b"small"[..]));
+        assert_eq!(pool.budget().used(), 5);
+        assert_eq!(blobs[3].to_vec().unwrap(), b"over the budget");
+        assert!(blobs[2].commit().is_none());
+
+        let path = blobs[0].commit().unwrap().to_path_buf();
+        assert!(blobs[0].is_committed() && !blobs[1].is_committed());
+        drop(blobs);
+        assert_eq!(pool.budget().used(), 0);
+        assert_eq!(std::fs::read(&path).unwrap(), b"kept");
+        assert_eq!(files(&dir), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn length_mismatch() {
+        let dir = dir("mismatch");
+        let pool = pool(&dir);
+        for (section, sent) in [
+            (Section::Stored, &b"abc"[..]),
+            (Section::Stored, b"abcdef"),
+            (Section::Temp, b"abc"),
+        ] {
+            let mut spool = pool.spool(&pool.connection_budget());
+            spool.start(section, 0, 4);
+            spool.middle(section, 0, sent);
+            spool.finish(section, 0);
+            let e = spool.take_error().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+        assert_eq!(files(&dir), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,422 @@
+// n x n mailboxes between worker threads.
+//
+// every (from, to) pair gets its own spsc ring so there is never more than one producer or
+// one consumer touching a queue. each worker owns exactly one Endpoint; the endpoint is the
+// only producer for its row and the only consumer for its column.
+//
+// a worker spends most of its time asleep in poll (or submit_and_wait), so a waker is not
+// enough to get its attention. each worker also has an eventfd that the sender kicks when
+// the receiver has announced that it is about to sleep. the eventfd is registered with the
+// worker's reactor like any other socket.
+
+use std::{
+    cell::{Cell, UnsafeCell},
+    future::Future,
+    io,
+    marker::PhantomData,
+    mem::MaybeUninit,
+    os::fd::{AsRawFd, RawFd},
+    pin::Pin,
+    sync::{
+        atomic::{fence, AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+};
+
+// must be a power of two
+pub const MAILBOX_CAPACITY: usize = 128;
+
+// a waker that may be registered by one thread and taken by another.
+struct WakerSlot {
+    set: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+impl WakerSlot {
+    fn new() -> Self {
+        Self {
+            set: AtomicBool::new(false),
+            waker: Mutex::new(None),
+        }
+    }
+    fn register(&self, waker: &Waker) {
+        let mut slot = self.waker.lock().unwrap();
+        match slot.as_ref() {
+            Some(w) if w.will_wake(waker) => {}
+            _ => *slot = Some(waker.clone()),
+        }
+        self.set.store(true, Ordering::Release);
+    }
+    fn wake(&self) {
+        if self.set.swap(false, Ordering::AcqRel) {
+            if let Some(w) = self.waker.lock().unwrap().take() {
+                w.wake();
+            }
+        }
+    }
+}
+
+// single producer, single consumer ring. head and tail count up forever and are masked on use.
+struct Mailbox<T> {
+    slot: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    head: AtomicUsize, // next slot to read, written only by the consumer
+    tail: AtomicUsize, // next slot to write, written only by the producer
+    // the producer parks here when the ring is full.
+    sender: WakerSlot,
+}
+impl<T> Mailbox<T> {
+    fn new() -> Self {
+        let slot = (0..MAILBOX_CAPACITY)
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect::<Vec<_>>()
+            .into_boxed_slice();
+        Self {
+            slot,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            sender: WakerSlot::new(),
+        }
+    }
+    // producer only
+    unsafe fn push(&self, msg: T) -> Result<(), T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == MAILBOX_CAPACITY {
+            return Err(msg);
+        }
+        (*self.slot[tail & (MAILBOX_CAPACITY - 1)].get()).write(msg);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+    // consumer only
+    unsafe fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let msg = (*self.slot[head & (MAILBOX_CAPACITY - 1)].get()).assume_init_read();
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(msg)
+    }
+    fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+    }
+}
+impl<T> Drop for Mailbox<T> {
+    fn drop(&mut self) {
+        // we have exclusive access here, drop anything that was never received.
+        while unsafe { self.pop() }.is_some() {}
+    }
+}
+
+#[cfg(target_os = "linux")]
+struct EventFd {
+    fd: std::os::fd::OwnedFd,
+}
+#[cfg(target_os = "linux")]
+impl EventFd {
+    fn new() -> io::Result<Self> {
+        use std::os::fd::FromRawFd;
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd: unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) },
+        })
+    }
+    fn kick(&self) {
+        let one = 1u64.to_ne_bytes();
+        // EAGAIN means the counter is already saturated, which is as awake as it gets.
+        _ = unsafe { libc::write(self.fd.as_raw_fd(), one.as_ptr() as *const _, 8) };
+    }
+    fn drain(&self) {
+        let mut buf = [0u8; 8];
+        _ = unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr() as *mut _, 8) };
+    }
+    fn raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+// no eventfd outside of linux, a nonblocking socket pair does the same job.
+#[cfg(not(target_os = "linux"))]
+struct EventFd {
+    rx: std::os::unix::net::UnixStream,
+    tx: std::os::unix::net::UnixStream,
+}
+#[cfg(not(target_os = "linux"))]
+impl EventFd {
+    fn new() -> io::Result<Self> {
+        let (rx, tx) = std::os::unix::net::UnixStream::pair()?;
+        rx.set_nonblocking(true)?;
+        tx.set_nonblocking(true)?;
+        Ok(Self { rx, tx })
+    }
+    fn kick(&self) {
+        use std::io::Write;
+        _ = (&self.tx).write(&[1]);
+    }
+    fn drain(&self) {
+        use std::io::Read;
+        let mut buf = [0u8; 64];
+        while let Ok(n) = (&self.rx).read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+    }
+    fn raw_fd(&self) -> RawFd {
+        self.rx.as_raw_fd()
+    }
+}
+
+// per destination worker
+struct Notify {
+    event: EventFd,
+    // set by the receiver just before it blocks in the reactor.
+    sleeping: AtomicBool,
+    // a task on the receiving worker waiting in recv().
+    receiver: WakerSlot,
+}
+
+pub struct Matrix<T> {
+    n: usize,
+    // row major, mailbox[from * n + to]
+    mailbox: Box<[Mailbox<T>]>,
+    notify: Box<[Notify]>,
+}
+// SAFETY: mailbox[from * n + to] is pushed only by endpoint `from` and popped only by
+// endpoint `to`, and an endpoint is used by one thread at a time: it isn't Clone and isn't
+// Sync, so push and pop each have a single caller however the matrix is shared. the notify
+// fields are atomics, a mutex and an fd.
+unsafe impl<T: Send> Sync for Matrix<T> {}
+unsafe impl<T: Send> Send for Matrix<T> {}
+
+impl<T> Matrix<T> {
+    fn mailbox(&self, from: usize, to: usize) -> &Mailbox<T> {
+        &self.mailbox[from * self.n + to]
+    }
+}
+
+/// Builds the mailboxes for `n` workers and returns one endpoint per worker, in worker order.
+pub fn matrix<T: Send>(n: usize) -> io::Result<Vec<Endpoint<T>>> {
+    let mailbox = (0..n * n)
+        .map(|_| Mailbox::new())
+        .collect::<Vec<_>>()
+        .into_boxed_slice();
+    let notify = (0..n)
+        .map(|_| {
+            Ok(Notify {
+                event: EventFd::new()?,
+                sleeping: AtomicBool::new(false),
+                receiver: WakerSlot::new(),
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?
+        .into_boxed_slice();
+    let matrix = Arc::new(Matrix { n, mailbox, notify });
+    Ok((0..n)
+        .map(|id| Endpoint {
+            id,
+            matrix: matrix.clone(),
+            next: 0,
+            _not_sync: PhantomData,
+        })
+        .collect())
+}
+
+// an endpoint is deliberately not Clone; owning it is what makes the queues spsc. nor is it
+// Sync: try_send takes &self, and two threads with a &Endpoint would be two producers.
+pub struct Endpoint<T> {
+    id: usize,
+    matrix: Arc<Matrix<T>>,
+    // round robin over the senders so a chatty peer can't starve the others.
+    next: usize,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+impl<T> Endpoint<T> {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+    pub fn peers(&self) -> usize {
+        self.matrix.n
+    }
+
+    // the fd to register with mio or io_uring; readable when a peer kicked us.
+    pub fn raw_fd(&self) -> RawFd {
+        self.matrix.notify[self.id].event.raw_fd()
+    }
+
+    pub fn try_send(&self, to: usize, msg: T) -> Result<(), T> {
+        if to >= self.matrix.n {
+            return Err(msg);
+        }
+        unsafe { self.matrix.mailbox(self.id, to).push(msg)? };
+        // the push has to be visible before we look at sleeping, and prepare_sleep stores
+        // sleeping before it looks at the mailboxes; without a fence on both sides each can
+        // miss the other's store and the receiver sleeps with nobody to kick it.
+        fence(Ordering::SeqCst);
+        let notify = &self.matrix.notify[to];
+        notify.receiver.wake();
+        if notify.sleeping.swap(false, Ordering::AcqRel) {
+            notify.event.kick();
+        }
+        Ok(())
+    }
+
+    // waits for room in the peer's mailbox
+    pub fn send(&self, to: usize, msg: T) -> SendFuture<'_, T> {
+        SendFuture {
+            endpoint: self,
+            to,
+            msg: Some(msg),
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<(usize, T)> {
+        let n = self.matrix.n;
+        for i in 0..n {
+            let from = (self.next + i) % n;
+            let mailbox = self.matrix.mailbox(from, self.id);
+            if let Some(msg) = unsafe { mailbox.pop() } {
+                self.next = (from + 1) % n;
+                mailbox.sender.wake();
+                return Some((from, msg));
+            }
+        }
+        None
+    }
+
+    pub fn recv(&mut self) -> RecvFuture<'_, T> {
+        RecvFuture { endpoint: self }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        (0..self.matrix.n).all(|from| self.matrix.mailbox(from, self.id).is_empty())
+    }
+
+    // call before blocking in the reactor. returns false if messages arrived meanwhile, in
+    // which case the caller should poll with a zero timeout instead of sleeping.
+    pub fn prepare_sleep(&self) -> bool {
+        let notify = &self.matrix.notify[self.id];
+        notify.sleeping.store(true, Ordering::SeqCst);
+        // pairs with the fence in try_send
+        fence(Ordering::SeqCst);
+        if !self.is_empty() {
+            notify.sleeping.store(false, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+
+    // call after the reactor returns, whether or not our fd was the reason.
+    pub fn wakeup(&self) {
+        let notify = &self.matrix.notify[self.id];
+        notify.sleeping.store(false, Ordering::SeqCst);
+        notify.event.drain();
+    }
+}
+
+pub struct SendFuture<'a, T> {
+    endpoint: &'a Endpoint<T>,
+    to: usize,
+    msg: Option<T>,
+}
+impl<T> Unpin for SendFuture<'_, T> {}
+
+impl<T> Future for SendFuture<'_, T> {
+    // gives the message back if the destination doesn't exist
+    type Output = Result<(), T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let Some(msg) = self.msg.take() else {
+            return Poll::Ready(Ok(()));
+        };
+        if self.to >= self.endpoint.matrix.n {
+            return Poll::Ready(Err(msg));
+        }
+        let mailbox = self.endpoint.matrix.mailbox(self.endpoint.id, self.to);
+        let msg = match self.endpoint.try_send(self.to, msg) {
+            Ok(()) => return Poll::Ready(Ok(())),
+            Err(msg) => msg,
+        };
+        // register, then try again so a pop between the two can't be missed.
+        mailbox.sender.register(cx.waker());
+        match self.endpoint.try_send(self.to, msg) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(msg) => {
+                self.msg = Some(msg);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+pub struct RecvFuture<'a, T> {
+    endpoint: &'a mut Endpoint<T>,
+}
+
+impl<T> Future for RecvFuture<'_, T> {
+    type Output = (usize, T);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(v) = self.endpoint.try_recv() {
+            return Poll::Ready(v);
+        }
+        let id = self.endpoint.id;
+        self.endpoint.matrix.notify[id].receiver.register(cx.waker());
+        match self.endpoint.try_recv() {
+            Some(v) => Poll::Ready(v),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // blocks until the eventfd is readable, like the reactor would
+    fn wait(fd: RawFd) {
+        let mut pfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // a lost wakeup hangs here; the timeout turns it into a failure
+        let n = unsafe { libc::poll(&mut pfd, 1, 5000) };
+        assert_eq!(n, 1, "receiver slept through a send");
+    }
+
+    #[test]
+    fn no_lost_wakeup() {
+        const COUNT: usize = 200_000;
+        let mut endpoints = matrix::<usize>(2).unwrap();
+        let mut rx = endpoints.pop().unwrap();
+        let tx = endpoints.pop().unwrap();
+        let producer = std::thread::spawn(move || {
+            for i in 0..COUNT {
+                let mut msg = i;
+                while let Err(m) = tx.try_send(1, msg) {
+                    msg = m;
+                    std::thread::yield_now();
+                }
+            }
+        });
+        let mut next = 0;
+        while next < COUNT {
+            while let Some((from, msg)) = rx.try_recv() {
+                assert_eq!((from, msg), (0, next));
+                next += 1;
+            }
+            if next < COUNT && rx.prepare_sleep() {
+                wait(rx.raw_fd());
+            }
+            rx.wakeup();
+        }
+        producer.join().unwrap();
+    }
+}
@@ -0,0 +1,311 @@
+// calling procedures from rust: services talking to the database, and integration tests.
+//
+// a call is one stream: the 8 byte StreamHeader (env, iface, procid, continues), then the
+// parameter block, then fin. the server answers on the same stream with the result and fin,
+// or resets the stream with the procedure's error code. a statement that starts a transaction
+// (continues = 1) gets the transaction's handle, 2 bytes little endian, in front of its
+// result; later statements send it back, see exec::BEGIN.
+//
+// the transport is a webtransport session over quic, which is what the server serves to
+// browsers too. the tls listener doesn't speak rpc yet; when it does it gets a Session of its
+// own here.
+//
+// everything is blocking: `send` starts calls, `wait` drives the connection until the one
+// asked for is done, and the others keep progressing meanwhile.
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    exec::{StreamHeader, AUTOCOMMIT, BEGIN},
+    param::{IntEncoding, ParamBlockBuilder, ParamBlockSchema, ParamError},
+};
+// offered in the session's CONNECT and answered with the one the server picked
+pub use crate::exec::{CATALOG_HEADER, VERSION_HEADER};
+
+mod quic;
+use quic::Session;
+
+// the protocol versions we speak, the preferred one last
+pub const VERSIONS: &[u16] = &[1, 2, 3];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("QUIC error: {0}")]
+    Quic(#[from] quiche::Error),
+
+    #[error("HTTP/3 error: {0}")]
+    H3(#[from] quiche::h3::Error),
+
+    #[error("session refused: {0}")]
+    Refused(String),
+
+    #[error("no protocol version in common with the server")]
+    Version,
+
+    // our ids are from a later export than the server's, see exec::registry
+    #[error("the server's catalogue {0} is older than ours")]
+    Catalog(u32),
+
+    // the procedure's own error code
+    #[error("procedure failed with code {0}")]
+    Rpc(u32),
+
+    // reset with a code outside the procedure range
+    #[error("stream reset with {0:#x}")]
+    Reset(u64),
+
+    #[error("connection closed")]
+    Closed,
+
+    #[error("timed out")]
+    Timeout,
+
+    #[error("malformed result")]
+    Malformed,
+
+    #[error("parameter block: {0}")]
+    Param(#[from] ParamError),
+}
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub struct ClientConfig {
+    // for sni and certificate verification
+    pub server_name: String,
+    // the webtransport endpoint
+    pub path: String,
+    pub verify: bool,
+    // pem file of trusted roots, when the server's certificate isn't signed by a system one
+    pub ca: Option<String>,
+    pub connect_timeout: Duration,
+    // how long a call may take
+    pub call_timeout: Duration,
+    pub idle_timeout: Duration,
+    // the catalogue version the ids we call with were exported at, see exec::registry. 0 if
+    // they didn't come from an export.
+    pub catalog: u32,
+}
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            server_name: "localhost".to_string(),
+            path: "/".to_string(),
+            verify: true,
+            ca: None,
+            connect_timeout: Duration::from_secs(5),
+            call_timeout: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(30),
+            catalog: 0,
+        }
+    }
+}
+
+// a result, from the bytes the procedure sent back
+pub trait FromResult: Sized {
+    fn from_result(buf: Vec<u8>) -> Result<Self>;
+}
+impl FromResult for Vec<u8> {
+    fn from_result(buf: Vec<u8>) -> Result<Self> {
+        Ok(buf)
+    }
+}
+impl FromResult for () {
+    fn from_result(_buf: Vec<u8>) -> Result<Self> {
+        Ok(())
+    }
+}
+impl FromResult for String {
+    fn from_result(buf: Vec<u8>) -> Result<Self> {
+        String::from_utf8(buf).map_err(|_| Error::Malformed)
+    }
+}
+impl FromResult for u64 {
+    fn from_result(buf: Vec<u8>) -> Result<Self> {
+        let b = buf.try_into().map_err(|_| Error::Malformed)?;
+        Ok(u64::from_le_bytes(b))
+    }
+}
+impl FromResult for i64 {
+    fn from_result(buf: Vec<u8>) -> Result<Self> {
+        let b = buf.try_into().map_err(|_| Error::Malformed)?;
+        Ok(i64::from_le_bytes(b))
+    }
+}
+
+// a call that was sent
+#[must_use]
+pub struct Pending {
+    stream_id: u64,
+    // the result starts with a transaction handle
+    begins: bool,
+}
+
+// an open transaction: statements run in it until one commits it.
+#[must_use]
+pub struct Transaction {
+    env: u16,
+    handle: u16,
+}
+impl Transaction {
+    pub fn handle(&self) -> u16 {
+        self.handle
+    }
+}
+
+pub struct Client {
+    session: Session,
+    call_timeout: Duration,
+}
+
+impl Client {
+    pub fn connect(addr: SocketAddr, config: &ClientConfig) -> Result<Self> {
+        Ok(Self {
+            session: Session::connect(addr, config)?,
+            call_timeout: config.call_timeout,
+        })
+    }
+
+    // the version the server picked
+    pub fn version(&self) -> u16 {
+        self.session.version()
+    }
+
+    // the server's catalogue version. procedures exported after it don't exist there.
+    pub fn catalog(&self) -> u32 {
+        self.session.catalog()
+    }
+
+    // start a call without waiting for it
+    pub fn send(&mut self, header: StreamHeader, params: &[u8]) -> Result<Pending> {
+        let mut data = Vec::with_capacity(header.to_bytes().len() + params.len());
+        data.extend_from_slice(&header.to_bytes());
+        data.extend_from_slice(params);
+        self.send_data(header, data)
+    }
+
+    // a parameter block in the encoding the server agreed to
+    pub fn builder(&self, schema: &ParamBlockSchema) -> Result<ParamBlockBuilder> {
+        let enc = IntEncoding::for_version(self.version()).ok_or(Error::Version)?;
+        Ok(ParamBlockBuilder::new(schema, enc)?)
+    }
+
+    // the same, encoding the parameter block from `builder` right behind the header
+    pub fn send_block(
+        &mut self,
+        header: StreamHeader,
+        params: &ParamBlockBuilder,
+    ) -> Result<Pending> {
+        let mut data = Vec::with_capacity(header.to_bytes().len() + params.len());
+        data.extend_from_slice(&header.to_bytes());
+        params.write(&mut data)?;
+        self.send_data(header, data)
+    }
+
+    fn send_data(&mut self, header: StreamHeader, data: Vec<u8>) -> Result<Pending> {
+        let begins = header.continues == BEGIN;
+        let deadline = Instant::now() + self.call_timeout;
+        let stream_id = self.session.open_stream(data, deadline)?;
+        Ok(Pending { stream_id, begins })
+    }
+
+    // the call's result, driving the connection until it arrives
+    pub fn wait<T: FromResult>(&mut self, call: Pending) -> Result<T> {
+        self.wait_raw(call).and_then(|(_, buf)| T::from_result(buf))
+    }
+
+    // without blocking; the connection only moves on in `wait`.
+    pub fn poll<T: FromResult>(&mut self, call: &Pending) -> Option<Result<T>> {
+        let r = self.session.take(call.stream_id)?;
+        Some(
+            r.and_then(|buf| split_handle(call, buf))
+                .and_then(|(_, buf)| T::from_result(buf)),
+        )
+    }
+
+    // with the transaction handle split off the front, for statements that begin one
+    fn wait_raw(&mut self, call: Pending) -> Result<(Option<u16>, Vec<u8>)> {
+        if !self.session.is_known(call.stream_id) {
+            return Err(Error::Closed);
+        }
+        let deadline = Instant::now() + self.call_timeout;
+        loop {
+            if let Some(r) = self.session.take(call.stream_id) {
+                return split_handle(&call, r?);
+            }
+            self.session.drive(deadline)?;
+        }
+    }
+
+    // one statement in its own transaction
+    pub fn call<T: FromResult>(
+        &mut self,
+        env: u16,
+        iface: u16,
+        procid: u16,
+        params: &[u8],
+    ) -> Result<T> {
+        let call = self.send(StreamHeader::with(env, iface, procid, AUTOCOMMIT), params)?;
+        self.wait(call)
+    }
+
+    // the first statement of a transaction that stays open for more
+    pub fn begin<T: FromResult>(
+        &mut self,
+        env: u16,
+        iface: u16,
+        procid: u16,
+        params: &[u8],
+    ) -> Result<(Transaction, T)> {
+        let call = self.send(StreamHeader::with(env, iface, procid, BEGIN), params)?;
+        let (handle, buf) = self.wait_raw(call)?;
+        let handle = handle.ok_or(Error::Malformed)?;
+        Ok((Transaction { env, handle }, T::from_result(buf)?))
+    }
+
+    // another statement in the transaction, which stays open
+    pub fn then<T: FromResult>(
+        &mut self,
+        tx: &Transaction,
+        iface: u16,
+        procid: u16,
+        params: &[u8],
+    ) -> Result<T> {
+        let header = StreamHeader::with(tx.env, iface, procid, tx.handle + 1);
+        let call = self.send(header, params)?;
+        self.wait(call)
+    }
+
+    // the last statement; the transaction commits with it.
+    pub fn commit<T: FromResult>(
+        &mut self,
+        tx: Transaction,
+        iface: u16,
+        procid: u16,
+        params: &[u8],
+    ) -> Result<T> {
+        let header = StreamHeader::with(tx.env, iface, procid, tx.handle);
+        let call = self.send(header, params)?;
+        self.wait(call)
+    }
+
+    pub fn close(mut self) {
+        self.session.close();
+    }
+}
+
+fn split_handle(call: &Pending, mut buf: Vec<u8>) -> Result<(Option<u16>, Vec<u8>)> {
+    if !call.begins {
+        return Ok((None, buf));
+    }
+    if buf.len() < 2 {
+        return Err(Error::Malformed);
+    }
+    let handle = u16::from_le_bytes([buf[0], buf[1]]);
+    buf.drain(..2);
+    Ok((Some(handle), buf))
+}
@@ -0,0 +1,422 @@
+// the client end of a webtransport session over quiche, see quic/webtransport.rs for the
+// server's.
+//
+// like the server, we read our call streams before h3 polls: h3 would take their replies for
+// malformed responses to requests it never sent.
+
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use log::*;
+use quiche::h3::NameValue;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::quic::webtransport::app_error_code;
+
+use super::{ClientConfig, Error, Result, CATALOG_HEADER, VERSIONS, VERSION_HEADER};
+
+const WEBTRANSPORT_BIDI: u64 = 0x41;
+const SETTINGS_ENABLE_WEBTRANSPORT: u64 = 0x2b603742;
+const MAX_DATAGRAM_SIZE: usize = 1350;
+
+// one call's stream
+#[derive(Default)]
+struct Call {
+    send: Vec<u8>,
+    sent: usize,
+    recv: Vec<u8>,
+    done: Option<Result<()>>,
+}
+
+pub struct Session {
+    socket: UdpSocket,
+    local: SocketAddr,
+    conn: quiche::Connection,
+    h3: Option<quiche::h3::Connection>,
+    // the CONNECT stream
+    session_id: Option<u64>,
+    // the protocol version the server picked
+    version: Option<u16>,
+    // and its catalogue version
+    catalog: u32,
+    next_stream: u64,
+    calls: HashMap<u64, Call>,
+    buf: Vec<u8>,
+    out: Vec<u8>,
+}
+
+impl Session {
+    pub fn connect(peer: SocketAddr, config: &ClientConfig) -> Result<Self> {
+        let mut qc = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+        qc.set_application_protos(quiche::h3::APPLICATION_PROTOCOL)?;
+        qc.verify_peer(config.verify);
+        if let Some(ca) = &config.ca {
+            qc.load_verify_locations_from_file(ca)?;
+        }
+        qc.set_max_idle_timeout(config.idle_timeout.as_millis() as u64);
+        qc.set_max_recv_udp_payload_size(MAX_DATAGRAM_SIZE);
+        qc.set_max_send_udp_payload_size(MAX_DATAGRAM_SIZE);
+        qc.set_initial_max_data(10_000_000);
+        qc.set_initial_max_stream_data_bidi_local(1_000_000);
+        qc.set_initial_max_stream_data_bidi_remote(1_000_000);
+        qc.set_initial_max_stream_data_uni(1_000_000);
+        qc.set_initial_max_streams_bidi(100);
+        qc.set_initial_max_streams_uni(100);
+        qc.enable_dgram(true, 1000, 1000);
+
+        let bind: SocketAddr = match peer {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+            SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+        };
+        let socket = UdpSocket::bind(bind)?;
+        let local = socket.local_addr()?;
+
+        let mut scid = [0u8; quiche::MAX_CONN_ID_LEN];
+        SystemRandom::new()
+            .fill(&mut scid)
+            .map_err(|_| Error::Io(std::io::Error::other("system rng failed")))?;
+        let scid = quiche::ConnectionId::from_ref(&scid);
+        let conn = quiche::connect(Some(&config.server_name), &scid, local, peer, &mut qc)?;
+
+        let mut session = Self {
+            socket,
+            local,
+            conn,
+            h3: None,
+            session_id: None,
+            version: None,
+            catalog: 0,
+            next_stream: 0,
+            calls: HashMap::new(),
+            buf: vec![0; 65535],
+            out: vec![0; MAX_DATAGRAM_SIZE],
+        };
+        let deadline = Instant::now() + config.connect_timeout;
+        session.open(config, deadline)?;
+        Ok(session)
+    }
+
+    // handshake, settings, then the CONNECT for the session
+    fn open(&mut self, config: &ClientConfig, deadline: Instant) -> Result<()> {
+        while !self.conn.is_established() {
+            self.drive(deadline)?;
+        }
+        let mut h3_config = quiche::h3::Config::new()?;
+        h3_config.set_additional_settings(vec![(SETTINGS_ENABLE_WEBTRANSPORT, 1)])?;
+        self.h3 = Some(quiche::h3::Connection::with_transport(
+            &mut self.conn,
+            &h3_config,
+        )?);
+        while self.h3().peer_settings_raw().is_none() {
+            self.drive(deadline)?;
+        }
+        if !self.h3().extended_connect_enabled_by_peer() {
+            return Err(Error::Refused("server does not speak webtransport".into()));
+        }
+
+        let versions = VERSIONS
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let catalog = config.catalog.to_string();
+        let mut headers = vec![
+            quiche::h3::Header::new(b":method", b"CONNECT"),
+            quiche::h3::Header::new(b":protocol", b"webtransport"),
+            quiche::h3::Header::new(b":scheme", b"https"),
+            quiche::h3::Header::new(b":authority", config.server_name.as_bytes()),
+            quiche::h3::Header::new(b":path", config.path.as_bytes()),
+            quiche::h3::Header::new(VERSION_HEADER.as_bytes(), versions.as_bytes()),
+        ];
+        if config.catalog != 0 {
+            headers.push(quiche::h3::Header::new(
+                CATALOG_HEADER.as_bytes(),
+                catalog.as_bytes(),
+            ));
+        }
+        let h3 = self.h3.as_mut().unwrap();
+        let session_id = h3.send_request(&mut self.conn, &headers, false)?;
+        self.session_id = Some(session_id);
+        self.next_stream = session_id + 4;
+        while self.version.is_none() {
+            self.drive(deadline)?;
+        }
+        Ok(())
+    }
+
+    fn h3(&self) -> &quiche::h3::Connection {
+        self.h3.as_ref().expect("h3 is set up after the handshake")
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version.unwrap_or(VERSIONS[0])
+    }
+
+    pub fn catalog(&self) -> u32 {
+        self.catalog
+    }
+
+    // a new call stream with `data` to write, all of it, then fin
+    pub fn open_stream(&mut self, data: Vec<u8>, deadline: Instant) -> Result<u64> {
+        while self.conn.peer_streams_left_bidi() == 0 {
+            self.drive(deadline)?;
+        }
+        let session_id = self.session_id.ok_or(Error::Closed)?;
+        let mut send = Vec::with_capacity(16 + data.len());
+        put_varint(&mut send, WEBTRANSPORT_BIDI);
+        put_varint(&mut send, session_id);
+        send.extend_from_slice(&data);
+
+        let stream_id = self.next_stream;
+        self.next_stream += 4;
+        self.calls.insert(
+            stream_id,
+            Call {
+                send,
+                ..Default::default()
+            },
+        );
+        self.write_streams();
+        self.flush()?;
+        Ok(stream_id)
+    }
+
+    // what the call's stream brought back, once it is complete.
+    pub fn take(&mut self, stream_id: u64) -> Option<Result<Vec<u8>>> {
+        let done = self.calls.get(&stream_id)?.done.is_some();
+        if !done {
+            return None;
+        }
+        let call = self.calls.remove(&stream_id)?;
+        Some(call.done.unwrap().map(|()| call.recv))
+    }
+
+    pub fn is_known(&self, stream_id: u64) -> bool {
+        self.calls.contains_key(&stream_id)
+    }
+
+    // send what we can, wait for packets or a timer up to `deadline`, and process them.
+    pub fn drive(&mut self, deadline: Instant) -> Result<()> {
+        self.flush()?;
+        if self.conn.is_closed() {
+            return Err(Error::Closed);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::Timeout);
+        }
+        let mut wait = deadline - now;
+        if let Some(t) = self.conn.timeout() {
+            wait = wait.min(t);
+        }
+        // a zero timeout means block forever
+        self.socket
+            .set_read_timeout(Some(wait.max(Duration::from_millis(1))))?;
+        match self.socket.recv_from(&mut self.buf) {
+            Ok((len, from)) => {
+                self.recv(len, from)?;
+                self.socket.set_nonblocking(true)?;
+                let r = self.recv_pending();
+                self.socket.set_nonblocking(false)?;
+                r?;
+            }
+            Err(e)
+                if e.kind() == std::io::ErrorKind::WouldBlock
+                    || e.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                self.conn.on_timeout();
+            }
+            Err(e) => return Err(e.into()),
+        }
+        if self.h3.is_some() {
+            self.read_streams();
+            self.poll_h3()?;
+            self.write_streams();
+        }
+        self.flush()
+    }
+
+    fn recv(&mut self, len: usize, from: SocketAddr) -> Result<()> {
+        let info = quiche::RecvInfo {
+            from,
+            to: self.local,
+        };
+        match self.conn.recv(&mut self.buf[..len], info) {
+            Ok(_) | Err(quiche::Error::Done) => Ok(()),
+            Err(e) => {
+                debug!("{} recv failed: {:?}", self.conn.trace_id(), e);
+                Ok(())
+            }
+        }
+    }
+
+    fn recv_pending(&mut self) -> Result<()> {
+        loop {
+            match self.socket.recv_from(&mut self.buf) {
+                Ok((len, from)) => self.recv(len, from)?,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        loop {
+            let (len, info) = match self.conn.send(&mut self.out) {
+                Ok(v) => v,
+                Err(quiche::Error::Done) => return Ok(()),
+                Err(e) => {
+                    self.conn.close(false, 0x1, b"fail").ok();
+                    return Err(e.into());
+                }
+            };
+            match self.socket.send_to(&self.out[..len], info.to) {
+                Ok(_) => (),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn write_streams(&mut self) {
+        for (&stream_id, call) in self.calls.iter_mut() {
+            if call.sent == call.send.len() || call.done.is_some() {
+                continue;
+            }
+            match self
+                .conn
+                .stream_send(stream_id, &call.send[call.sent..], true)
+            {
+                Ok(n) => call.sent += n,
+                Err(quiche::Error::Done) => (),
+                Err(e) => call.done = Some(Err(e.into())),
+            }
+        }
+    }
+
+    // replies to our calls, before h3 sees them
+    fn read_streams(&mut self) {
+        let session_id = self.session_id;
+        for stream_id in self.conn.readable() {
+            // h3's own streams, and the session's
+            if stream_id % 4 != 0 || Some(stream_id) == session_id {
+                continue;
+            }
+            let mut call = self.calls.get_mut(&stream_id);
+            loop {
+                match self.conn.stream_recv(stream_id, &mut self.buf) {
+                    Ok((len, fin)) => {
+                        // a call we gave up on still has to be drained
+                        let Some(call) = call.as_deref_mut() else {
+                            continue;
+                        };
+                        call.recv.extend_from_slice(&self.buf[..len]);
+                        if fin {
+                            call.done.get_or_insert(Ok(()));
+                            break;
+                        }
+                    }
+                    Err(quiche::Error::Done) => break,
+                    Err(quiche::Error::StreamReset(code)) => {
+                        if let Some(call) = call.as_deref_mut() {
+                            call.done = Some(Err(match app_error_code(code) {
+                                Some(code) => Error::Rpc(code),
+                                None => Error::Reset(code),
+                            }));
+                        }
+                        break;
+                    }
+                    Err(e) => {
+                        if let Some(call) = call.as_deref_mut() {
+                            call.done = Some(Err(e.into()));
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    fn poll_h3(&mut self) -> Result<()> {
+        let h3 = self.h3.as_mut().unwrap();
+        loop {
+            match h3.poll(&mut self.conn) {
+                Ok((stream_id, quiche::h3::Event::Headers { list, .. }))
+                    if Some(stream_id) == self.session_id =>
+                {
+                    let mut status = None;
+                    let mut version = None;
+                    let mut catalog = None;
+                    for hdr in &list {
+                        let value = std::str::from_utf8(hdr.value()).unwrap_or_default().trim();
+                        match hdr.name() {
+                            b":status" => status = Some(hdr.value().to_vec()),
+                            name if name == VERSION_HEADER.as_bytes() => {
+                                version = Some(value.parse::<u16>().ok());
+                            }
+                            name if name == CATALOG_HEADER.as_bytes() => {
+                                catalog = value.parse::<u32>().ok();
+                            }
+                            _ => (),
+                        }
+                    }
+                    // a refusal that lists the versions the server speaks: none of ours
+                    if status.as_deref() == Some(b"400") && version.is_some() {
+                        return Err(Error::Version);
+                    }
+                    // a refusal with the server's catalogue: older than ours
+                    if let (Some(b"409"), Some(catalog)) = (status.as_deref(), catalog) {
+                        return Err(Error::Catalog(catalog));
+                    }
+                    if status.as_deref() != Some(b"200") {
+                        let status = String::from_utf8_lossy(&status.unwrap_or_default()).into();
+                        return Err(Error::Refused(status));
+                    }
+                    // a server that predates negotiation speaks the first version
+                    let version = version.unwrap_or(Some(VERSIONS[0])).ok_or(Error::Version)?;
+                    if !VERSIONS.contains(&version) {
+                        return Err(Error::Version);
+                    }
+                    self.version = Some(version);
+                    self.catalog = catalog.unwrap_or(0);
+                }
+                Ok((stream_id, quiche::h3::Event::Data)) => {
+                    while h3
+                        .recv_body(&mut self.conn, stream_id, &mut self.buf)
+                        .is_ok()
+                    {}
+                }
+                Ok((stream_id, quiche::h3::Event::Finished))
+                | Ok((stream_id, quiche::h3::Event::Reset(_)))
+                    if Some(stream_id) == self.session_id =>
+                {
+                    info!("{} session closed by the server", self.conn.trace_id());
+                    self.session_id = None;
+                    for call in self.calls.values_mut() {
+                        call.done.get_or_insert(Err(Error::Closed));
+                    }
+                }
+                Ok(_) => (),
+                Err(quiche::h3::Error::Done) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    pub fn close(&mut self) {
+        _ = self.conn.close(true, 0, b"");
+        _ = self.flush();
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, v: u64) {
+    let mut b = [0u8; 8];
+    let len = octets::varint_len(v);
+    let mut o = octets::OctetsMut::with_slice(&mut b);
+    o.put_varint_with_len(v, len)
+        .expect("a varint fits in 8 bytes");
+    out.extend_from_slice(&b[..len]);
+}
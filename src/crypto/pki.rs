@@ -8,8 +8,12 @@ use rustls::ServerConfig;
 use std::io::BufReader;
 
 pub fn load_tls_config() -> Arc<ServerConfig> {
-    let cert_file = &mut BufReader::new(File::open("cert.pem").unwrap());
-    let key_file = &mut BufReader::new(File::open("key.pem").unwrap());
+    load_tls_config_from("cert.pem", "key.pem")
+}
+
+pub fn load_tls_config_from(cert: &str, key: &str) -> Arc<ServerConfig> {
+    let cert_file = &mut BufReader::new(File::open(cert).unwrap());
+    let key_file = &mut BufReader::new(File::open(key).unwrap());
 
     let certs: Vec<CertificateDer> = rustls_pemfile::certs(cert_file)
         .collect::<Result<_, _>>()
@@ -0,0 +1,312 @@
+// diagnostics for one misbehaving client at a time, switched on at runtime.
+//
+// while a filter matches a connection, quic connections get a qlog (for qvis) and both
+// transports write their tls secrets to a shared SSLKEYLOGFILE (for wireshark). nothing is
+// written for connections that don't match, and with no filters the check is one atomic load.
+//
+// quic connections already open when a filter is added get a qlog from then on; their keys
+// were settled in the handshake, so the client has to reconnect for those.
+//
+// filters are added and removed from the admin socket, see admin.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+
+use log::*;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    Addr(IpAddr),
+    // one of the connection's ids, as hex in the logs (the quic trace id)
+    Cid(Vec<u8>),
+    All,
+}
+
+impl Filter {
+    // as Display writes it: `addr <ip>`, `cid <hex>` or `all`
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut words = s.split_whitespace();
+        let filter = match (words.next()?, words.next()) {
+            ("addr", Some(ip)) => Filter::Addr(ip.parse().ok()?),
+            ("cid", Some(cid)) => Filter::Cid(unhex(cid)?),
+            ("all", None) => Filter::All,
+            _ => return None,
+        };
+        words.next().is_none().then_some(filter)
+    }
+
+    fn matches(&self, addr: &SocketAddr, cids: &[&[u8]]) -> bool {
+        match self {
+            Filter::Addr(ip) => addr.ip() == *ip,
+            Filter::Cid(cid) => cids.contains(&cid.as_slice()),
+            Filter::All => true,
+        }
+    }
+}
+
+impl std::fmt::Display for Filter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Filter::Addr(ip) => write!(f, "addr {}", ip),
+            Filter::Cid(cid) => write!(f, "cid {}", hex(cid)),
+            Filter::All => f.write_str("all"),
+        }
+    }
+}
+
+// shared by all workers
+pub struct Diagnostics {
+    dir: PathBuf,
+    filters: RwLock<Vec<Filter>>,
+    active: AtomicBool,
+    // bumped on every change, so workers can notice cheaply
+    generation: AtomicU64,
+    keylog: Mutex<Option<Arc<Mutex<File>>>>,
+}
+
+impl Diagnostics {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            filters: RwLock::new(Vec::new()),
+            active: AtomicBool::new(false),
+            generation: AtomicU64::new(0),
+            keylog: Mutex::new(None),
+        }
+    }
+
+    // the admin calls
+    pub fn enable(&self, filter: Filter) {
+        info!("diagnostics on for {:?}", filter);
+        let mut filters = self.filters.write().unwrap();
+        if !filters.contains(&filter) {
+            filters.push(filter);
+        }
+        self.active.store(true, Ordering::Release);
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn disable(&self, filter: &Filter) {
+        info!("diagnostics off for {:?}", filter);
+        let mut filters = self.filters.write().unwrap();
+        filters.retain(|f| f != filter);
+        self.active.store(!filters.is_empty(), Ordering::Release);
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn filters(&self) -> Vec<Filter> {
+        self.filters.read().unwrap().clone()
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    pub fn matches<'a>(&self, addr: &SocketAddr, cids: impl IntoIterator<Item = &'a [u8]>) -> bool {
+        if !self.active.load(Ordering::Acquire) {
+            return false;
+        }
+        let filters = self.filters.read().unwrap();
+        let cids = cids.into_iter().collect::<Vec<_>>();
+        filters.iter().any(|f| f.matches(addr, &cids))
+    }
+
+    // the qlog for one quic connection, named by its trace id.
+    pub fn qlog(&self, trace_id: &str) -> io::Result<BufWriter<File>> {
+        std::fs::create_dir_all(&self.dir)?;
+        crate::quiche::make_qlog_writer(self.dir.as_os_str(), "server", trace_id)
+    }
+
+    // all matching connections append to one file, in the NSS key log format both quiche
+    // and rustls write.
+    pub fn keylog(&self) -> io::Result<KeyLogFile> {
+        let mut keylog = self.keylog.lock().unwrap();
+        let file = match keylog.as_ref() {
+            Some(f) => f.clone(),
+            None => {
+                std::fs::create_dir_all(&self.dir)?;
+                let path = std::env::var_os("SSLKEYLOGFILE")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| self.dir.join("keylog.txt"));
+                let f = OpenOptions::new().create(true).append(true).open(path)?;
+                keylog.insert(Arc::new(Mutex::new(f))).clone()
+            }
+        };
+        Ok(KeyLogFile(file))
+    }
+
+    // the tls config for a tcp client: the shared one, or a copy that logs keys if the
+    // client is being watched.
+    pub fn tls_config(
+        &self,
+        config: &Arc<rustls::ServerConfig>,
+        addr: &SocketAddr,
+    ) -> Arc<rustls::ServerConfig> {
+        if !self.matches(addr, []) {
+            return config.clone();
+        }
+        match self.keylog() {
+            Ok(keylog) => {
+                let mut c = (**config).clone();
+                c.key_log = Arc::new(keylog);
+                Arc::new(c)
+            }
+            Err(e) => {
+                warn!("cannot open keylog: {}", e);
+                config.clone()
+            }
+        }
+    }
+}
+
+impl Default for Diagnostics {
+    fn default() -> Self {
+        Self::new("diag")
+    }
+}
+
+#[derive(Clone)]
+pub struct KeyLogFile(Arc<Mutex<File>>);
+
+// for quiche, which writes whole lines
+impl Write for KeyLogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+// for rustls, which hands over the parts
+impl rustls::KeyLog for KeyLogFile {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let line = format!("{} {} {}\n", label, hex(client_random), hex(secret));
+        if let Err(e) = self.0.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("keylog write failed: {}", e);
+        }
+    }
+}
+
+impl std::fmt::Debug for KeyLogFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("KeyLogFile")
+    }
+}
+
+fn hex(b: &[u8]) -> String {
+    b.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if s.is_empty() || !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use rustls::KeyLog;
+
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("simpleweb-diag-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn filters() {
+        let addr = "192.0.2.7:443".parse::<SocketAddr>().unwrap();
+        let other = "192.0.2.8:443".parse::<SocketAddr>().unwrap();
+        let cid = [0xab, 0x01];
+        assert!(Filter::Addr(addr.ip()).matches(&addr, &[]));
+        assert!(!Filter::Addr(addr.ip()).matches(&other, &[&cid]));
+        assert!(Filter::Cid(cid.to_vec()).matches(&other, &[b"x", &cid]));
+        assert!(!Filter::Cid(cid.to_vec()).matches(&addr, &[&cid[..1]]));
+        assert!(Filter::All.matches(&other, &[]));
+
+        for f in [
+            Filter::Addr(addr.ip()),
+            Filter::Cid(cid.to_vec()),
+            Filter::All,
+        ] {
+            assert_eq!(Filter::parse(&f.to_string()), Some(f));
+        }
+        assert_eq!(
+            Filter::parse(" cid  AB01 "),
+            Some(Filter::Cid(cid.to_vec()))
+        );
+        for bad in [
+            "",
+            "addr",
+            "addr x",
+            "cid abc",
+            "cid zz",
+            "all 1",
+            "addr 1.2.3.4 5",
+        ] {
+            assert_eq!(Filter::parse(bad), None, "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn enable_disable() {
+        let addr = "192.0.2.7:443".parse::<SocketAddr>().unwrap();
+        let d = Diagnostics::new(dir("toggle"));
+        let g = d.generation();
+        assert!(!d.matches(&addr, []));
+
+        d.enable(Filter::Addr(addr.ip()));
+        d.enable(Filter::Addr(addr.ip()));
+        assert_eq!(d.filters(), [Filter::Addr(addr.ip())]);
+        assert!(d.matches(&addr, []));
+        // every change is one workers look at, even one that changed nothing
+        assert_eq!(d.generation(), g + 2);
+
+        d.enable(Filter::Cid(vec![1]));
+        d.disable(&Filter::Addr(addr.ip()));
+        assert!(!d.matches(&addr, []));
+        assert!(d.matches(&addr, [&[1u8][..]]));
+        d.disable(&Filter::Cid(vec![1]));
+        assert!(d.filters().is_empty());
+        assert!(!d.active.load(Ordering::Acquire));
+        assert_eq!(d.generation(), g + 5);
+    }
+
+    // quiche and rustls connections all write to the one file
+    #[test]
+    fn keylog_shared() {
+        let d = Diagnostics::new(dir("keylog"));
+        let mut a = d.keylog().unwrap();
+        let b = d.keylog().unwrap();
+        assert!(Arc::ptr_eq(&a.0, &b.0));
+        a.write_all(b"CLIENT_RANDOM 00 11\n").unwrap();
+        b.log("SERVER_HANDSHAKE_TRAFFIC_SECRET", &[0xaa], &[0x0b, 0xcd]);
+        if std::env::var_os("SSLKEYLOGFILE").is_none() {
+            let mut text = String::new();
+            File::open(d.dir.join("keylog.txt"))
+                .unwrap()
+                .read_to_string(&mut text)
+                .unwrap();
+            assert_eq!(
+                text,
+                "CLIENT_RANDOM 00 11\nSERVER_HANDSHAKE_TRAFFIC_SECRET aa 0bcd\n"
+            );
+        }
+        std::fs::remove_dir_all(&d.dir).unwrap();
+    }
+}
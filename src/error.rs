@@ -17,6 +17,11 @@ impl From<rustls::Error> for Error {
         Error::Tls(err)
     }
 }
+impl From<quiche::Error> for Error {
+    fn from(err: quiche::Error) -> Self {
+        Error::Quic(err)
+    }
+}
 // impl From<s2n_quic::provider::tls::default::error::Error> for Error {
 //     fn from(err: s2n_quic::provider::tls::default::error::Error) -> Self {
 //         Error::Quic(err)
@@ -1,26 +1,65 @@
 use std::{
+    cell::RefCell,
     collections::{hash_map::Entry, HashMap},
     future::Future,
     ops::{Deref, DerefMut},
     pin::Pin,
-    task::{Context, Poll},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Wake, Waker},
 };
 
-use env_logger::Env;
+use log::*;
+use quiche::h3::NameValue;
 use rustls::pki_types::Der;
 
+use crate::{
+    blob::{BlobConfig, Budget, Pool, Spool},
+    param::{self, IntEncoding, ParamBlock, ParamBlockSchema, ParamError, Parser},
+    quic::{
+        early::{Admit, EarlyData, TOO_EARLY},
+        webtransport::{HandlerFactory, Replies, StreamHandler, StreamHandlers},
+    },
+};
+
+pub mod registry;
+
 // probably want something like dashmap but with more memory control
 
 //static mut IS_URING : bool = false;
 
 // each procedure has n blobs, and up to nnK bytes of inline data.
-// the blobs may be written to disk (for some procedures we might actually want this as early as possible? can that be a hint? seperate count for eager or lazy writing of blobs?)
+// the blobs may be written to disk depending on memory pressure, see blob. stored blobs are eager and temp blobs lazy.
 // is it too limiting to force the parameter block to be reified before the procedure starts? In general this is a good thing, since it allows us to build it outside a transaction. We can retry the transaction if it fails without rebuilding the parameter block.
 
-pub struct Blob {}
+pub use crate::blob::Blob;
 pub struct ParameterBlock {
+    // the stored blobs then the temp ones
     pub blob: Box<[Blob]>,
+    // everything else, see param
     pub inline: Box<[u8]>,
+    // the procedure's, which inline was checked against
+    pub schema: ParamBlockSchema,
+    // the connection's, from its protocol version
+    pub encoding: IntEncoding,
+}
+
+impl ParameterBlock {
+    // the fields, borrowed from inline. the stored and temp fields have their lengths only;
+    // their bytes are in blob, see stored and temp.
+    pub fn params(&self) -> ParamBlock<'_> {
+        param::parse_inline(&self.inline, &self.schema, self.encoding).expect("checked at dispatch")
+    }
+    // deleted with the block unless the procedure commits it, see Blob::commit
+    pub fn stored(&self, i: usize) -> Option<&Blob> {
+        self.blob[..self.schema.stored].get(i)
+    }
+    pub fn temp(&self, i: usize) -> Option<&Blob> {
+        self.blob[self.schema.stored..].get(i)
+    }
 }
 
 pub struct Ptr<T> {
@@ -36,6 +75,10 @@ impl<T> Ptr<T> {
     // pub fn new(ptr: *mut T) -> Self {
     //     Self { ptr }
     // }
+    // from a unique reference only: what's behind a Ptr gets mutated
+    pub fn from_mut(r: &mut T) -> Self {
+        Self { ptr: r }
+    }
     pub fn new(slice: &[T], index: usize) -> Result<Ptr<T>, DbError> {
         if index >= slice.len() {
             return Err(DbError::InvalidArgument);
@@ -85,20 +128,39 @@ impl<T> Vecb<T> {
 // we want to allocate the transaction procedure inside the memory pool of the transaction.
 // where do helper tasks allocate in?
 
+// what a procedure returns: its result, or an error code for the client, PROC_ERROR and up.
+pub type ProcResult = Result<Vec<u8>, i32>;
+pub type ProcFuture = Pin<Box<dyn Future<Output = ProcResult>>>;
+
 // either returns a result or spawns a task that returns a result.
-type Proc = fn(
-    db: Ptr<Db>,
-    thr: Ptr<DbThread>,
-    cn: Ptr<Connection>,
-    pm: &ParameterBlock, // reference or pointer? the proc might need it move it into the closure.
-    streamid: u64, // we need this to return, but we have already looked in the connection map and know that this does not exist.
-                   // buf: &[u8],
-                   // fin: bool,
-);
+#[derive(Clone, Copy)]
+pub enum Proc {
+    // simple lookups and writes to temporary tables don't need to be async: they create no
+    // log entries, and if cached require no io. these run right on the read path.
+    Inline(
+        fn(db: Ptr<Db>, thr: Ptr<DbThread>, cn: Ptr<Connection>, pm: &ParameterBlock) -> ProcResult,
+    ),
+    // anything that may wait. the future owns the parameter block and runs as a task.
+    Spawn(
+        fn(db: Ptr<Db>, thr: Ptr<DbThread>, cn: Ptr<Connection>, pm: ParameterBlock) -> ProcFuture,
+    ),
+}
 // should we share these globally and deal with locks?
 // should we compile procedures dynamically or AOT?
-type Iface = Vecb<Proc>;
+#[derive(Clone)]
+pub struct Iface {
+    // by procedure id; None is a procedure that is gone, see registry
+    pub proc: Box<[Option<Proc>]>,
+    // the parameter block each procedure takes, by procedure id
+    pub schema: Box<[ParamBlockSchema]>,
+    // which may run from 0-rtt data, see Registry::set_idempotent
+    pub idempotent: Box<[bool]>,
+}
 // capabilities are injected into the environment
+pub struct Env {
+    // the interfaces injected, as indexes into Db::iface
+    pub iface: Box<[u32]>,
+}
 
 // interface = schema or no? is there a better security language we can use?
 // each schema has multiple partitions; the user is authorized to access a subset of the partitions (like row level security) why not use the schema as the interface? we can always create
@@ -107,12 +169,111 @@ pub struct Connection {
     pub user: u32,
 
     // authorize connection, allows other rules than simply user (location, time)
-    pub iface: Vecb<Iface>,
+    // the environments injected, as indexes into the thread's pool
+    pub env: Box<[u32]>,
     pub statement: Box<[DbStream]>,
+    // statements still arriving
     pub stream: HashMap<u64, DbStream>,
+    // open transactions by handle
+    pub tx: HashMap<u16, Tx>,
+    next_tx: u16,
+    // results waiting for the transport to send them
+    pub out: Vec<Out>,
+    // the wire format agreed when the session opened, see negotiate
+    pub version: u16,
+    // the memory its lazy blobs may hold, see Pool::connection_budget
+    pub blob: Budget,
+}
+
+impl Connection {
+    pub fn new(connection_type: ConnectionType, user: u32, env: Box<[u32]>) -> Self {
+        Self {
+            connection_type,
+            user,
+            env,
+            statement: Box::new([]),
+            stream: HashMap::new(),
+            tx: HashMap::new(),
+            next_tx: 2,
+            out: Vec::new(),
+            version: VERSIONS[0],
+            blob: Budget::new(BlobConfig::default().connection_budget),
+        }
+    }
+
+    // which transaction a statement runs in, from the continues field of its header
+    fn statement(&mut self, env: u16, continues: u16) -> DbResult<Statement> {
+        match continues {
+            AUTOCOMMIT => Ok(Statement::Autocommit),
+            BEGIN => {
+                let handle = self.begin(env)?;
+                Ok(Statement::Begin(handle))
+            }
+            c => {
+                let handle = c & !1;
+                match self.tx.get(&handle) {
+                    Some(tx) if tx.env == env => (),
+                    _ => return Err(DbError::NoTransaction),
+                }
+                match c & 1 {
+                    1 => Ok(Statement::Continue(handle)),
+                    _ => Ok(Statement::Commit(handle)),
+                }
+            }
+        }
+    }
+
+    // the server will only return even handles, and never 0
+    fn begin(&mut self, env: u16) -> DbResult<u16> {
+        if self.tx.len() >= MAX_TX {
+            return Err(DbError::NoTransaction);
+        }
+        loop {
+            let handle = self.next_tx;
+            self.next_tx = match self.next_tx.wrapping_add(2) {
+                0 => 2,
+                n => n,
+            };
+            if let Entry::Vacant(e) = self.tx.entry(handle) {
+                e.insert(Tx { env });
+                return Ok(handle);
+            }
+        }
+    }
+}
+
+// an open transaction; statements in it must come from the environment that began it.
+pub struct Tx {
+    pub env: u16,
+}
+// every even u16 but 0
+const MAX_TX: usize = 0x7fff;
+
+#[derive(Clone, Copy)]
+enum Statement {
+    Autocommit,
+    Begin(u16),
+    Continue(u16),
+    Commit(u16),
+}
+
+// what the transport has to send for a connection
+pub enum Out {
+    Data {
+        stream_id: u64,
+        buf: Vec<u8>,
+        fin: bool,
+    },
+    // the stream is reset with the code
+    Error {
+        stream_id: u64,
+        code: i32,
+    },
 }
 
 pub struct Db {
+    // the catalogue version of iface, see registry::Registry::catalog
+    pub catalog: u32,
     // is it plausible to have users assigned to a thread? the problem is that the user does not show up in source. we could potentially have multiple ports and then webtransport to the port that the user is assigned to. CID, but only with quic, user routing to port, but makes deployment more complex.
     pub user: Box<[User]>,
     pub iface: Box<[Iface]>,
@@ -130,12 +291,65 @@ pub struct Db {
 
 // instead of futures, can we use something that takes our slice? but then if that future needs to block for something, how would we manage that? otoh how do we (temporarily) store the new network packet if we can't immediately feed it to the future? what if the future is not ready to accept it, eg. is still writing the previous packet?
 // would it make more sense to use an intermediate layer that attempts to cache the entire stream in memory, but falls back to swapping to disk? such a layer would need to understand something about the layout of the procedure block, at least the blobs.
-pub struct DbStream {}
+#[derive(Default)]
+pub struct DbStream {
+    // the header, until it's all here
+    head: Vec<u8>,
+    // then the parameter block, for the procedure the header names
+    ingest: Option<Ingest>,
+    // the block was complete before fin and the procedure has it; only fin may follow
+    dispatched: bool,
+}
+
+struct Ingest {
+    header: StreamHeader,
+    proc: Proc,
+    schema: ParamBlockSchema,
+    encoding: IntEncoding,
+    parser: Parser,
+    spool: Spool,
+}
 
 pub struct DbThread {
     is_uring: bool,
+    // the pool connections' environments point into
+    pub env: Vec<Env>,
     pub connection: Box<[Connection]>,
     pub statement: Box<[DbStream]>,
+    // where the connections' blobs go
+    pub blobs: Pool,
+    tasks: RefCell<Vec<Task>>,
+    // spawned since the last run; a task may spawn while the others are borrowed
+    spawned: RefCell<Vec<Task>>,
+    // wakes the worker when a task is woken, so it runs them; see set_waker
+    waker: Option<Arc<mio::Waker>>,
+}
+
+struct Task {
+    future: Pin<Box<dyn Future<Output = ()>>>,
+    woken: Arc<Woken>,
+    // the connection whose results it sends, so they go when it does
+    owner: *const Connection,
+}
+
+// a task's waker. the worker is woken only for the first wake since the task last ran; the
+// others find that wakeup still on its way.
+struct Woken {
+    woken: AtomicBool,
+    worker: Option<Arc<mio::Waker>>,
+}
+impl Wake for Woken {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+    fn wake_by_ref(self: &Arc<Self>) {
+        if self.woken.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        if let Some(Err(e)) = self.worker.as_ref().map(|w| w.wake()) {
+            warn!("cannot wake the worker for a task: {}", e);
+        }
+    }
 }
 
 pub struct CountFuture {}
@@ -165,13 +379,109 @@ pub enum DbError {
 
     #[error("Invalid argument")]
     InvalidArgument,
+
+    #[error("No such environment")]
+    NoEnv,
+
+    #[error("No such interface")]
+    NoIface,
+
+    #[error("No such procedure")]
+    NoProc,
+
+    #[error("No such transaction")]
+    NoTransaction,
+
+    #[error("Statement too large")]
+    TooLarge,
+
+    #[error("Bad parameter block: {0}")]
+    Param(#[from] ParamError),
 }
 type DbResult<T> = std::result::Result<T, DbError>;
 
+// what a stream is reset with when the statement fails before its procedure could. procedures
+// use their own codes from PROC_ERROR up.
+pub const ERR_IO: i32 = 1;
+pub const ERR_INVALID: i32 = 2;
+pub const ERR_NO_ENV: i32 = 3;
+pub const ERR_NO_IFACE: i32 = 4;
+pub const ERR_NO_PROC: i32 = 5;
+pub const ERR_NO_TRANSACTION: i32 = 6;
+pub const ERR_TOO_LARGE: i32 = 7;
+pub const ERR_PARAM: i32 = 8;
+pub const PROC_ERROR: i32 = 256;
+
+impl From<DbError> for i32 {
+    fn from(e: DbError) -> i32 {
+        match e {
+            DbError::Io(_) => ERR_IO,
+            DbError::InvalidArgument => ERR_INVALID,
+            DbError::NoEnv => ERR_NO_ENV,
+            DbError::NoIface => ERR_NO_IFACE,
+            DbError::NoProc => ERR_NO_PROC,
+            DbError::NoTransaction => ERR_NO_TRANSACTION,
+            DbError::TooLarge => ERR_TOO_LARGE,
+            DbError::Param(ParamError::TooLarge(_)) => ERR_TOO_LARGE,
+            DbError::Param(_) => ERR_PARAM,
+        }
+    }
+}
+
 impl DbThread {
+    pub fn new(is_uring: bool) -> Self {
+        Self {
+            is_uring,
+            env: Vec::new(),
+            connection: Box::new([]),
+            statement: Box::new([]),
+            blobs: Pool::new(BlobConfig::default()),
+            tasks: RefCell::new(Vec::new()),
+            spawned: RefCell::new(Vec::new()),
+            waker: None,
+        }
+    }
+    // what wakes the worker's poll. tasks spawned before this only run when something
+    // else wakes it.
+    pub fn set_waker(&mut self, waker: Arc<mio::Waker>) {
+        self.waker = Some(waker);
+    }
     pub fn spawn(&self, fut: Pin<Box<dyn Future<Output = ()>>>) {
-        // spawn the future on the thread
-        // this is a no-op for now
+        self.spawn_task(std::ptr::null(), fut);
+    }
+    // a task that sends results for `connection`, dropped with it
+    pub fn spawn_for(&self, connection: Ptr<Connection>, fut: Pin<Box<dyn Future<Output = ()>>>) {
+        self.spawn_task(connection.ptr, fut);
+    }
+    fn spawn_task(&self, owner: *const Connection, future: Pin<Box<dyn Future<Output = ()>>>) {
+        self.spawned.borrow_mut().push(Task {
+            future,
+            woken: Arc::new(Woken {
+                woken: AtomicBool::new(true),
+                worker: self.waker.clone(),
+            }),
+            owner,
+        });
+    }
+    // poll the tasks woken since the last run. the ones they spawn wait for the next.
+    pub fn run(&self) {
+        let mut tasks = self.tasks.take();
+        tasks.append(&mut self.spawned.borrow_mut());
+        tasks.retain_mut(|t| {
+            if !t.woken.woken.swap(false, Ordering::Acquire) {
+                return true;
+            }
+            let waker = Waker::from(t.woken.clone());
+            let mut cx = Context::from_waker(&waker);
+            t.future.as_mut().poll(&mut cx).is_pending()
+        });
+        self.tasks.replace(tasks);
+    }
+    // the connection is gone; its tasks have nowhere to send to.
+    pub fn close(&self, connection: Ptr<Connection>) {
+        let owner = connection.ptr as *const Connection;
+        self.tasks.borrow_mut().retain(|t| t.owner != owner);
+        self.spawned.borrow_mut().retain(|t| t.owner != owner);
     }
     pub fn read_some(
         &self,
@@ -181,9 +491,24 @@ impl DbThread {
         Box::pin(CountFuture {})
     }
     // maybe these should be on the connection? do they need the thread?
-    pub fn result_error(&self, connection: Ptr<Connection>, streamid: u64, error: i32) {}
-    pub fn result(&self, connection: Ptr<Connection>, streamid: u64, buf: &[u8], complete: bool) {
-        // the first packet in a stream must be at least 8 bytes, (aside from the stream id in the header)
+    pub fn result_error(&self, mut connection: Ptr<Connection>, streamid: u64, error: i32) {
+        connection.out.push(Out::Error {
+            stream_id: streamid,
+            code: error,
+        });
+    }
+    pub fn result(
+        &self,
+        mut connection: Ptr<Connection>,
+        streamid: u64,
+        buf: &[u8],
+        complete: bool,
+    ) {
+        connection.out.push(Out::Data {
+            stream_id: streamid,
+            buf: buf.to_vec(),
+            fin: complete,
+        });
     }
 }
 fn read_u16(input: &[u8], range: std::ops::Range<usize>) -> Result<u16, DbError> {
@@ -226,12 +551,43 @@ impl Clone for NetworkBuffer {
 }
 #[repr(C)]
 pub struct StreamHeader {
-    env: u16,
-    iface: u16,
-    procid: u16,
-    continues: u16,
+    pub env: u16,
+    pub iface: u16,
+    pub procid: u16,
+    pub continues: u16,
 }
+// continues: 0 runs the statement in its own transaction, 1 starts a transaction and returns
+// its handle, handle + 1 runs another statement in it, and the handle itself commits with this
+// last statement.
+pub const AUTOCOMMIT: u16 = 0;
+pub const BEGIN: u16 = 1;
+pub const HEADER_LEN: usize = 8;
+
 impl StreamHeader {
+    pub fn with(env: u16, iface: u16, procid: u16, continues: u16) -> Self {
+        Self {
+            env,
+            iface,
+            procid,
+            continues,
+        }
+    }
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut b = [0u8; HEADER_LEN];
+        b[0..2].copy_from_slice(&self.env.to_le_bytes());
+        b[2..4].copy_from_slice(&self.iface.to_le_bytes());
+        b[4..6].copy_from_slice(&self.procid.to_le_bytes());
+        b[6..8].copy_from_slice(&self.continues.to_le_bytes());
+        b
+    }
+    // the header in the format of a protocol version
+    pub fn parse(version: u16, nb: &[u8]) -> Result<Self, DbError> {
+        // so far versions differ in the parameter block only
+        match version {
+            1..=3 => Self::new(nb),
+            _ => Err(DbError::InvalidArgument),
+        }
+    }
     pub fn new(nb: &[u8]) -> Result<Self, DbError> {
         let env = read_u16(nb, 0..2)?;
         let iface = read_u16(nb, 2..4)?;
@@ -246,6 +602,29 @@ impl StreamHeader {
     }
 }
 
+// client sends versions it can speak, server picks one. the versions of the wire format (the
+// stream header and the parameter block) this server speaks, the preferred one last. 2 and 3
+// have compact integers in the parameter block, see param::IntEncoding::for_version.
+pub const VERSIONS: &[u16] = &[1, 2, 3];
+// the webtransport CONNECT offers a comma separated list of versions, and the response has
+// the one picked. no header is a client from before negotiation: version 1.
+pub const VERSION_HEADER: &str = "simpleweb-version";
+// the catalogue version the client's ids were exported at, answered with the server's
+pub const CATALOG_HEADER: &str = "simpleweb-catalog";
+
+// the version to speak with a client offering `offered`, if there's one in common
+pub fn negotiate(offered: Option<&[u8]>) -> Option<u16> {
+    let Some(offered) = offered else {
+        return Some(1).filter(|v| VERSIONS.contains(v));
+    };
+    let offered = std::str::from_utf8(offered).ok()?;
+    let offered = offered
+        .split(',')
+        .filter_map(|v| v.trim().parse::<u16>().ok())
+        .collect::<Vec<_>>();
+    VERSIONS.iter().rev().copied().find(|v| offered.contains(v))
+}
+
 // quic can pack multiple frames (multiple streams) into a packet on a connection. if we model this using websockets we could send multiple rpcs in a single websocket frame.
 // more to the point though, we don't control if chrome decides to send multiple websocket frames in a single tcp packet. This makes it difficult to use the same buffer to return the result.
 
@@ -253,125 +632,479 @@ impl StreamHeader {
 
 type TryMaybeFuture = Result<Option<Box<dyn Future<Output = ()>>>, DbError>;
 // handle start of a stream
-// we might resolve immediately (fast path) or we might return a future
-// if not fin, then we will always return a future or error.
-// if fin, then we can return a future or error or Done.
+// we might resolve immediately (fast path) or we might spawn a task.
 // simple lookups and writes to temporary tables don't need to be async
 // these create no log entries, and if cached require no io.
-// we don't need to return the future? just spawn it from here?
 // what about a list of varints, (streamvbyte? that is primarily 32 bit)
 //
+// every failure goes back to the client through result_error.
 
 // somproc(x: LazyBlob, y: EagerBlob, z: u64)
 pub fn handle_read(
     db: Ptr<Db>,
     thread: Ptr<DbThread>,
-    connection: Ptr<Connection>,
+    mut connection: Ptr<Connection>,
     streamid: u64, // we need this to return, but we have already looked in the connection map and know that this does not exist.
     buf: &[u8],
     fin: bool,
-) -> Result<(), DbError> {
-    let str = connection.stream.get(&streamid);
-    if let Some(str) = str {
-        // we need to build the parameter block on packet at a time.
-        // try to cache the the lazy blobs, aggresively write the eager blobs.
-
-        if fin {
-            // invoke the procedure.
+) {
+    if let Err(e) = read(db, thread, connection, streamid, buf, fin) {
+        connection.stream.remove(&streamid);
+        thread.result_error(connection, streamid, e.into());
+    }
+}
+
+fn read(
+    db: Ptr<Db>,
+    thread: Ptr<DbThread>,
+    mut connection: Ptr<Connection>,
+    streamid: u64,
+    mut buf: &[u8],
+    fin: bool,
+) -> DbResult<()> {
+    // we build the parameter block one packet at a time. the inline part is cached, the blobs
+    // go to the spool as they arrive: the eager ones to storage, the lazy ones to memory until
+    // the budgets run out.
+    let mut str = connection.stream.remove(&streamid).unwrap_or_default();
+    if str.dispatched {
+        // quic often sends the fin on its own, after the last of the data
+        if !buf.is_empty() {
+            return Err(ParamError::Trailing(buf.len()).into());
+        }
+        if !fin {
+            connection.stream.insert(streamid, str);
         }
+        return Ok(());
+    }
+    if str.ingest.is_none() {
+        let header = match str.head.is_empty() && buf.len() >= HEADER_LEN {
+            // optimize for single packet: the header is read in place.
+            true => {
+                let header = StreamHeader::parse(connection.version, &buf[..HEADER_LEN])?;
+                buf = &buf[HEADER_LEN..];
+                header
+            }
+            false => {
+                let n = (HEADER_LEN - str.head.len()).min(buf.len());
+                str.head.extend_from_slice(&buf[..n]);
+                buf = &buf[n..];
+                if str.head.len() < HEADER_LEN && !fin {
+                    connection.stream.insert(streamid, str);
+                    return Ok(());
+                }
+                StreamHeader::parse(connection.version, &str.head)?
+            }
+        };
+        str.ingest = Some(ingest(db, thread, connection, header)?);
+    }
+    let ingest = str.ingest.as_mut().unwrap();
+    let done = ingest.parser.push(buf, fin, &mut ingest.spool)?;
+    if let Some(e) = ingest.spool.take_error() {
+        return Err(match e.kind() {
+            std::io::ErrorKind::FileTooLarge => DbError::TooLarge,
+            _ => DbError::Io(e),
+        });
+    }
+    if !done {
+        connection.stream.insert(streamid, str);
+        return Ok(());
+    }
+    let ingest = str.ingest.take().unwrap();
+    if !fin {
+        str.dispatched = true;
+        connection.stream.insert(streamid, str);
     }
-    // stream does not exist, treat as a new stream
-    let header = StreamHeader::new(buf)?;
+    dispatch(db, thread, connection, streamid, ingest)
+}
 
-    // now hopefully we can simply execute the procedure and schedule a packet to be sent back with no async needed. otherwise the procedure will spawn a task that will send the result back.
+// the stream has its header: find its procedure and get ready for its parameter block.
+fn ingest(
+    db: Ptr<Db>,
+    thread: Ptr<DbThread>,
+    connection: Ptr<Connection>,
+    header: StreamHeader,
+) -> DbResult<Ingest> {
+    let iface = resolve(&db, &thread, &connection, &header)?;
+    // a hole fails here, before its parameter block is looked at
+    let proc = iface
+        .proc
+        .get(header.procid as usize)
+        .copied()
+        .flatten()
+        .ok_or(DbError::NoProc)?;
+    let schema = *iface
+        .schema
+        .get(header.procid as usize)
+        .ok_or(DbError::NoProc)?;
+    let encoding = IntEncoding::for_version(connection.version).ok_or(DbError::InvalidArgument)?;
+    Ok(Ingest {
+        header,
+        proc,
+        schema,
+        encoding,
+        parser: Parser::new(&schema, encoding)?,
+        spool: thread.blobs.spool(&connection.blob),
+    })
+}
 
-    // getting the procedure might require an async call, so in that case we will need to spawn.
-    //db.exec_procedure(header)?
+// the interface a header names
+fn resolve<'a>(
+    db: &'a Db,
+    thread: &DbThread,
+    connection: &Connection,
+    header: &StreamHeader,
+) -> DbResult<&'a Iface> {
+    // indirect through the connection; the connection has dense vector that points to pool of envionments in the thread.
+    let env = *connection
+        .env
+        .get(header.env as usize)
+        .ok_or(DbError::NoEnv)?;
+    let env = thread.env.get(env as usize).ok_or(DbError::NoEnv)?;
     // the interface must already be injected into the environment.
-    let iface = connection.iface.get(header.iface as usize)?;
-    let proc = iface.get(header.procid as usize)?;
-    // if not finished we need the procedure to accept more packets as they arrive. it will need to spawn a task to do this. o
+    let iface = *env
+        .iface
+        .get(header.iface as usize)
+        .ok_or(DbError::NoIface)?;
+    db.iface.get(iface as usize).ok_or(DbError::NoIface)
+}
 
-    proc(db, thread, connection, streamid, pb);
+// whether the statement a stream starts with is safe to run twice. anything that doesn't
+// resolve isn't; it waits, and fails once the handshake is done.
+fn idempotent(db: &Db, thread: &DbThread, connection: &Connection, buf: &[u8]) -> bool {
+    let Ok(header) = StreamHeader::parse(connection.version, buf) else {
+        return false;
+    };
+    resolve(db, thread, connection, &header)
+        .ok()
+        .and_then(|iface| iface.idempotent.get(header.procid as usize))
+        .is_some_and(|&i| i)
+}
+
+// the statement is complete: run its procedure.
+fn dispatch(
+    db: Ptr<Db>,
+    thread: Ptr<DbThread>,
+    mut connection: Ptr<Connection>,
+    streamid: u64,
+    ingest: Ingest,
+) -> DbResult<()> {
+    let header = ingest.header;
+
+    // continues = 0 means that this statement is autocommit; when the stream is closed, the transaction is committed or rolled back.
+    // continues = 1 means that this is the first statement of a transaction. the return value of the first statement will include a handle that allows the transaction to be continued with an additional stream.
+    // note that waiting for this continuation handle is intentional; if you don't need to wait, just make a more complex statement.
+    let stmt = connection.statement(header.env, header.continues)?;
+
+    let pm = ParameterBlock {
+        blob: ingest.spool.into_blobs(),
+        inline: ingest.parser.into_inline(),
+        schema: ingest.schema,
+        encoding: ingest.encoding,
+    };
+    match ingest.proc {
+        // now hopefully we can simply execute the procedure and schedule a packet to be sent back with no async needed.
+        Proc::Inline(f) => {
+            let r = f(db, thread, connection, &pm);
+            finish(thread, connection, streamid, stmt, r);
+        }
+        // otherwise the procedure's task sends the result back.
+        Proc::Spawn(f) => {
+            let fut = f(db, thread, connection, pm);
+            thread.spawn_for(
+                connection,
+                Box::pin(async move {
+                    let r = fut.await;
+                    finish(thread, connection, streamid, stmt, r);
+                }),
+            );
+        }
+    }
     Ok(())
 }
 
+// send the result and settle the transaction. a failed statement ends its transaction.
+fn finish(
+    thread: Ptr<DbThread>,
+    mut connection: Ptr<Connection>,
+    streamid: u64,
+    stmt: Statement,
+    r: ProcResult,
+) {
+    match (stmt, &r) {
+        (Statement::Commit(h), _)
+        | (Statement::Begin(h), Err(_))
+        | (Statement::Continue(h), Err(_)) => {
+            connection.tx.remove(&h);
+        }
+        _ => (),
+    }
+    match (stmt, r) {
+        // the handle goes in front of the first statement's result
+        (Statement::Begin(h), Ok(buf)) => {
+            let mut out = Vec::with_capacity(2 + buf.len());
+            out.extend_from_slice(&h.to_le_bytes());
+            out.extend_from_slice(&buf);
+            thread.result(connection, streamid, &out, true);
+        }
+        (_, Ok(buf)) => thread.result(connection, streamid, &buf, true),
+        (_, Err(code)) => thread.result_error(connection, streamid, code),
+    }
+}
+
 // for web sockets we use messages to frame
 impl Db {
+    pub fn new(catalog: u32, iface: Box<[Iface]>) -> Self {
+        Self {
+            catalog,
+            user: Box::new([]),
+            iface,
+            thread: Box::new([]),
+        }
+    }
+
     // optimizize the case where we get the entire stream in a single read.
     // we don't know when we get the first packet of a stream in quic, we have to look in a map to see if we have an existing stream.
     pub fn handle_read(
-        &self,
-        mut thread: Ptr<DbThread>,
+        &mut self,
+        thread: Ptr<DbThread>,
         connection: Ptr<Connection>,
-        //
         streamid: u64,
-        first8: u64,
-        first: bool,
-        last: bool,
-    ) -> DbResult<()> {
-        let (stmt, first) = match connection.stream.entry(streamid) {
-            Entry::Occupied(mut occ) => {
-                // Already existed
-                (occ.get_mut(), false)
+        buf: &[u8],
+        fin: bool,
+    ) {
+        handle_read(Ptr::from_mut(self), thread, connection, streamid, buf, fin);
+        // return error is schema.procid is not authorized.
+    }
+}
+
+// rpc for the server, as MyConfig::stream_handler: each worker gets its own Db and DbThread, and
+// each of its connections a DbHandler on them.
+pub fn serve(
+    catalog: u32,
+    iface: Box<[Iface]>,
+) -> Arc<dyn Fn(usize) -> StreamHandlers + Send + Sync> {
+    let iface: Arc<[Iface]> = iface.into();
+    Arc::new(move |_worker| {
+        let mut worker = Worker::new(
+            Db::new(catalog, iface.to_vec().into()),
+            DbThread::new(false),
+        );
+        // until there is authorization every connection gets one environment with everything
+        worker.thread.env.push(Env {
+            iface: (0..worker.db.iface.len() as u32).collect(),
+        });
+        let env = worker.thread.env.len() as u32 - 1;
+        Box::new(DbHandlers {
+            worker: Rc::new(worker),
+            env,
+        })
+    })
+}
+
+// a worker's Db and DbThread. handlers and tasks reach them through Ptrs, so they are owned by
+// pointer too, and freed once neither the factory nor any handler is left.
+struct Worker {
+    db: Ptr<Db>,
+    thread: Ptr<DbThread>,
+}
+
+impl Worker {
+    fn new(db: Db, thread: DbThread) -> Self {
+        Self {
+            db: Ptr::from_mut(Box::leak(Box::new(db))),
+            thread: Ptr::from_mut(Box::leak(Box::new(thread))),
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        unsafe {
+            drop(Box::from_raw(self.thread.ptr));
+            drop(Box::from_raw(self.db.ptr));
+        }
+    }
+}
+
+struct DbHandlers {
+    worker: Rc<Worker>,
+    // the environment new connections get
+    env: u32,
+}
+
+impl HandlerFactory for DbHandlers {
+    fn handler(&mut self) -> Box<dyn StreamHandler> {
+        let mut connection = Connection::new(ConnectionType::Udp, 0, Box::new([self.env]));
+        connection.blob = self.worker.thread.blobs.connection_budget();
+        Box::new(DbHandler::new(self.worker.clone(), connection))
+    }
+
+    fn set_waker(&mut self, waker: Arc<mio::Waker>) {
+        let mut thread = self.worker.thread;
+        thread.set_waker(waker);
+    }
+
+    // tasks send on their connections, so they run with no handler borrowed
+    fn poll(&mut self) {
+        self.worker.thread.run();
+    }
+}
+
+// a webtransport connection's streams, run as statements on the worker's DbThread.
+pub struct DbHandler {
+    db: Ptr<Db>,
+    thread: Ptr<DbThread>,
+    // tasks send through their own Ptr to it, so it's never behind a Box or a reference
+    connection: Ptr<Connection>,
+    // streams that came in 0-rtt data: a replay would run them twice, so unless their
+    // procedure is idempotent they wait for the handshake to prove the client is live.
+    early: EarlyData,
+    // keeps db and thread alive
+    _worker: Rc<Worker>,
+}
+
+impl DbHandler {
+    fn new(worker: Rc<Worker>, connection: Connection) -> Self {
+        Self {
+            db: worker.db,
+            thread: worker.thread,
+            connection: Ptr::from_mut(Box::leak(Box::new(connection))),
+            early: EarlyData::default(),
+            _worker: worker,
+        }
+    }
+
+    fn connection(&mut self) -> Ptr<Connection> {
+        self.connection
+    }
+
+    fn flush(&mut self, out: &mut Replies) {
+        for o in self.connection.out.drain(..) {
+            match o {
+                Out::Data {
+                    stream_id,
+                    buf,
+                    fin,
+                } => out.stream.push((stream_id, buf, fin)),
+                Out::Error { stream_id, code } => out.reset(stream_id, code as u32),
             }
-            Entry::Vacant(vac) => {
-                // optimize for single packet.
-                let o = DbStream {};
-                vac.insert(o);
-                (o, true)
+        }
+    }
+}
+
+impl StreamHandler for DbHandler {
+    fn handle_read(
+        &mut self,
+        stream_id: u64,
+        buf: &[u8],
+        fin: bool,
+        replayable: bool,
+        out: &mut Replies,
+    ) {
+        let admit = match self.early.is_held(stream_id) {
+            true => self.early.admit(replayable, false, buf.len()),
+            // a stream under way was admitted when it started
+            false if self.connection.stream.contains_key(&stream_id) => Admit::Run,
+            false => {
+                let idempotent = idempotent(&self.db, &self.thread, &self.connection, buf);
+                self.early.admit(replayable, idempotent, buf.len())
             }
         };
-        // the first packet in a stream must be at least 8 bytes, (aside from the stream id in the header)
-        // probably don't need to check here?
-
-        // read 4 byte little endian environment and 4 byte little endian procid
-        let header = match StreamHeader::new(buf) {
-            Ok(header) => header,
-            Err(e) => {
-                thread.result_error(connection, streamid, e.into());
-                return;
+        match admit {
+            Admit::Run => {
+                handle_read(self.db, self.thread, self.connection(), stream_id, buf, fin);
+                self.flush(out);
             }
+            Admit::Hold => self.early.hold(stream_id, buf, fin),
+            Admit::Reject => {
+                debug!("too much 0-rtt data held, rejecting stream {}", stream_id);
+                self.early.drop_stream(stream_id);
+                out.reset(stream_id, TOO_EARLY);
+            }
+        }
+    }
+
+    // agree on the wire format and the catalogue before any statement is read
+    fn open_session(
+        &mut self,
+        request: &[quiche::h3::Header],
+        response: &mut Vec<quiche::h3::Header>,
+    ) -> Result<(), u16> {
+        let find = |name: &str| {
+            request
+                .iter()
+                .find(|h| h.name() == name.as_bytes())
+                .map(|h| h.value())
         };
-        let will_continue = continues & 1;
-        // continues = 0 means that this statement is autocommit; when the stream is closed, the transaction is committed or rolled back.
-        // continues = 1 means that this is the first statement of a transaction. the return value of the first statement will include a handle that allows the transaction to be continued with an additional stream.
-        // note that waiting for this continuation handle is intentional; if you don't need to wait, just make a more complex statement.
-        // the server will only return even handles. Set the low bit (+1) of the handle to indicate that the transaction is continuing.
-
-        // indirect through the connection; the connection has dense vector that points to pool of envionments in the thread.
-        let env = connection
-            .env
-            .get(env as usize)
-            .ok_or(DbError::InvalidArgument)?;
-        let env = Ptr::new(&thread.env, *env as usize)?;
-
-        // .get(*env as usize)
-        // .ok_or(DbError::InvalidArgument)?;
-
-        let interface = env
-            .iface
-            .get(iface as usize)
-            .ok_or(DbError::InvalidArgument)?;
-        let interface = Ptr::new(&self.iface, *interface as usize)?;
-        // let interface = self
-        //     .iface
-        //     .get(*interface as usize)
-        //     .ok_or(DbError::InvalidArgument)?;
-
-        let proc = interface
-            .proc
-            .get(procid as usize)
-            .ok_or(DbError::InvalidArgument)?;
-        let dbp = Ptr {
-            ptr: self as *const _ as *mut _,
+        let versions = VERSIONS
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let catalog = self.db.catalog.to_string();
+        response.push(quiche::h3::Header::new(
+            CATALOG_HEADER.as_bytes(),
+            catalog.as_bytes(),
+        ));
+        let Some(version) = negotiate(find(VERSION_HEADER)) else {
+            // tell the client what we do speak
+            response.push(quiche::h3::Header::new(
+                VERSION_HEADER.as_bytes(),
+                versions.as_bytes(),
+            ));
+            return Err(400);
         };
-        // we need to execute the procedure in a transaction, allocate its memory there.
-        let fut = (*proc)(env, dbp, thread, connection);
+        // ids never move, so an older catalogue is a subset of ours. a newer one was built
+        // for procedures this server doesn't have: the client is refused, and finds our
+        // catalogue in the response. 0 is ids that didn't come from an export.
+        let client_catalog = find(CATALOG_HEADER)
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .and_then(|v| v.trim().parse::<u32>().ok())
+            .unwrap_or(0);
+        if client_catalog > self.db.catalog {
+            debug!(
+                "client catalogue {} is newer than ours, {}",
+                client_catalog, self.db.catalog
+            );
+            return Err(409);
+        }
+        self.connection.version = version;
+        response.push(quiche::h3::Header::new(
+            VERSION_HEADER.as_bytes(),
+            version.to_string().as_bytes(),
+        ));
+        Ok(())
+    }
 
-        thread.spawn(fut);
-        std::result::Result::Ok(())
-        // return error is schema.procid is not authorized.
+    fn handshake_done(&mut self, out: &mut Replies) {
+        for (stream_id, buf, fin) in self.early.confirm() {
+            handle_read(
+                self.db,
+                self.thread,
+                self.connection(),
+                stream_id,
+                &buf,
+                fin,
+            );
+        }
+        self.flush(out);
+    }
+
+    fn handle_reset(&mut self, stream_id: u64) {
+        self.early.drop_stream(stream_id);
+        self.connection.stream.remove(&stream_id);
+    }
+
+    // what the worker's tasks sent since, see DbHandlers::poll
+    fn poll(&mut self, out: &mut Replies) {
+        self.flush(out);
+    }
+}
+
+impl Drop for DbHandler {
+    fn drop(&mut self) {
+        self.thread.close(self.connection);
+        unsafe { drop(Box::from_raw(self.connection.ptr)) };
     }
 }
 
@@ -0,0 +1,455 @@
+// procedures by name.
+//
+// the host registers each procedure as "schema.interface.procedure" with the shape of its
+// parameter block, and the registry hands out the numbers the wire uses: an interface id for
+// "schema.interface" and a procedure id within it. Db::iface is built from it.
+//
+// ids are handed out in registration order, so the same program gets the same ids. clients
+// compile the ids in, so they must not move when procedures are added or reordered: export
+// the map, keep it with the client, and seed the next registry with it. names it has keep
+// their ids, new names get ids nobody used before, and names gone from the program stay in
+// the map and leave a hole that answers ERR_NO_PROC.
+//
+// every export that added names is a new catalogue version. the client says which one its
+// ids came from when it connects, and a server with an older one refuses it; see
+// DbHandler::open_session.
+
+use std::{collections::HashMap, fmt};
+
+use super::{Connection, Db, DbThread, Iface, ParameterBlock, Proc, ProcFuture, ProcResult, Ptr};
+use crate::param::ParamBlockSchema;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RegisterError {
+    #[error("{0}: not a schema.interface.procedure name")]
+    BadName(String),
+
+    #[error("{0} is already registered")]
+    Duplicate(String),
+
+    #[error("{0} is not registered")]
+    Unknown(String),
+
+    #[error("{0}: {1}")]
+    Schema(String, &'static str),
+
+    #[error("no ids left in {0}")]
+    Full(String),
+
+    #[error("id map line {0}: {1}")]
+    IdMap(usize, &'static str),
+}
+
+// name -> (interface id, procedure id), what clients need to call by name
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdMap {
+    pub catalog: u32,
+    ids: HashMap<String, (u16, u16)>,
+}
+
+impl IdMap {
+    pub fn get(&self, name: &str) -> Option<(u16, u16)> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u16, u16)> {
+        self.ids.iter().map(|(n, &(i, p))| (n.as_str(), i, p))
+    }
+
+    // `catalog N`, then one `schema.interface.procedure iface proc` per line, as Display
+    // writes it
+    pub fn parse(text: &str) -> Result<Self, RegisterError> {
+        let mut map = Self::default();
+        let mut ifaces: HashMap<&str, u16> = HashMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(catalog) = line.strip_prefix("catalog ") {
+                map.catalog = catalog
+                    .trim()
+                    .parse()
+                    .map_err(|_| RegisterError::IdMap(n + 1, "bad catalog"))?;
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let (Some(name), Some(iface), Some(proc), None) =
+                (parts.next(), parts.next(), parts.next(), parts.next())
+            else {
+                return Err(RegisterError::IdMap(n + 1, "expected name and two ids"));
+            };
+            let (Ok(iface), Ok(proc)) = (iface.parse::<u16>(), proc.parse::<u16>()) else {
+                return Err(RegisterError::IdMap(n + 1, "bad id"));
+            };
+            let (iface_name, _) = split_name(name)?;
+            if *ifaces.entry(iface_name).or_insert(iface) != iface {
+                return Err(RegisterError::IdMap(n + 1, "interface has two ids"));
+            }
+            if ifaces
+                .iter()
+                .any(|(&other, &i)| i == iface && other != iface_name)
+            {
+                return Err(RegisterError::IdMap(n + 1, "two interfaces with one id"));
+            }
+            if map.ids.values().any(|&ids| ids == (iface, proc)) {
+                return Err(RegisterError::IdMap(n + 1, "two procedures with one id"));
+            }
+            if map.ids.insert(name.to_string(), (iface, proc)).is_some() {
+                return Err(RegisterError::IdMap(n + 1, "name twice"));
+            }
+        }
+        Ok(map)
+    }
+}
+
+impl fmt::Display for IdMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ids = self.iter().collect::<Vec<_>>();
+        ids.sort_by_key(|&(_, i, p)| (i, p));
+        writeln!(f, "catalog {}", self.catalog)?;
+        for (name, i, p) in ids {
+            writeln!(f, "{} {} {}", name, i, p)?;
+        }
+        Ok(())
+    }
+}
+
+struct Registered {
+    name: String,
+    schema: ParamBlockSchema,
+    proc: Proc,
+    idempotent: bool,
+}
+
+#[derive(Default)]
+struct IfaceSlot {
+    name: String,
+    // by procedure id; None is a hole
+    procs: Vec<Option<Registered>>,
+    // ids the seed map gave out in this interface
+    reserved: HashMap<String, u16>,
+}
+
+#[derive(Default)]
+pub struct Registry {
+    // by interface id; None is a hole
+    ifaces: Vec<Option<IfaceSlot>>,
+    by_name: HashMap<String, u16>,
+    // the catalogue of the seed, and whether anything was added to it
+    seed: u32,
+    grew: bool,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // keep the ids of an earlier export
+    pub fn with_ids(ids: &IdMap) -> Self {
+        let mut r = Self {
+            seed: ids.catalog,
+            ..Self::new()
+        };
+        for (name, iface, proc) in ids.iter() {
+            // parse checked the names
+            let Ok((iface_name, _)) = split_name(name) else {
+                continue;
+            };
+            let slot = r.slot_at(iface, iface_name);
+            slot.reserved.insert(name.to_string(), proc);
+        }
+        r
+    }
+
+    // a procedure that answers on the read path, see Proc::Inline
+    pub fn register_procedure(
+        &mut self,
+        name: &str,
+        schema: ParamBlockSchema,
+        f: fn(Ptr<Db>, Ptr<DbThread>, Ptr<Connection>, &ParameterBlock) -> ProcResult,
+    ) -> Result<(u16, u16), RegisterError> {
+        self.register(name, schema, Proc::Inline(f))
+    }
+
+    // a procedure that runs as a task, see Proc::Spawn
+    pub fn register_async_procedure(
+        &mut self,
+        name: &str,
+        schema: ParamBlockSchema,
+        f: fn(Ptr<Db>, Ptr<DbThread>, Ptr<Connection>, ParameterBlock) -> ProcFuture,
+    ) -> Result<(u16, u16), RegisterError> {
+        self.register(name, schema, Proc::Spawn(f))
+    }
+
+    pub fn register(
+        &mut self,
+        name: &str,
+        schema: ParamBlockSchema,
+        proc: Proc,
+    ) -> Result<(u16, u16), RegisterError> {
+        let (iface_name, _) = split_name(name)?;
+        schema
+            .validate()
+            .map_err(|e| RegisterError::Schema(name.to_string(), e))?;
+        let iface = match self.by_name.get(iface_name) {
+            Some(&i) => i,
+            None => {
+                let i = free_id(self.ifaces.len(), |i| {
+                    self.ifaces.get(i).is_some_and(|s| s.is_some())
+                })
+                .ok_or_else(|| RegisterError::Full("the registry".to_string()))?;
+                self.slot_at(i, iface_name);
+                i
+            }
+        };
+        let slot = self.ifaces[iface as usize].as_mut().unwrap();
+        if slot.procs.iter().flatten().any(|r| r.name == name) {
+            return Err(RegisterError::Duplicate(name.to_string()));
+        }
+        let id = match slot.reserved.get(name) {
+            Some(&id) => id,
+            None => {
+                self.grew = true;
+                let reserved = slot.reserved.values().copied().collect::<Vec<_>>();
+                let next = reserved.iter().max().map_or(0, |&m| m as usize + 1);
+                let used = |i: usize| {
+                    slot.procs.get(i).is_some_and(|p| p.is_some()) || reserved.contains(&(i as u16))
+                };
+                free_id(slot.procs.len().max(next), used)
+                    .ok_or_else(|| RegisterError::Full(iface_name.to_string()))?
+            }
+        };
+        if slot.procs.len() <= id as usize {
+            slot.procs.resize_with(id as usize + 1, || None);
+        }
+        slot.procs[id as usize] = Some(Registered {
+            name: name.to_string(),
+            schema,
+            proc,
+            idempotent: false,
+        });
+        Ok((iface, id))
+    }
+
+    // the procedure is safe to run twice, e.g. it only reads, so it may run from 0-rtt data
+    // that could be a replay. see quic::early.
+    pub fn set_idempotent(&mut self, name: &str) -> Result<(), RegisterError> {
+        let (iface_name, _) = split_name(name)?;
+        let r = self
+            .by_name
+            .get(iface_name)
+            .and_then(|&i| self.ifaces[i as usize].as_mut())
+            .and_then(|slot| slot.procs.iter_mut().flatten().find(|r| r.name == name))
+            .ok_or_else(|| RegisterError::Unknown(name.to_string()))?;
+        r.idempotent = true;
+        Ok(())
+    }
+
+    // the catalogue version of what's registered, for Db::catalog
+    pub fn catalog(&self) -> u32 {
+        self.seed + self.grew as u32
+    }
+
+    // the ids of everything registered, for clients, and of everything the seed had so they
+    // aren't given out again
+    pub fn export(&self) -> IdMap {
+        let mut map = IdMap {
+            catalog: self.catalog(),
+            ..Default::default()
+        };
+        for (i, slot) in self.ifaces.iter().enumerate() {
+            let Some(slot) = slot else {
+                continue;
+            };
+            for (name, &p) in &slot.reserved {
+                map.ids.insert(name.clone(), (i as u16, p));
+            }
+            for (p, r) in slot.procs.iter().enumerate() {
+                if let Some(r) = r {
+                    map.ids.insert(r.name.clone(), (i as u16, p as u16));
+                }
+            }
+        }
+        map
+    }
+
+    // Db::iface
+    pub fn build(self) -> Box<[Iface]> {
+        self.ifaces
+            .into_iter()
+            .map(|slot| {
+                let procs = slot.map(|s| s.procs).unwrap_or_default();
+                let procs = procs
+                    .into_iter()
+                    .map(|r| match r {
+                        Some(r) => (Some(r.proc), r.schema, r.idempotent),
+                        None => (None, ParamBlockSchema::default(), false),
+                    })
+                    .collect::<Vec<_>>();
+                Iface {
+                    proc: procs.iter().map(|p| p.0).collect(),
+                    schema: procs.iter().map(|p| p.1).collect(),
+                    idempotent: procs.iter().map(|p| p.2).collect(),
+                }
+            })
+            .collect()
+    }
+
+    fn slot_at(&mut self, iface: u16, name: &str) -> &mut IfaceSlot {
+        if self.ifaces.len() <= iface as usize {
+            self.ifaces.resize_with(iface as usize + 1, || None);
+        }
+        self.by_name.insert(name.to_string(), iface);
+        self.ifaces[iface as usize].get_or_insert_with(|| IfaceSlot {
+            name: name.to_string(),
+            ..Default::default()
+        })
+    }
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = self.ifaces.iter().flatten().map(|s| &s.name);
+        f.debug_list().entries(names).finish()
+    }
+}
+
+// "schema.interface.procedure" -> ("schema.interface", "procedure")
+fn split_name(name: &str) -> Result<(&str, &str), RegisterError> {
+    let ident =
+        |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_');
+    let parts = name.split('.').collect::<Vec<_>>();
+    match parts[..] {
+        [schema, iface, proc] if ident(schema) && ident(iface) && ident(proc) => {
+            Ok((&name[..schema.len() + 1 + iface.len()], proc))
+        }
+        _ => Err(RegisterError::BadName(name.to_string())),
+    }
+}
+
+// the first id not `used`, starting past the ones that ever were so a removed name's id
+// isn't given to another
+fn free_id(len: usize, used: impl Fn(usize) -> bool) -> Option<u16> {
+    (len..=u16::MAX as usize)
+        .chain(0..len)
+        .find(|&i| !used(i))
+        .map(|i| i as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: ParamBlockSchema = ParamBlockSchema {
+        binary: 0,
+        varlen: 1,
+        stored: 0,
+        temp: 0,
+        integer: 0,
+    };
+
+    fn nothing(_: Ptr<Db>, _: Ptr<DbThread>, _: Ptr<Connection>, _: &ParameterBlock) -> ProcResult {
+        Ok(Vec::new())
+    }
+
+    fn register(r: &mut Registry, name: &str) -> (u16, u16) {
+        r.register_procedure(name, SCHEMA, nothing).unwrap()
+    }
+
+    #[test]
+    fn ids_survive_removal() {
+        let mut r = Registry::new();
+        assert_eq!(register(&mut r, "app.a.x"), (0, 0));
+        assert_eq!(register(&mut r, "app.a.y"), (0, 1));
+        assert_eq!(register(&mut r, "app.b.z"), (1, 0));
+        assert_eq!(r.catalog(), 1);
+        let ids = r.export();
+
+        // x and all of b are gone, w is new
+        let mut r = Registry::with_ids(&ids);
+        assert_eq!(register(&mut r, "app.a.y"), (0, 1));
+        assert_eq!(r.catalog(), 1);
+        assert_eq!(register(&mut r, "app.a.w"), (0, 2));
+        assert_eq!(register(&mut r, "app.c.v"), (2, 0));
+        assert_eq!(r.catalog(), 2);
+        let next = r.export();
+        for name in ["app.a.x", "app.a.y", "app.b.z"] {
+            assert_eq!(next.get(name), ids.get(name), "{}", name);
+        }
+
+        // the holes are there and empty
+        let iface = r.build();
+        assert_eq!(iface.len(), 3);
+        assert!(iface[0].proc[0].is_none());
+        assert!(iface[0].proc[1].is_some() && iface[0].proc[2].is_some());
+        assert!(iface[1].proc.is_empty());
+
+        // an old seed with nothing new keeps its catalogue
+        let mut r = Registry::with_ids(&next);
+        register(&mut r, "app.c.v");
+        assert_eq!(r.catalog(), 2);
+    }
+
+    #[test]
+    fn bad_registrations() {
+        let mut r = Registry::new();
+        register(&mut r, "app.a.x");
+        let err = r.register_procedure("app.a.x", SCHEMA, nothing);
+        assert!(matches!(err, Err(RegisterError::Duplicate(_))));
+        for name in ["app.a", "app.a.x.y", "app..x", "app.a-b.x", ""] {
+            let err = r.register_procedure(name, SCHEMA, nothing);
+            assert!(matches!(err, Err(RegisterError::BadName(_))), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn idempotent() {
+        let mut r = Registry::new();
+        register(&mut r, "app.a.read");
+        register(&mut r, "app.a.write");
+        r.set_idempotent("app.a.read").unwrap();
+        assert!(matches!(
+            r.set_idempotent("app.a.delete"),
+            Err(RegisterError::Unknown(_))
+        ));
+        assert!(matches!(
+            r.set_idempotent("app.b.read"),
+            Err(RegisterError::Unknown(_))
+        ));
+        assert!(matches!(
+            r.set_idempotent("read"),
+            Err(RegisterError::BadName(_))
+        ));
+        assert_eq!(&*r.build()[0].idempotent, [true, false]);
+    }
+
+    #[test]
+    fn id_map_text() {
+        let mut r = Registry::new();
+        register(&mut r, "app.a.x");
+        register(&mut r, "app.b.y");
+        register(&mut r, "app.a.z");
+        let ids = r.export();
+        let text = ids.to_string();
+        assert_eq!(text, "catalog 1\napp.a.x 0 0\napp.a.z 0 1\napp.b.y 1 0\n");
+        assert_eq!(IdMap::parse(&text).unwrap(), ids);
+
+        let bad = [
+            ("catalog x", 1),
+            ("app.a.x 0", 1),
+            ("app.a.x 0 70000", 1),
+            ("app.a.x 0 0\napp.a.y 1 0", 2),
+            ("app.a.x 0 0\napp.b.y 0 1", 2),
+            ("app.a.x 0 0\napp.a.y 0 0", 2),
+            ("app.a.x 0 0\n\napp.a.x 0 1", 3),
+        ];
+        for (text, line) in bad {
+            match IdMap::parse(text) {
+                Err(RegisterError::IdMap(n, _)) => assert_eq!(n, line, "{:?}", text),
+                r => panic!("{:?}: {:?}", text, r),
+            }
+        }
+    }
+}
@@ -1,8 +1,14 @@
+pub mod admin;
+pub mod blob;
+pub mod channel;
+pub mod client;
 pub mod crypto;
+pub mod diag;
 pub mod error;
 pub mod exec;
 pub mod linux;
 pub mod param;
+pub mod quic;
 pub mod quiche;
 pub mod server;
 pub mod tls;
@@ -4,8 +4,13 @@ use io_uring::{opcode, types, IoUring};
 use rustls::{ServerConfig, ServerConnection, StreamOwned};
 
 use crate::crypto::load_tls_config;
+use crate::quic::altsvc::AltSvc;
 
-pub fn uring_handle_tls(mut stream: TcpStream, tls_config: Arc<ServerConfig>) {
+pub fn uring_handle_tls(
+    mut stream: TcpStream,
+    tls_config: Arc<ServerConfig>,
+    alt_svc: &AltSvc,
+) {
     let conn = ServerConnection::new(tls_config).unwrap();
     let mut tls = StreamOwned::new(conn, stream);
 
@@ -15,7 +20,8 @@ pub fn uring_handle_tls(mut stream: TcpStream, tls_config: Arc<ServerConfig>) {
 
         let body = b"Hello, world!";
         let response = format!(
-            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            "HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\n\r\n{}",
+            alt_svc.header(),
             body.len(),
             std::str::from_utf8(body).unwrap()
         );
@@ -27,6 +33,8 @@ pub fn uring_handle_tls(mut stream: TcpStream, tls_config: Arc<ServerConfig>) {
 
 pub fn web_hello(addr: String) -> std::io::Result<()> {
     let tls_config = load_tls_config();
+    // no quic here, nothing to advertise
+    let alt_svc = AltSvc::default();
     let listener = TcpListener::bind(addr)?;
     listener.set_nonblocking(true)?;
 
@@ -52,7 +60,7 @@ pub fn web_hello(addr: String) -> std::io::Result<()> {
             let conn_fd = cqe.result();
             if conn_fd >= 0 {
                 let stream = unsafe { TcpStream::from_raw_fd(conn_fd) };
-                uring_handle_tls(stream, tls_config.clone());
+                uring_handle_tls(stream, tls_config.clone(), &alt_svc);
             }
         }
     }
@@ -1,11 +1,27 @@
-use simpleweb::server::{init_server, MyConfig};
+use simpleweb::{
+    exec::{self, registry::Registry, Connection, Db, DbThread, ParameterBlock, ProcResult, Ptr},
+    param::ParamBlockSchema,
+    server::{init_server, MyConfig},
+};
 
 // each worker thread has its own executor. No stealing/helping.
 
 pub fn main() {
+    let mut registry = Registry::new();
+    let echo_schema = ParamBlockSchema {
+        varlen: 1,
+        ..Default::default()
+    };
+    registry
+        .register_procedure("example.echo.echo", echo_schema, echo)
+        .unwrap();
+    // it has no effects, so it may run from 0-rtt data
+    registry.set_idempotent("example.echo.echo").unwrap();
+
     let config = MyConfig {
         host: "127.0.0.1:8321".to_string(),
         threads: 1,
+        stream_handler: exec::serve(registry.catalog(), registry.build()),
         .. MyConfig::default()
     };
 
@@ -13,7 +29,15 @@ pub fn main() {
     server.join();
 }
 
-// I should put some rpcs here so it looks like a host example
+// sends back its one varlen parameter
+fn echo(
+    _db: Ptr<Db>,
+    _thread: Ptr<DbThread>,
+    _connection: Ptr<Connection>,
+    pm: &ParameterBlock,
+) -> ProcResult {
+    Ok(pm.params().varlen.get(0).unwrap_or_default().to_vec())
+}
 
 // fn dummy_waker() -> Waker {
 //     fn no_op(_: *const ()) {}
@@ -0,0 +1,437 @@
+// the integer runs of a parameter block: the lengths, and the integer fields.
+//
+// which encoding is negotiated with the protocol version, see for_version. the readme went
+// back and forth on stream vbyte and fastlanes; for the handful of integers a procedure takes
+// those are overkill, and what matters is that small numbers (lengths, ids, counts) are small
+// on the wire without making big ones slow. bin/intbench.rs compares them.
+//
+// Fixed: 8 bytes little endian each.
+// Varint: LEB128, 7 bits a byte, the high bit set on all but the last. at most 10 bytes.
+// Group: groups of 4, a control byte with 2 bits a value for its length, 1, 2, 4 or 8 bytes,
+//        lowest bits first, then the values little endian. the last group has the codes of the
+//        values it doesn't have 0, and no bytes for them. a group decodes with two shuffles.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IntEncoding {
+    #[default]
+    Fixed,
+    Varint,
+    Group,
+}
+
+impl IntEncoding {
+    pub fn for_version(version: u16) -> Option<Self> {
+        match version {
+            1 => Some(Self::Fixed),
+            2 => Some(Self::Varint),
+            3 => Some(Self::Group),
+            _ => None,
+        }
+    }
+
+    // the fewest bytes a value can take, for the schema's size checks
+    pub const fn min_len(self) -> usize {
+        match self {
+            Self::Fixed => 8,
+            Self::Varint | Self::Group => 1,
+        }
+    }
+}
+
+// why a run didn't decode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bad {
+    // the buffer ended; it needs at least this many bytes in all
+    Short(usize),
+    // a varint over 64 bits
+    Malformed,
+}
+
+pub fn encode(enc: IntEncoding, values: &[u64], out: &mut Vec<u8>) {
+    match enc {
+        IntEncoding::Fixed => {
+            for v in values {
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        IntEncoding::Varint => {
+            for &v in values {
+                varint(v, out);
+            }
+        }
+        IntEncoding::Group => {
+            // every value is written whole and the next one over the bytes it didn't need
+            let mut group = [0u8; 1 + 4 * 8];
+            for values in values.chunks(4) {
+                let mut ctrl = 0;
+                let mut len = 1;
+                for (i, &v) in values.iter().enumerate() {
+                    let code = code(v);
+                    ctrl |= code << (2 * i);
+                    group[len..len + 8].copy_from_slice(&v.to_le_bytes());
+                    len += LENS[code as usize];
+                }
+                group[0] = ctrl;
+                out.extend_from_slice(&group[..len]);
+            }
+        }
+    }
+}
+
+// the bytes encode would write
+pub fn encoded_len(enc: IntEncoding, values: &[u64]) -> usize {
+    match enc {
+        IntEncoding::Fixed => values.len() * 8,
+        IntEncoding::Varint => values
+            .iter()
+            .map(|&v| (64 - (v | 1).leading_zeros() as usize).div_ceil(7))
+            .sum(),
+        IntEncoding::Group => {
+            let data = values.iter().map(|&v| LENS[code(v) as usize]);
+            values.len().div_ceil(4) + data.sum::<usize>()
+        }
+    }
+}
+
+// how many bytes the run of n values at the start of buf takes, without decoding it
+pub fn measure(enc: IntEncoding, buf: &[u8], n: usize) -> Result<usize, Bad> {
+    let len = match enc {
+        IntEncoding::Fixed => n * 8,
+        IntEncoding::Varint => {
+            let mut left = n;
+            let mut len = 0;
+            while left > 0 {
+                let Some(&b) = buf.get(len) else {
+                    return Err(Bad::Short(len + left));
+                };
+                left -= (b & 0x80 == 0) as usize;
+                len += 1;
+            }
+            len
+        }
+        IntEncoding::Group => {
+            let mut len = 0;
+            for group in 0..n.div_ceil(4) {
+                let Some(&ctrl) = buf.get(len) else {
+                    return Err(Bad::Short(len + 1));
+                };
+                len += 1 + group_len(ctrl, (n - group * 4).min(4));
+            }
+            len
+        }
+    };
+    match len > buf.len() {
+        true => Err(Bad::Short(len)),
+        false => Ok(len),
+    }
+}
+
+// the n values at the start of buf, appended to out. returns the bytes they took.
+pub fn decode(enc: IntEncoding, buf: &[u8], n: usize, out: &mut Vec<u64>) -> Result<usize, Bad> {
+    out.reserve(n);
+    match enc {
+        IntEncoding::Fixed => {
+            let len = measure(enc, buf, n)?;
+            let values = buf[..len].chunks_exact(8);
+            out.extend(values.map(|c| u64::from_le_bytes(c.try_into().unwrap())));
+            Ok(len)
+        }
+        IntEncoding::Varint => {
+            let mut pos = 0;
+            for _ in 0..n {
+                // most are one byte
+                if let Some(&b) = buf.get(pos).filter(|&&b| b < 0x80) {
+                    out.push(b as u64);
+                    pos += 1;
+                    continue;
+                }
+                let (v, len) = read_varint(&buf[pos..]).map_err(|e| match e {
+                    Bad::Short(k) => Bad::Short(pos + k),
+                    e => e,
+                })?;
+                out.push(v);
+                pos += len;
+            }
+            Ok(pos)
+        }
+        IntEncoding::Group => {
+            let mut pos = 0;
+            let mut left = n;
+            simd::groups(buf, &mut pos, &mut left, out);
+            while left > 0 {
+                let Some(&ctrl) = buf.get(pos) else {
+                    return Err(Bad::Short(pos + 1));
+                };
+                let k = left.min(4);
+                let len = group_len(ctrl, k);
+                let data = &buf[pos + 1..];
+                if data.len() < len {
+                    return Err(Bad::Short(pos + 1 + len));
+                }
+                let mut v = [0u64; 4];
+                scalar_group(data, ctrl, k, &mut v);
+                out.extend_from_slice(&v[..k]);
+                pos += 1 + len;
+                left -= k;
+            }
+            Ok(pos)
+        }
+    }
+}
+
+const LENS: [usize; 4] = [1, 2, 4, 8];
+
+fn code(v: u64) -> u8 {
+    match v {
+        0..=0xff => 0,
+        0x100..=0xffff => 1,
+        0x1_0000..=0xffff_ffff => 2,
+        _ => 3,
+    }
+}
+
+// the data bytes of the first k values of a group
+fn group_len(ctrl: u8, k: usize) -> usize {
+    (0..k).map(|i| LENS[(ctrl >> (2 * i)) as usize & 3]).sum()
+}
+
+fn varint(mut v: u64, out: &mut Vec<u8>) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn read_varint(buf: &[u8]) -> Result<(u64, usize), Bad> {
+    let mut v = 0u64;
+    for (i, &b) in buf.iter().enumerate() {
+        // the 10th byte has room for one bit
+        if i == 9 && b > 1 {
+            return Err(Bad::Malformed);
+        }
+        v |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            return Ok((v, i + 1));
+        }
+    }
+    Err(Bad::Short(buf.len() + 1))
+}
+
+fn scalar_group(data: &[u8], ctrl: u8, k: usize, out: &mut [u64; 4]) {
+    let mut pos = 0;
+    for (i, v) in out.iter_mut().enumerate().take(k) {
+        let len = LENS[(ctrl >> (2 * i)) as usize & 3];
+        let mut b = [0u8; 8];
+        b[..len].copy_from_slice(&data[pos..pos + len]);
+        *v = u64::from_le_bytes(b);
+        pos += len;
+    }
+}
+
+// whole groups, while there are 32 bytes after the control byte to load. two values go in
+// each 16 byte register; the first two are in the first 16 bytes of data and the last two in
+// the 16 after them, so each pair is one pshufb with a mask from the control byte.
+#[cfg(target_arch = "x86_64")]
+mod simd {
+    use std::arch::x86_64::*;
+
+    use super::LENS;
+
+    // by control byte: the masks for the first pair and the second, and the data bytes
+    static MASKS: [[[u8; 16]; 2]; 256] = masks();
+    static DATA_LEN: [u8; 256] = data_len();
+
+    const fn masks() -> [[[u8; 16]; 2]; 256] {
+        let mut m = [[[0x80u8; 16]; 2]; 256];
+        let mut ctrl = 0;
+        while ctrl < 256 {
+            let mut pair = 0;
+            while pair < 2 {
+                let a = LENS[(ctrl >> (4 * pair)) & 3];
+                let b = LENS[(ctrl >> (4 * pair + 2)) & 3];
+                let mut i = 0;
+                while i < a {
+                    m[ctrl][pair][i] = i as u8;
+                    i += 1;
+                }
+                let mut i = 0;
+                while i < b {
+                    m[ctrl][pair][8 + i] = (a + i) as u8;
+                    i += 1;
+                }
+                pair += 1;
+            }
+            ctrl += 1;
+        }
+        m
+    }
+
+    const fn data_len() -> [u8; 256] {
+        let mut l = [0u8; 256];
+        let mut ctrl = 0;
+        while ctrl < 256 {
+            let mut i = 0;
+            while i < 4 {
+                l[ctrl] += LENS[(ctrl >> (2 * i)) & 3] as u8;
+                i += 1;
+            }
+            ctrl += 1;
+        }
+        l
+    }
+
+    pub fn groups(buf: &[u8], pos: &mut usize, left: &mut usize, out: &mut Vec<u64>) {
+        if std::is_x86_feature_detected!("ssse3") {
+            // SAFETY: ssse3 is there
+            unsafe { shuffle(buf, pos, left, out) }
+        }
+    }
+
+    #[target_feature(enable = "ssse3")]
+    unsafe fn shuffle(buf: &[u8], pos: &mut usize, left: &mut usize, out: &mut Vec<u64>) {
+        while *left >= 4 && buf.len() - *pos > 32 {
+            let ctrl = buf[*pos] as usize;
+            let first = LENS[ctrl & 3] + LENS[(ctrl >> 2) & 3];
+            let [lo, hi] = &MASKS[ctrl];
+            // both loads are in the 32 bytes after ctrl
+            let p = buf.as_ptr().add(*pos + 1);
+            let a = _mm_loadu_si128(p as *const __m128i);
+            let b = _mm_loadu_si128(p.add(first) as *const __m128i);
+            let a = _mm_shuffle_epi8(a, _mm_loadu_si128(lo.as_ptr() as *const __m128i));
+            let b = _mm_shuffle_epi8(b, _mm_loadu_si128(hi.as_ptr() as *const __m128i));
+            out.reserve(4);
+            let len = out.len();
+            let o = out.as_mut_ptr().add(len) as *mut __m128i;
+            _mm_storeu_si128(o, a);
+            _mm_storeu_si128(o.add(1), b);
+            out.set_len(len + 4);
+            *pos += 1 + DATA_LEN[ctrl] as usize;
+            *left -= 4;
+        }
+    }
+}
+
+// everything is left to the scalar loop
+#[cfg(not(target_arch = "x86_64"))]
+mod simd {
+    pub fn groups(_buf: &[u8], _pos: &mut usize, _left: &mut usize, _out: &mut Vec<u64>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENCODINGS: [IntEncoding; 3] =
+        [IntEncoding::Fixed, IntEncoding::Varint, IntEncoding::Group];
+
+    // 0, u64::MAX, and either side of every power of two
+    fn edges() -> Vec<u64> {
+        let mut v = vec![0, u64::MAX];
+        for k in 0..64 {
+            v.extend([(1 << k) - 1, 1 << k, (1 << k) + 1]);
+        }
+        v
+    }
+
+    #[test]
+    fn round_trip() {
+        let edges = edges();
+        for enc in ENCODINGS {
+            // each alone, and all of them in every group position
+            let runs = edges
+                .iter()
+                .map(|v| vec![*v])
+                .chain((0..4).map(|skip| edges[skip..].to_vec()));
+            for values in runs {
+                let mut buf = Vec::new();
+                encode(enc, &values, &mut buf);
+                assert_eq!(encoded_len(enc, &values), buf.len());
+                assert_eq!(measure(enc, &buf, values.len()), Ok(buf.len()));
+                let mut out = Vec::new();
+                assert_eq!(decode(enc, &buf, values.len(), &mut out), Ok(buf.len()));
+                assert_eq!(out, values);
+            }
+        }
+    }
+
+    // decode, without the simd
+    fn scalar(buf: &[u8], n: usize) -> Vec<u64> {
+        let mut out = Vec::new();
+        let mut pos = 0;
+        for group in 0..n.div_ceil(4) {
+            let k = (n - group * 4).min(4);
+            let mut v = [0u64; 4];
+            scalar_group(&buf[pos + 1..], buf[pos], k, &mut v);
+            out.extend_from_slice(&v[..k]);
+            pos += 1 + group_len(buf[pos], k);
+        }
+        out
+    }
+
+    // the simd stops short of the end of the buffer, so how much follows the run decides
+    // where the scalar loop takes over
+    #[test]
+    fn simd_matches_scalar() {
+        let edges = edges();
+        for n in [1, 3, 4, 5, 8, 13, 16, 64, edges.len()] {
+            let values = edges
+                .iter()
+                .cycle()
+                .skip(n)
+                .take(n)
+                .copied()
+                .collect::<Vec<_>>();
+            let mut run = Vec::new();
+            encode(IntEncoding::Group, &values, &mut run);
+            assert_eq!(scalar(&run, n), values);
+            for tail in 0..=32 {
+                let mut buf = run.clone();
+                buf.resize(run.len() + tail, 0xa5);
+                let mut out = Vec::new();
+                assert_eq!(decode(IntEncoding::Group, &buf, n, &mut out), Ok(run.len()));
+                assert_eq!(out, values, "{} values, {} bytes after", n, tail);
+            }
+        }
+        // every control byte
+        for ctrl in 0..=255u8 {
+            let mut buf = vec![ctrl];
+            buf.extend((1..=64).map(|b| b as u8));
+            let mut out = Vec::new();
+            let len = decode(IntEncoding::Group, &buf, 4, &mut out).unwrap();
+            assert_eq!(len, 1 + group_len(ctrl, 4));
+            assert_eq!(out, scalar(&buf, 4));
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let values = edges();
+        for enc in ENCODINGS {
+            let mut buf = Vec::new();
+            encode(enc, &values, &mut buf);
+            for at in 0..buf.len() {
+                let short = &buf[..at];
+                assert!(matches!(measure(enc, short, values.len()), Err(Bad::Short(n)) if n > at));
+                let mut out = Vec::new();
+                assert!(matches!(
+                    decode(enc, short, values.len(), &mut out),
+                    Err(Bad::Short(n)) if n > at
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_varint() {
+        let mut out = Vec::new();
+        let over = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02];
+        assert_eq!(
+            decode(IntEncoding::Varint, &over, 1, &mut out),
+            Err(Bad::Malformed)
+        );
+        let max = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        assert_eq!(decode(IntEncoding::Varint, &max, 1, &mut out), Ok(10));
+        assert_eq!(out, [u64::MAX]);
+    }
+}
@@ -1,5 +1,14 @@
 // parse a parameter block
 
+pub mod int;
+pub use int::IntEncoding;
+
+// the parameter block is always cached, so it must be under this
+pub const MAX_INLINE: usize = 256 * 1024;
+// of each kind of field
+pub const MAX_FIELDS: usize = u16::MAX as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub struct ParamBlockSchema {
     pub binary: usize,
     pub varlen: usize,
@@ -8,58 +17,1024 @@ pub struct ParamBlockSchema {
     pub integer: usize,
 }
 
-pub struct StoredBlob {
-    pub data: Vec<u8>,
+impl ParamBlockSchema {
+    // whether a block of this shape can be sent at all. every integer and length takes at
+    // least a byte, so the smallest block has to fit the inline limit.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let counts = [self.varlen, self.stored, self.temp, self.integer];
+        if counts.iter().any(|&n| n > MAX_FIELDS) {
+            return Err("too many fields");
+        }
+        if self.binary > MAX_INLINE {
+            return Err("binary part over the inline limit");
+        }
+        if self.binary + counts.iter().sum::<usize>() > MAX_INLINE {
+            return Err("smallest block over the inline limit");
+        }
+        Ok(())
+    }
+}
+
+// the wire format, in order:
+//
+// 1. binary: schema.binary bytes
+// 2. one run of integers: the lengths of the temp, stored and varlen fields
+// 3. another: the integer fields
+// 4. the varlen bytes, the stored bytes, then the temp bytes, each field after the other
+//
+// the runs are 8 bytes little endian each in protocol version 1, and compact in later ones,
+// see int.rs. the binary part comes first so it can be aligned. everything but the stored and
+// temp bytes is inline: cached for as long as the statement runs, so it has to fit
+// MAX_INLINE. the blobs may be spooled, see blob.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    Binary,
+    Lengths,
+    Integer,
+    Varlen,
+    Stored,
+    Temp,
+}
+
+impl std::fmt::Display for Section {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Section::Binary => "binary",
+            Section::Lengths => "length",
+            Section::Integer => "integer",
+            Section::Varlen => "varlen",
+            Section::Stored => "stored",
+            Section::Temp => "temp",
+        };
+        f.write_str(name)
+    }
 }
-pub struct TempBlob {
-    pub data: Vec<u8>,
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ParamError {
+    #[error("schema: {0}")]
+    Schema(&'static str),
+
+    #[error("truncated in the {section} section: {need} bytes needed, {have} left")]
+    Truncated {
+        section: Section,
+        need: usize,
+        have: usize,
+    },
+
+    #[error("{section} length {len} overflows")]
+    Overflow { section: Section, len: u64 },
+
+    #[error("inline part of {0} bytes is over the {MAX_INLINE} byte limit")]
+    TooLarge(usize),
+
+    #[error("{0} bytes after the end")]
+    Trailing(usize),
+
+    #[error("malformed integer in the {0} section")]
+    Integer(Section),
+
+    #[error("{section} section has {got}, the schema says {expected}")]
+    Fields {
+        section: Section,
+        expected: usize,
+        got: usize,
+    },
+}
+
+// fields of one kind, each a slice of the input
+#[derive(Debug, Clone, Default)]
+pub struct Fields<'a> {
+    data: &'a [u8],
+    // where each field ends in data
+    end: Box<[usize]>,
+}
+
+impl<'a> Fields<'a> {
+    pub fn len(&self) -> usize {
+        self.end.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.end.is_empty()
+    }
+
+    // None past the end, or when the bytes aren't here, see parse_inline
+    pub fn get(&self, i: usize) -> Option<&'a [u8]> {
+        self.data.get(self.range(i)?)
+    }
+
+    pub fn field_len(&self, i: usize) -> Option<usize> {
+        self.range(i).map(|r| r.len())
+    }
+
+    fn range(&self, i: usize) -> Option<std::ops::Range<usize>> {
+        let end = *self.end.get(i)?;
+        let start = match i {
+            0 => 0,
+            _ => self.end[i - 1],
+        };
+        Some(start..end)
+    }
+
+    // where each field ends, from their lengths
+    fn ends(section: Section, lengths: &[u64]) -> Result<Box<[usize]>, ParamError> {
+        let mut total = 0usize;
+        lengths
+            .iter()
+            .map(|&len| {
+                total = usize::try_from(len)
+                    .ok()
+                    .and_then(|len| total.checked_add(len))
+                    .ok_or(ParamError::Overflow { section, len })?;
+                Ok(total)
+            })
+            .collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'a [u8]> + '_ {
+        (0..self.len()).filter_map(move |i| self.get(i))
+    }
+
+    // all of them, back to back
+    pub fn bytes(&self) -> &'a [u8] {
+        self.data
+    }
 }
 
-pub struct ParamBlock {
+// a decoded parameter block, borrowing its bytes from the input
+#[derive(Debug, Clone, Default)]
+pub struct ParamBlock<'a> {
+    pub binary: &'a [u8],
     pub integer: Box<[u64]>,
-    pub stored: Box<[StoredBlob]>,
-    pub temp: Box<[TempBlob]>,
-    pub binary: Box<[u8]>,
-    pub varlen: Box<[[u8]]>,
+    pub varlen: Fields<'a>,
+    pub stored: Fields<'a>,
+    pub temp: Fields<'a>,
+    inline_len: usize,
 }
 
-pub fn parse_complete(input: &[u8], schema: &ParamBlockSchema) -> Result<ParamBlock, String> {
-    let mut integer = vec![0; schema.integer];
-    let mut stored = vec![StoredBlob::default(); schema.stored];
-    let mut temp = vec![TempBlob::default(); schema.temp];
-    let mut binary = vec![0; schema.binary];
-    let mut varlen = vec![vec![0; 0]; schema.varlen];
+impl<'a> ParamBlock<'a> {
+    // the bytes that have to stay cached
+    pub fn inline_len(&self) -> usize {
+        self.inline_len
+    }
 
-    // parse the input buffer into the param block
-    // ...
+    // the integers as the builder's typed writers put them
+    pub fn u64(&self, i: usize) -> Option<u64> {
+        self.integer.get(i).copied()
+    }
+    pub fn i64(&self, i: usize) -> Option<i64> {
+        self.u64(i).map(|v| v as i64)
+    }
+    pub fn f64(&self, i: usize) -> Option<f64> {
+        self.u64(i).map(f64::from_bits)
+    }
+    pub fn bool(&self, i: usize) -> Option<bool> {
+        self.u64(i).map(|v| v != 0)
+    }
+    pub fn str(&self, i: usize) -> Option<&'a str> {
+        std::str::from_utf8(self.varlen.get(i)?).ok()
+    }
+}
 
-    Ok(ParamBlock {
-        integer: integer.into_boxed_slice(),
-        stored: stored.into_boxed_slice(),
-        temp: temp.into_boxed_slice(),
-        binary: binary.into_boxed_slice(),
-        varlen: varlen.into_boxed_slice(),
-    })
+// reads the input front to back
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    enc: IntEncoding,
 }
 
-async fn parse<R: AsyncRead + Unpin>(
-    reader: &mut R,
+impl<'a> Reader<'a> {
+    fn take(&mut self, section: Section, need: usize) -> Result<&'a [u8], ParamError> {
+        let have = self.buf.len() - self.pos;
+        if need > have {
+            return Err(ParamError::Truncated {
+                section,
+                need,
+                have,
+            });
+        }
+        let r = &self.buf[self.pos..self.pos + need];
+        self.pos += need;
+        Ok(r)
+    }
+
+    fn integers(&mut self, section: Section, n: usize) -> Result<Vec<u64>, ParamError> {
+        let buf = &self.buf[self.pos..];
+        let mut v = Vec::new();
+        match int::decode(self.enc, buf, n, &mut v) {
+            Ok(len) => {
+                self.pos += len;
+                Ok(v)
+            }
+            Err(int::Bad::Short(need)) => Err(ParamError::Truncated {
+                section,
+                need,
+                have: buf.len(),
+            }),
+            Err(int::Bad::Malformed) => Err(ParamError::Integer(section)),
+        }
+    }
+
+    // the lengths run, split into temp, stored and varlen
+    fn lengths(&mut self, schema: &ParamBlockSchema) -> Result<[Vec<u64>; 3], ParamError> {
+        let mut temp = self.integers(
+            Section::Lengths,
+            schema.temp + schema.stored + schema.varlen,
+        )?;
+        let mut stored = temp.split_off(schema.temp);
+        let varlen = stored.split_off(schema.stored);
+        Ok([temp, stored, varlen])
+    }
+
+    fn fields(&mut self, section: Section, lengths: &[u64]) -> Result<Fields<'a>, ParamError> {
+        let end = Fields::ends(section, lengths)?;
+        let total = end.last().copied().unwrap_or(0);
+        Ok(Fields {
+            data: self.take(section, total)?,
+            end,
+        })
+    }
+}
+
+// a whole block that is already in memory. nothing is copied but the integers.
+pub fn parse_complete<'a>(
+    input: &'a [u8],
+    schema: &ParamBlockSchema,
+    enc: IntEncoding,
+) -> Result<ParamBlock<'a>, ParamError> {
+    decode(input, schema, enc, true)
+}
+
+// the inline part of a block whose blobs went elsewhere, see Parser
+pub fn parse_inline<'a>(
+    input: &'a [u8],
     schema: &ParamBlockSchema,
-) -> Result<ParamBlock, String> {
-    let mut integer = vec![0; schema.integer];
-    let mut stored = vec![StoredBlob::default(); schema.stored];
-    let mut temp = vec![TempBlob::default(); schema.temp];
-    let mut binary = vec![0; schema.binary];
-    let mut varlen = vec![vec![0; 0]; schema.varlen];
+    enc: IntEncoding,
+) -> Result<ParamBlock<'a>, ParamError> {
+    decode(input, schema, enc, false)
+}
 
-    // parse the input buffer into the param block
-    // ...
+fn decode<'a>(
+    input: &'a [u8],
+    schema: &ParamBlockSchema,
+    enc: IntEncoding,
+    blobs: bool,
+) -> Result<ParamBlock<'a>, ParamError> {
+    schema.validate().map_err(ParamError::Schema)?;
+    let mut r = Reader {
+        buf: input,
+        pos: 0,
+        enc,
+    };
+
+    let binary = r.take(Section::Binary, schema.binary)?;
+    let [temp_len, stored_len, varlen_len] = r.lengths(schema)?;
+    let integer = r.integers(Section::Integer, schema.integer)?.into();
+    let varlen = r.fields(Section::Varlen, &varlen_len)?;
+    // the varlen bytes end the inline part
+    let inline_len = r.pos;
+    if inline_len > MAX_INLINE {
+        return Err(ParamError::TooLarge(inline_len));
+    }
+    let (stored, temp) = match blobs {
+        true => (
+            r.fields(Section::Stored, &stored_len)?,
+            r.fields(Section::Temp, &temp_len)?,
+        ),
+        false => (
+            Fields {
+                data: &[],
+                end: Fields::ends(Section::Stored, &stored_len)?,
+            },
+            Fields {
+                data: &[],
+                end: Fields::ends(Section::Temp, &temp_len)?,
+            },
+        ),
+    };
+    if r.pos != input.len() {
+        return Err(ParamError::Trailing(input.len() - r.pos));
+    }
 
     Ok(ParamBlock {
-        integer: integer.into_boxed_slice(),
-        stored: stored.into_boxed_slice(),
-        temp: temp.into_boxed_slice(),
-        binary: binary.into_boxed_slice(),
-        varlen: varlen.into_boxed_slice(),
+        binary,
+        integer,
+        varlen,
+        stored,
+        temp,
+        inline_len,
     })
 }
+
+// a block arriving a packet at a time, as quic and websocket deliver a stream. the inline part
+// is collected, and is the only part that is; each blob goes to the sink as it arrives:
+//
+//   start(section, index, len)
+//   middle(section, index, &[u8])
+//   finish(section, index)
+//
+// once push returns true the whole block is in. `block` then decodes the inline part; the
+// stored and temp fields have their lengths but not their bytes, those went to the sink.
+pub trait BlobSink {
+    // a section of the inline part is complete; `inline` is all of it so far
+    fn section(&mut self, _section: Section, _inline: &[u8]) {}
+    fn start(&mut self, section: Section, index: usize, len: u64);
+    fn middle(&mut self, section: Section, index: usize, buf: &[u8]);
+    fn finish(&mut self, section: Section, index: usize);
+}
+
+pub struct Parser {
+    schema: ParamBlockSchema,
+    enc: IntEncoding,
+    inline: Vec<u8>,
+    // the inline section being read, and where it starts and ends in inline. the end of an
+    // integer run is only known once all of it is here; until then it's as far as it has to
+    // go at least.
+    section: Section,
+    start: usize,
+    end: usize,
+    // the blob being read, as an index into blobs, and what's left of it
+    blob: usize,
+    left: u64,
+    started: bool,
+    // stored then temp, in wire order
+    blobs: Vec<(Section, usize, u64)>,
+    done: bool,
+}
+
+impl Parser {
+    pub fn new(schema: &ParamBlockSchema, enc: IntEncoding) -> Result<Self, ParamError> {
+        schema.validate().map_err(ParamError::Schema)?;
+        Ok(Self {
+            schema: *schema,
+            enc,
+            inline: Vec::new(),
+            section: Section::Binary,
+            start: 0,
+            end: schema.binary,
+            blob: 0,
+            left: 0,
+            started: false,
+            blobs: Vec::new(),
+            done: false,
+        })
+    }
+
+    // the next piece of the stream. true once the block is complete, which has to be by fin.
+    pub fn push(
+        &mut self,
+        mut buf: &[u8],
+        fin: bool,
+        sink: &mut impl BlobSink,
+    ) -> Result<bool, ParamError> {
+        while !self.done {
+            if let Some(n) = self.run() {
+                // take what there is, then give back what's past the run
+                let had = self.inline.len();
+                self.inline.extend_from_slice(buf);
+                match int::measure(self.enc, &self.inline[self.start..], n) {
+                    Ok(len) => {
+                        self.end = self.start + len;
+                        self.inline.truncate(self.end);
+                        buf = &buf[self.end - had..];
+                    }
+                    Err(int::Bad::Short(need)) => {
+                        self.end = self.start + need;
+                        if self.inline.len() > MAX_INLINE {
+                            return Err(ParamError::TooLarge(self.inline.len()));
+                        }
+                        buf = &[];
+                        break;
+                    }
+                    Err(int::Bad::Malformed) => return Err(ParamError::Integer(self.section)),
+                }
+                sink.section(self.section, &self.inline);
+                self.next_section()?;
+                continue;
+            }
+            if !self.blobs_started() {
+                let n = (self.end - self.inline.len()).min(buf.len());
+                self.inline.extend_from_slice(&buf[..n]);
+                buf = &buf[n..];
+                if self.inline.len() < self.end {
+                    break;
+                }
+                sink.section(self.section, &self.inline);
+                self.next_section()?;
+                continue;
+            }
+            let Some(&(section, index, len)) = self.blobs.get(self.blob) else {
+                self.done = true;
+                break;
+            };
+            if !self.started {
+                sink.start(section, index, len);
+                self.started = true;
+                self.left = len;
+            }
+            let n = (self.left.min(buf.len() as u64)) as usize;
+            if n > 0 {
+                sink.middle(section, index, &buf[..n]);
+                buf = &buf[n..];
+                self.left -= n as u64;
+            }
+            if self.left > 0 {
+                break;
+            }
+            sink.finish(section, index);
+            self.started = false;
+            self.blob += 1;
+        }
+        if self.done && !buf.is_empty() {
+            return Err(ParamError::Trailing(buf.len()));
+        }
+        if fin && !self.done {
+            return Err(self.truncated());
+        }
+        Ok(self.done)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    // the inline part, as far as it arrived
+    pub fn inline(&self) -> &[u8] {
+        &self.inline
+    }
+
+    // the inline part decoded, once the block is complete
+    pub fn block(&self) -> Option<ParamBlock<'_>> {
+        match self.done {
+            true => parse_inline(&self.inline, &self.schema, self.enc).ok(),
+            false => None,
+        }
+    }
+
+    pub fn into_inline(self) -> Box<[u8]> {
+        self.inline.into()
+    }
+
+    fn blobs_started(&self) -> bool {
+        self.section == Section::Stored
+    }
+
+    // how many integers, when the section is a run of them
+    fn run(&self) -> Option<usize> {
+        let s = &self.schema;
+        match self.section {
+            Section::Lengths => Some(s.temp + s.stored + s.varlen),
+            Section::Integer => Some(s.integer),
+            _ => None,
+        }
+    }
+
+    // the section after the one just read, and how much inline it needs
+    fn next_section(&mut self) -> Result<(), ParamError> {
+        let s = &self.schema;
+        let least = |n: usize| n * self.enc.min_len();
+        self.start = self.end;
+        (self.section, self.end) = match self.section {
+            Section::Binary => (
+                Section::Lengths,
+                self.end + least(s.temp + s.stored + s.varlen),
+            ),
+            Section::Lengths => (Section::Integer, self.end + least(s.integer)),
+            Section::Integer => {
+                let mut r = Reader {
+                    buf: &self.inline,
+                    pos: s.binary,
+                    enc: self.enc,
+                };
+                let [temp, stored, varlen] = r.lengths(s)?;
+                let varlen = Fields::ends(Section::Varlen, &varlen)?;
+                let total = varlen.last().copied().unwrap_or(0);
+                let end = self.end.saturating_add(total);
+                // before buffering any of it
+                if end > MAX_INLINE {
+                    return Err(ParamError::TooLarge(end));
+                }
+                let stored = stored
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &l)| (Section::Stored, i, l));
+                let temp = temp.iter().enumerate().map(|(i, &l)| (Section::Temp, i, l));
+                self.blobs = stored.chain(temp).collect();
+                (Section::Varlen, end)
+            }
+            Section::Varlen | Section::Stored | Section::Temp => (Section::Stored, self.end),
+        };
+        Ok(())
+    }
+
+    fn truncated(&self) -> ParamError {
+        if !self.blobs_started() {
+            return ParamError::Truncated {
+                section: self.section,
+                need: self.end - self.start,
+                have: self.inline.len() - self.start,
+            };
+        }
+        let (section, _, len) = self.blobs[self.blob];
+        ParamError::Truncated {
+            section,
+            need: len as usize,
+            have: (len - self.left) as usize,
+        }
+    }
+}
+
+// writes a block in the order above. the fields of each kind are added in order, and build
+// checks there are as many as the schema says. procedures build their results with it too.
+//
+//   let mut b = ParamBlockBuilder::new(&schema, enc)?;
+//   b.u64(7)?.str("name")?.stored(&bytes)?;
+//   let block = b.build()?;
+#[derive(Debug, Clone)]
+pub struct ParamBlockBuilder {
+    schema: ParamBlockSchema,
+    enc: IntEncoding,
+    binary: Vec<u8>,
+    integer: Vec<u64>,
+    varlen: Lengths,
+    stored: Lengths,
+    temp: Lengths,
+}
+
+// fields of one kind, back to back
+#[derive(Debug, Clone, Default)]
+struct Lengths {
+    len: Vec<u64>,
+    data: Vec<u8>,
+}
+
+impl Lengths {
+    fn push(&mut self, buf: &[u8]) {
+        self.len.push(buf.len() as u64);
+        self.data.extend_from_slice(buf);
+    }
+}
+
+impl ParamBlockBuilder {
+    pub fn new(schema: &ParamBlockSchema, enc: IntEncoding) -> Result<Self, ParamError> {
+        schema.validate().map_err(ParamError::Schema)?;
+        Ok(Self {
+            schema: *schema,
+            enc,
+            binary: Vec::with_capacity(schema.binary),
+            integer: Vec::with_capacity(schema.integer),
+            varlen: Lengths::default(),
+            stored: Lengths::default(),
+            temp: Lengths::default(),
+        })
+    }
+
+    // more of the binary part, which has exactly schema.binary bytes
+    pub fn binary(&mut self, buf: &[u8]) -> Result<&mut Self, ParamError> {
+        let got = self.binary.len() + buf.len();
+        check(Section::Binary, self.schema.binary, got)?;
+        self.binary.extend_from_slice(buf);
+        Ok(self)
+    }
+
+    pub fn u64(&mut self, v: u64) -> Result<&mut Self, ParamError> {
+        check(
+            Section::Integer,
+            self.schema.integer,
+            self.integer.len() + 1,
+        )?;
+        self.integer.push(v);
+        Ok(self)
+    }
+    pub fn i64(&mut self, v: i64) -> Result<&mut Self, ParamError> {
+        self.u64(v as u64)
+    }
+    pub fn f64(&mut self, v: f64) -> Result<&mut Self, ParamError> {
+        self.u64(v.to_bits())
+    }
+    pub fn bool(&mut self, v: bool) -> Result<&mut Self, ParamError> {
+        self.u64(v as u64)
+    }
+
+    pub fn varlen(&mut self, buf: &[u8]) -> Result<&mut Self, ParamError> {
+        check(
+            Section::Varlen,
+            self.schema.varlen,
+            self.varlen.len.len() + 1,
+        )?;
+        self.varlen.push(buf);
+        Ok(self)
+    }
+    pub fn str(&mut self, v: &str) -> Result<&mut Self, ParamError> {
+        self.varlen(v.as_bytes())
+    }
+
+    pub fn stored(&mut self, buf: &[u8]) -> Result<&mut Self, ParamError> {
+        check(
+            Section::Stored,
+            self.schema.stored,
+            self.stored.len.len() + 1,
+        )?;
+        self.stored.push(buf);
+        Ok(self)
+    }
+
+    pub fn temp(&mut self, buf: &[u8]) -> Result<&mut Self, ParamError> {
+        check(Section::Temp, self.schema.temp, self.temp.len.len() + 1)?;
+        self.temp.push(buf);
+        Ok(self)
+    }
+
+    // the size of the block build writes
+    pub fn len(&self) -> usize {
+        self.inline_len() + self.stored.data.len() + self.temp.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn build(&self) -> Result<Vec<u8>, ParamError> {
+        let mut out = Vec::with_capacity(self.len());
+        self.write(&mut out)?;
+        Ok(out)
+    }
+
+    // appended to out, e.g. after a stream header
+    pub fn write(&self, out: &mut Vec<u8>) -> Result<(), ParamError> {
+        let s = &self.schema;
+        expect(Section::Binary, s.binary, self.binary.len())?;
+        expect(Section::Temp, s.temp, self.temp.len.len())?;
+        expect(Section::Stored, s.stored, self.stored.len.len())?;
+        expect(Section::Varlen, s.varlen, self.varlen.len.len())?;
+        expect(Section::Integer, s.integer, self.integer.len())?;
+        let lengths = self.lengths();
+        let inline_len = self.inline_len_of(&lengths);
+        if inline_len > MAX_INLINE {
+            return Err(ParamError::TooLarge(inline_len));
+        }
+
+        out.reserve(inline_len + self.stored.data.len() + self.temp.data.len());
+        out.extend_from_slice(&self.binary);
+        int::encode(self.enc, &lengths, out);
+        int::encode(self.enc, &self.integer, out);
+        out.extend_from_slice(&self.varlen.data);
+        out.extend_from_slice(&self.stored.data);
+        out.extend_from_slice(&self.temp.data);
+        Ok(())
+    }
+
+    fn inline_len(&self) -> usize {
+        self.inline_len_of(&self.lengths())
+    }
+
+    fn inline_len_of(&self, lengths: &[u64]) -> usize {
+        let ints = int::encoded_len(self.enc, lengths) + int::encoded_len(self.enc, &self.integer);
+        self.binary.len() + ints + self.varlen.data.len()
+    }
+
+    // the lengths run
+    fn lengths(&self) -> Vec<u64> {
+        let all = [&self.temp.len, &self.stored.len, &self.varlen.len];
+        all.into_iter().flatten().copied().collect()
+    }
+}
+
+// no more than the schema has
+fn check(section: Section, expected: usize, got: usize) -> Result<(), ParamError> {
+    match got > expected {
+        true => Err(ParamError::Fields {
+            section,
+            expected,
+            got,
+        }),
+        false => Ok(()),
+    }
+}
+
+// exactly what the schema has
+fn expect(section: Section, expected: usize, got: usize) -> Result<(), ParamError> {
+    match got != expected {
+        true => Err(ParamError::Fields {
+            section,
+            expected,
+            got,
+        }),
+        false => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENCODINGS: [IntEncoding; 3] =
+        [IntEncoding::Fixed, IntEncoding::Varint, IntEncoding::Group];
+
+    const SCHEMA: ParamBlockSchema = ParamBlockSchema {
+        binary: 3,
+        varlen: 2,
+        stored: 2,
+        temp: 1,
+        integer: 5,
+    };
+
+    fn block(enc: IntEncoding) -> Vec<u8> {
+        let mut b = ParamBlockBuilder::new(&SCHEMA, enc).unwrap();
+        b.binary(&[1, 2]).unwrap().binary(&[3]).unwrap();
+        b.u64(u64::MAX).unwrap().i64(-5).unwrap().f64(1.5).unwrap();
+        b.bool(true).unwrap().u64(300).unwrap();
+        b.str("héllo").unwrap().varlen(b"").unwrap();
+        b.stored(&[7; 1000]).unwrap().stored(b"s").unwrap();
+        b.temp(b"temp").unwrap();
+        b.build().unwrap()
+    }
+
+    fn check_block(p: &ParamBlock) {
+        assert_eq!(p.binary, [1, 2, 3]);
+        assert_eq!(p.u64(0), Some(u64::MAX));
+        assert_eq!(p.i64(1), Some(-5));
+        assert_eq!(p.f64(2), Some(1.5));
+        assert_eq!(p.bool(3), Some(true));
+        assert_eq!(p.u64(4), Some(300));
+        assert_eq!(p.u64(5), None);
+        assert_eq!(p.str(0), Some("héllo"));
+        assert_eq!(p.varlen.get(1), Some(&b""[..]));
+        assert_eq!(p.varlen.get(2), None);
+        assert_eq!(p.stored.field_len(0), Some(1000));
+        assert_eq!(p.stored.field_len(1), Some(1));
+        assert_eq!(p.temp.field_len(0), Some(4));
+    }
+
+    #[derive(Default)]
+    struct Collect {
+        blobs: Vec<(Section, usize, u64, Vec<u8>, bool)>,
+    }
+
+    impl BlobSink for Collect {
+        fn start(&mut self, section: Section, index: usize, len: u64) {
+            self.blobs.push((section, index, len, Vec::new(), false));
+        }
+        fn middle(&mut self, section: Section, index: usize, buf: &[u8]) {
+            let b = self.blobs.last_mut().unwrap();
+            assert_eq!((b.0, b.1), (section, index));
+            b.3.extend_from_slice(buf);
+        }
+        fn finish(&mut self, section: Section, index: usize) {
+            let b = self.blobs.last_mut().unwrap();
+            assert_eq!((b.0, b.1), (section, index));
+            assert_eq!(b.2, b.3.len() as u64);
+            b.4 = true;
+        }
+    }
+
+    fn check_blobs(sink: &Collect) {
+        let blobs = sink
+            .blobs
+            .iter()
+            .map(|b| (b.0, b.1, &b.3[..], b.4))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            blobs,
+            [
+                (Section::Stored, 0, &[7; 1000][..], true),
+                (Section::Stored, 1, b"s", true),
+                (Section::Temp, 0, b"temp", true),
+            ]
+        );
+    }
+
+    // the whole block in the given pieces
+    fn push_all(enc: IntEncoding, pieces: &[&[u8]]) -> Result<(Parser, Collect), ParamError> {
+        let mut parser = Parser::new(&SCHEMA, enc)?;
+        let mut sink = Collect::default();
+        for (i, piece) in pieces.iter().enumerate() {
+            parser.push(piece, i + 1 == pieces.len(), &mut sink)?;
+        }
+        Ok((parser, sink))
+    }
+
+    #[test]
+    fn round_trip() {
+        for enc in ENCODINGS {
+            let buf = block(enc);
+            let p = parse_complete(&buf, &SCHEMA, enc).unwrap();
+            check_block(&p);
+            assert_eq!(p.stored.get(0), Some(&[7; 1000][..]));
+            assert_eq!(p.stored.get(1), Some(&b"s"[..]));
+            assert_eq!(p.temp.get(0), Some(&b"temp"[..]));
+            assert_eq!(p.inline_len() + 1005, buf.len());
+
+            let inline = parse_inline(&buf[..p.inline_len()], &SCHEMA, enc).unwrap();
+            check_block(&inline);
+            assert_eq!(inline.stored.get(0), None);
+        }
+    }
+
+    #[test]
+    fn split_everywhere() {
+        for enc in ENCODINGS {
+            let buf = block(enc);
+            for at in 0..=buf.len() {
+                let (parser, sink) = push_all(enc, &[&buf[..at], &buf[at..]]).unwrap();
+                assert!(parser.is_done());
+                check_block(&parser.block().unwrap());
+                check_blobs(&sink);
+            }
+            for size in 1..8 {
+                let pieces = buf.chunks(size).collect::<Vec<_>>();
+                let (parser, sink) = push_all(enc, &pieces).unwrap();
+                check_block(&parser.block().unwrap());
+                check_blobs(&sink);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        for enc in ENCODINGS {
+            let buf = block(enc);
+            for at in 0..buf.len() {
+                let short = &buf[..at];
+                assert!(matches!(
+                    parse_complete(short, &SCHEMA, enc),
+                    Err(ParamError::Truncated { .. })
+                ));
+                assert!(matches!(
+                    push_all(enc, &[short]),
+                    Err(ParamError::Truncated { .. })
+                ));
+            }
+            let mut long = buf.clone();
+            long.push(0);
+            assert_eq!(
+                parse_complete(&long, &SCHEMA, enc).unwrap_err(),
+                ParamError::Trailing(1)
+            );
+            assert_eq!(push_all(enc, &[&long]).err(), Some(ParamError::Trailing(1)));
+        }
+    }
+
+    // the block is complete before fin, which comes on its own
+    #[test]
+    fn done_before_fin() {
+        for enc in ENCODINGS {
+            let buf = block(enc);
+            let mut parser = Parser::new(&SCHEMA, enc).unwrap();
+            let mut sink = Collect::default();
+            assert_eq!(parser.push(&buf, false, &mut sink), Ok(true));
+            assert_eq!(parser.push(&[], false, &mut sink), Ok(true));
+            assert_eq!(parser.push(&[], true, &mut sink), Ok(true));
+            assert_eq!(
+                parser.push(b"x", true, &mut sink),
+                Err(ParamError::Trailing(1))
+            );
+            check_blobs(&sink);
+        }
+    }
+
+    // lengths as the peer pleases, in the Fixed encoding
+    fn with_lengths(schema: &ParamBlockSchema, lengths: &[u64], rest: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0; schema.binary];
+        int::encode(IntEncoding::Fixed, lengths, &mut buf);
+        int::encode(IntEncoding::Fixed, &vec![0; schema.integer], &mut buf);
+        buf.extend_from_slice(rest);
+        buf
+    }
+
+    #[test]
+    fn oversize_lengths() {
+        let enc = IntEncoding::Fixed;
+        let varlen = ParamBlockSchema {
+            varlen: 2,
+            ..Default::default()
+        };
+        // the sum overflows
+        let buf = with_lengths(&varlen, &[u64::MAX, 2], b"");
+        assert!(matches!(
+            parse_complete(&buf, &varlen, enc),
+            Err(ParamError::Overflow { .. })
+        ));
+        assert!(matches!(
+            Parser::new(&varlen, enc)
+                .unwrap()
+                .push(&buf, true, &mut Collect::default()),
+            Err(ParamError::Overflow { .. })
+        ));
+        // more than the inline limit, refused before it is buffered
+        let buf = with_lengths(&varlen, &[MAX_INLINE as u64, 0], b"abc");
+        assert!(matches!(
+            parse_complete(&buf, &varlen, enc),
+            Err(ParamError::Truncated { .. })
+        ));
+        assert!(matches!(
+            Parser::new(&varlen, enc)
+                .unwrap()
+                .push(&buf, false, &mut Collect::default()),
+            Err(ParamError::TooLarge(_))
+        ));
+        // a blob says it's huge and then isn't
+        let blobs = ParamBlockSchema {
+            stored: 1,
+            temp: 1,
+            ..Default::default()
+        };
+        let buf = with_lengths(&blobs, &[1, u64::MAX], b"abc");
+        assert!(matches!(
+            parse_complete(&buf, &blobs, enc),
+            Err(ParamError::Truncated { .. })
+        ));
+        let mut sink = Collect::default();
+        assert!(matches!(
+            Parser::new(&blobs, enc)
+                .unwrap()
+                .push(&buf, true, &mut sink),
+            Err(ParamError::Truncated {
+                section: Section::Stored,
+                ..
+            })
+        ));
+        // a varint over 64 bits
+        let mut buf = vec![0xff; 10];
+        buf.push(0);
+        assert_eq!(
+            parse_complete(&buf, &varlen, IntEncoding::Varint).unwrap_err(),
+            ParamError::Integer(Section::Lengths)
+        );
+    }
+
+    // whatever a peer sends, it's an error and not a panic
+    #[test]
+    fn garbage() {
+        let mut x = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        };
+        for enc in ENCODINGS {
+            let mut buf = block(enc);
+            for _ in 0..2000 {
+                let i = next() as usize % buf.len();
+                buf[i] = next() as u8;
+                let n = next() as usize % buf.len();
+                _ = parse_complete(&buf[..n], &SCHEMA, enc);
+                _ = parse_inline(&buf[..n], &SCHEMA, enc);
+                let size = next() as usize % 64 + 1;
+                let pieces = buf[..n].chunks(size).collect::<Vec<_>>();
+                _ = push_all(enc, &pieces);
+            }
+        }
+    }
+
+    #[test]
+    fn schema_limits() {
+        let too_many = ParamBlockSchema {
+            varlen: MAX_FIELDS + 1,
+            ..Default::default()
+        };
+        assert!(too_many.validate().is_err());
+        assert!(Parser::new(&too_many, IntEncoding::Fixed).is_err());
+        let too_big = ParamBlockSchema {
+            binary: MAX_INLINE + 1,
+            ..Default::default()
+        };
+        assert!(too_big.validate().is_err());
+        let together = ParamBlockSchema {
+            binary: MAX_INLINE - 10,
+            integer: 11,
+            ..Default::default()
+        };
+        assert!(together.validate().is_err());
+        assert!(SCHEMA.validate().is_ok());
+    }
+
+    #[test]
+    fn builder_counts() {
+        let enc = IntEncoding::Varint;
+        let mut b = ParamBlockBuilder::new(&SCHEMA, enc).unwrap();
+        assert!(b.binary(&[0; 4]).is_err());
+        assert!(matches!(
+            b.build(),
+            Err(ParamError::Fields {
+                section: Section::Binary,
+                ..
+            })
+        ));
+        let schema = ParamBlockSchema {
+            varlen: 1,
+            ..Default::default()
+        };
+        let mut b = ParamBlockBuilder::new(&schema, enc).unwrap();
+        b.varlen(b"a").unwrap();
+        assert!(b.varlen(b"b").is_err());
+        assert!(b.u64(1).is_err());
+        assert_eq!(b.len(), b.build().unwrap().len());
+        let mut b = ParamBlockBuilder::new(&schema, enc).unwrap();
+        b.varlen(&vec![0; MAX_INLINE]).unwrap();
+        assert!(matches!(b.build(), Err(ParamError::TooLarge(_))));
+    }
+}
@@ -0,0 +1,177 @@
+// telling tls clients that quic is there (RFC 7838).
+//
+// a browser only tries http/3 after an http/1.1 or http/2 response advertised it, with an
+// `Alt-Svc: h3=":port"` header or an ALTSVC frame, and then remembers it for max-age seconds.
+// we only speak http/1.1 over tls, so only the header is sent. the advertisement follows the
+// quic listeners: the port is the one they are bound to, and while none is up (quic is off,
+// or every worker's listener died) or an admin withdrew it, responses say `clear` instead so
+// clients forget what they cached. nothing probes whether quic actually works; withdrawing
+// while it is up but broken is up to the admin, see admin.
+//
+// shared by all workers like the diagnostics; reading it is a few atomic loads.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use log::*;
+
+// what browsers assume when ma is left out
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub struct AltSvc {
+    // seconds clients may remember it; 0 sends no header at all
+    max_age: AtomicU64,
+    // quic listeners up, and the port they're on
+    up: AtomicUsize,
+    port: AtomicU16,
+    withdrawn: AtomicBool,
+    // once clients were told, withdrawing has to tell them too
+    advertised: AtomicBool,
+}
+
+impl Default for AltSvc {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_AGE)
+    }
+}
+
+impl AltSvc {
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            max_age: AtomicU64::new(max_age.as_secs()),
+            up: AtomicUsize::new(0),
+            port: AtomicU16::new(0),
+            withdrawn: AtomicBool::new(false),
+            advertised: AtomicBool::new(false),
+        }
+    }
+
+    pub fn set_max_age(&self, max_age: Duration) {
+        self.max_age.store(max_age.as_secs(), Ordering::Relaxed);
+    }
+
+    // stop advertising quic, e.g. before taking it down for maintenance
+    pub fn withdraw(&self) {
+        info!("alt-svc withdrawn");
+        self.withdrawn.store(true, Ordering::Relaxed);
+    }
+
+    pub fn restore(&self) {
+        info!("alt-svc restored");
+        self.withdrawn.store(false, Ordering::Relaxed);
+    }
+
+    // a quic listener bound to `port` is up until the guard is dropped
+    pub fn listening(self: &Arc<Self>, port: u16) -> Listening {
+        self.port.store(port, Ordering::Relaxed);
+        self.up.fetch_add(1, Ordering::AcqRel);
+        Listening(self.clone())
+    }
+
+    // the field value to send now, if any
+    pub fn value(&self) -> Option<String> {
+        if self.max_age.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        if let Some(v) = self.offer() {
+            self.advertised.store(true, Ordering::Relaxed);
+            return Some(v);
+        }
+        match self.advertised.load(Ordering::Relaxed) {
+            true => Some("clear".to_string()),
+            false => None,
+        }
+    }
+
+    // what clients are being told, for the admin
+    pub fn state(&self) -> String {
+        if let Some(v) = self.offer() {
+            return v;
+        }
+        let why = if self.max_age.load(Ordering::Relaxed) == 0 {
+            "off"
+        } else if self.withdrawn.load(Ordering::Relaxed) {
+            "withdrawn"
+        } else {
+            "no quic listener"
+        };
+        match self.advertised.load(Ordering::Relaxed) {
+            true => format!("clear ({})", why),
+            false => format!("nothing ({})", why),
+        }
+    }
+
+    fn offer(&self) -> Option<String> {
+        let max_age = self.max_age.load(Ordering::Relaxed);
+        let up = self.up.load(Ordering::Acquire) > 0;
+        if max_age == 0 || !up || self.withdrawn.load(Ordering::Relaxed) {
+            return None;
+        }
+        let port = self.port.load(Ordering::Relaxed);
+        Some(format!("h3=\":{}\"; ma={}", port, max_age))
+    }
+
+    // the header line for an http/1.1 response, empty when there's nothing to say
+    pub fn header(&self) -> String {
+        match self.value() {
+            Some(v) => format!("Alt-Svc: {}\r\n", v),
+            None => String::new(),
+        }
+    }
+}
+
+// held by a quic listener; when the worker goes down with it, so does the advertisement.
+pub struct Listening(Arc<AltSvc>);
+
+impl Drop for Listening {
+    fn drop(&mut self) {
+        if self.0.up.fetch_sub(1, Ordering::AcqRel) == 1 {
+            warn!("no quic listener left, alt-svc withdrawn");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_listeners() {
+        let alt_svc = Arc::new(AltSvc::new(Duration::from_secs(60)));
+        assert_eq!(alt_svc.value(), None);
+        assert_eq!(alt_svc.header(), "");
+
+        let a = alt_svc.listening(4433);
+        let b = alt_svc.listening(4433);
+        assert_eq!(alt_svc.header(), "Alt-Svc: h3=\":4433\"; ma=60\r\n");
+        drop(a);
+        assert_eq!(alt_svc.value().as_deref(), Some("h3=\":4433\"; ma=60"));
+        drop(b);
+        // told before, so now told to forget
+        assert_eq!(alt_svc.value().as_deref(), Some("clear"));
+        assert_eq!(alt_svc.state(), "clear (no quic listener)");
+    }
+
+    #[test]
+    fn admin_switches() {
+        let alt_svc = Arc::new(AltSvc::default());
+        let _l = alt_svc.listening(443);
+        alt_svc.withdraw();
+        // never advertised, nothing to take back
+        assert_eq!(alt_svc.value(), None);
+        assert_eq!(alt_svc.state(), "nothing (withdrawn)");
+        alt_svc.restore();
+        assert_eq!(alt_svc.value().as_deref(), Some("h3=\":443\"; ma=86400"));
+        alt_svc.withdraw();
+        assert_eq!(alt_svc.value().as_deref(), Some("clear"));
+        alt_svc.restore();
+        alt_svc.set_max_age(Duration::ZERO);
+        assert_eq!(alt_svc.value(), None);
+        assert_eq!(alt_svc.state(), "clear (off)");
+    }
+}
@@ -0,0 +1,104 @@
+// connection ids that say which worker owns the connection.
+//
+// every worker binds the port with SO_REUSEPORT and the kernel picks a socket by hashing the
+// 4-tuple, so after a NAT rebinding (or a migration) a connection's packets show up on some
+// other worker. the ids we issue carry the owner, so whoever receives a packet can forward it
+// without any shared connection table.
+//
+// [worker ^ mac[0]:1][nonce:11][mac[1..9]:8]    mac = hmac(key, nonce)
+//
+// the mac tells our ids apart from the random ones clients pick for their first Initial, and
+// the mask keeps the worker index from being read off the wire.
+//
+// each id also has a stateless reset token, from a second key: anything mac'd with the id key
+// ends up on the wire, and a reset token must not.
+
+use quiche::ConnectionId;
+use ring::{
+    hkdf, hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+
+pub const CID_LEN: usize = quiche::MAX_CONN_ID_LEN;
+const NONCE: std::ops::Range<usize> = 1..12;
+const TAG: std::ops::Range<usize> = 12..20;
+
+// shared by all workers
+pub struct CidKeys {
+    key: hmac::Key,
+    reset: hmac::Key,
+}
+
+impl CidKeys {
+    pub fn generate() -> std::io::Result<Self> {
+        let rng = SystemRandom::new();
+        let generate = || {
+            hmac::Key::generate(hmac::HMAC_SHA256, &rng)
+                .map_err(|_| std::io::Error::other("cannot generate connection id key"))
+        };
+        Ok(Self {
+            key: generate()?,
+            reset: generate()?,
+        })
+    }
+
+    // several processes behind one address must share the secret, and a restarted process
+    // needs it to reset the connections it lost.
+    pub fn from_secret(secret: &[u8]) -> Self {
+        let reset = hkdf::Salt::new(hkdf::HKDF_SHA256, b"simpleweb reset")
+            .extract(secret)
+            .expand(&[], hmac::HMAC_SHA256)
+            .expect("hmac key length is valid for hkdf")
+            .into();
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+            reset,
+        }
+    }
+
+    fn seal(&self, worker: usize, nonce: &[u8]) -> ConnectionId<'static> {
+        debug_assert!(worker < 256);
+        let mac = hmac::sign(&self.key, nonce);
+        let mac = mac.as_ref();
+        let mut cid = [0u8; CID_LEN];
+        cid[0] = worker as u8 ^ mac[0];
+        cid[NONCE].copy_from_slice(nonce);
+        cid[TAG].copy_from_slice(&mac[1..9]);
+        cid.to_vec().into()
+    }
+
+    // the id for a connection, derived from the client's first dcid so retransmitted
+    // Initials map to the same connection.
+    pub fn issue(&self, worker: usize, client_dcid: &[u8]) -> ConnectionId<'static> {
+        let nonce = hmac::sign(&self.key, client_dcid);
+        self.seal(worker, &nonce.as_ref()[..NONCE.len()])
+    }
+
+    // a fresh id for an existing connection, e.g. for the peer to migrate to.
+    pub fn fresh(&self, worker: usize, rng: &SystemRandom) -> ConnectionId<'static> {
+        let mut nonce = [0u8; 11];
+        rng.fill(&mut nonce).expect("system rng failed");
+        self.seal(worker, &nonce)
+    }
+
+    // the stateless reset token that goes with one of our ids. derived rather than stored so
+    // any worker, or a restarted process with the same secret, can still reset it.
+    pub fn reset_token(&self, cid: &[u8]) -> u128 {
+        let mac = hmac::sign(&self.reset, cid);
+        u128::from_be_bytes(mac.as_ref()[..16].try_into().unwrap())
+    }
+
+    // the worker that issued `dcid`, if we issued it.
+    pub fn owner(&self, dcid: &[u8]) -> Option<usize> {
+        if dcid.len() != CID_LEN {
+            return None;
+        }
+        let mac = hmac::sign(&self.key, &dcid[NONCE]);
+        let mac = mac.as_ref();
+        // not constant time, but the tag only routes; it doesn't authenticate anything.
+        if dcid[TAG] != mac[1..9] {
+            return None;
+        }
+        Some((dcid[0] ^ mac[0]) as usize)
+    }
+}
@@ -0,0 +1,138 @@
+// 0-rtt: session tickets every worker can open, and holding back requests that could be
+// replays.
+//
+// a client resuming with a ticket may send requests before the handshake finishes, and
+// anyone who recorded those packets can send them again. the handshake itself can't be
+// replayed, so a request is only in doubt until it completes. procedures that are safe to run
+// twice (reads) run at once and get their results a round trip early; everything else is held
+// until the handshake is done, and rejected if too much piles up meanwhile.
+//
+// the ticket key follows the same scheme as the token keys: derived per epoch with hkdf from
+// one secret, so workers (and processes sharing the secret) agree without talking. the tls
+// library takes a single key, so a ticket older than the current epoch just means a full
+// handshake.
+
+use std::{
+    collections::HashSet,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ring::{
+    hkdf,
+    rand::{SecureRandom, SystemRandom},
+};
+
+// key name, hmac key and aes key, 16 bytes each
+pub const TICKET_KEY_LEN: usize = 48;
+// bytes of early requests a connection may have held at once
+pub const MAX_HELD: usize = 64 * 1024;
+// the application error a rejected early request is reset with, after http's 425 Too Early
+pub const TOO_EARLY: u32 = 425;
+
+struct KeyLen(usize);
+impl hkdf::KeyType for KeyLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+// shared by all workers
+pub struct TicketKeys {
+    prk: hkdf::Prk,
+    pub rotation: Duration,
+}
+
+impl TicketKeys {
+    pub fn generate() -> std::io::Result<Self> {
+        let mut secret = [0u8; 32];
+        SystemRandom::new()
+            .fill(&mut secret)
+            .map_err(|_| std::io::Error::other("cannot generate ticket secret"))?;
+        Ok(Self::from_secret(&secret))
+    }
+
+    // several processes behind one address must share the secret.
+    pub fn from_secret(secret: &[u8]) -> Self {
+        Self {
+            prk: hkdf::Salt::new(hkdf::HKDF_SHA256, b"simpleweb ticket").extract(secret),
+            rotation: Duration::from_secs(6 * 3600),
+        }
+    }
+
+    pub fn epoch(&self, now: SystemTime) -> u64 {
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        now / self.rotation.as_secs().max(1)
+    }
+
+    pub fn key(&self, epoch: u64) -> [u8; TICKET_KEY_LEN] {
+        let epoch = epoch.to_be_bytes();
+        let info = [&epoch[..]];
+        let mut key = [0u8; TICKET_KEY_LEN];
+        self.prk
+            .expand(&info, KeyLen(TICKET_KEY_LEN))
+            .and_then(|okm| okm.fill(&mut key))
+            .expect("ticket key length is valid for hkdf");
+        key
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Admit {
+    Run,
+    Hold,
+    Reject,
+}
+
+// per connection, for whoever dispatches its requests.
+#[derive(Default)]
+pub struct EarlyData {
+    held: Vec<(u64, Vec<u8>, bool)>,
+    held_streams: HashSet<u64>,
+    bytes: usize,
+    confirmed: bool,
+}
+
+impl EarlyData {
+    // what to do with the start of a request. `idempotent` is what the procedure declares.
+    pub fn admit(&mut self, replayable: bool, idempotent: bool, len: usize) -> Admit {
+        if !replayable || idempotent || self.confirmed {
+            Admit::Run
+        } else if self.bytes + len > MAX_HELD {
+            Admit::Reject
+        } else {
+            Admit::Hold
+        }
+    }
+
+    // once a stream is held, the rest of it waits behind it.
+    pub fn is_held(&self, stream_id: u64) -> bool {
+        self.held_streams.contains(&stream_id)
+    }
+
+    pub fn hold(&mut self, stream_id: u64, buf: &[u8], fin: bool) {
+        self.bytes += buf.len();
+        self.held_streams.insert(stream_id);
+        self.held.push((stream_id, buf.to_vec(), fin));
+    }
+
+    // the client reset a stream that was still waiting
+    pub fn drop_stream(&mut self, stream_id: u64) {
+        if self.held_streams.remove(&stream_id) {
+            self.held.retain(|(id, buf, _)| {
+                let keep = *id != stream_id;
+                if !keep {
+                    self.bytes -= buf.len();
+                }
+                keep
+            });
+        }
+    }
+
+    // the handshake is done: nothing is in doubt any more. returns what was held, in order.
+    pub fn confirm(&mut self) -> Vec<(u64, Vec<u8>, bool)> {
+        self.confirmed = true;
+        self.bytes = 0;
+        self.held_streams.clear();
+        std::mem::take(&mut self.held)
+    }
+}
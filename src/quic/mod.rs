@@ -0,0 +1,752 @@
+// the quic side of a worker. each worker binds its own udp socket on the shared port
+// (SO_REUSEPORT) and owns the connections that land on it; nothing here is shared between threads.
+//
+// this is the accept/recv/send loop from bin/quic.rs, driven by the worker's poll instead of
+// its own.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    os::fd::AsRawFd,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use log::*;
+use mio::net::UdpSocket;
+use quiche::ConnectionId;
+use ring::rand::SystemRandom;
+
+use crate::diag::Diagnostics;
+use crate::error::Result;
+use crate::quiche::{handle_path_events, Client, ClientId, ClientIdMap, ClientMap};
+use crate::server::MyConfig;
+use altsvc::Listening;
+use cid::CidKeys;
+use early::TicketKeys;
+use path::PathBudget;
+use reset::ResetGate;
+use retry::RetryGate;
+use token::{TokenKeys, Tokens};
+use udp::{Pacing, RecvBatch, SendBatch, UdpIo, UdpStats};
+use uring::UdpRing;
+use webtransport::{StreamHandlers, WebTransportConn};
+
+pub mod altsvc;
+pub mod cid;
+pub mod early;
+pub mod path;
+pub mod priority;
+pub mod reset;
+pub mod retry;
+pub mod token;
+pub mod udp;
+pub mod uring;
+pub mod webtransport;
+
+pub const MAX_DATAGRAM_SIZE: usize = 1350;
+const MAX_BUF_SIZE: usize = 65535;
+// user pacing sends anything due this soon rather than waking up again for it
+const PACING_GRANULARITY: Duration = Duration::from_millis(1);
+
+pub fn quic_config(config: &MyConfig) -> Result<quiche::Config> {
+    let mut qc = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    qc.load_cert_chain_from_pem_file(&config.cert)?;
+    qc.load_priv_key_from_pem_file(&config.key)?;
+    qc.set_application_protos(quiche::h3::APPLICATION_PROTOCOL)?;
+
+    qc.set_max_idle_timeout(5000);
+    qc.set_max_recv_udp_payload_size(MAX_DATAGRAM_SIZE);
+    qc.set_max_send_udp_payload_size(MAX_DATAGRAM_SIZE);
+    qc.set_initial_max_data(10_000_000);
+    qc.set_initial_max_stream_data_bidi_local(1_000_000);
+    qc.set_initial_max_stream_data_bidi_remote(1_000_000);
+    qc.set_initial_max_stream_data_uni(1_000_000);
+    qc.set_initial_max_streams_bidi(100);
+    qc.set_initial_max_streams_uni(100);
+    qc.set_disable_active_migration(!config.migration);
+    qc.set_active_connection_id_limit(path::ACTIVE_CID_LIMIT);
+    qc.enable_early_data();
+    qc.set_cc_algorithm(config.congestion);
+    // quiche stamps each packet with when it should leave; udp.rs sees that it does.
+    qc.enable_pacing(config.pacing != Pacing::Off);
+    // keys are only written for connections given a keylog, see diag.
+    qc.log_keys();
+    // for webtransport
+    qc.enable_dgram(true, 1000, 1000);
+    Ok(qc)
+}
+
+// secrets every worker must agree on
+#[derive(Clone)]
+pub struct QuicKeys {
+    pub token: Arc<TokenKeys>,
+    pub cid: Arc<CidKeys>,
+    pub ticket: Arc<TicketKeys>,
+}
+impl QuicKeys {
+    pub fn generate() -> std::io::Result<Self> {
+        Ok(Self {
+            token: Arc::new(TokenKeys::generate()?),
+            cid: Arc::new(CidKeys::generate()?),
+            ticket: Arc::new(TicketKeys::generate()?),
+        })
+    }
+
+    // keys that outlive the process, so a restarted server can still reset the connections
+    // it lost and accept the tokens and tickets it handed out.
+    pub fn from_secret(secret: &[u8]) -> Self {
+        Self {
+            token: Arc::new(TokenKeys::from_secret(secret)),
+            cid: Arc::new(CidKeys::from_secret(secret)),
+            ticket: Arc::new(TicketKeys::from_secret(secret)),
+        }
+    }
+}
+
+struct Paced {
+    at: Instant,
+    to: SocketAddr,
+    pkt: Vec<u8>,
+}
+
+// a datagram that arrived on the wrong worker
+pub struct Datagram {
+    pub data: Vec<u8>,
+    pub from: SocketAddr,
+}
+
+pub struct QuicListener {
+    pub socket: UdpSocket,
+    local_addr: SocketAddr,
+    config: quiche::Config,
+    worker: usize,
+    workers: usize,
+    keys: QuicKeys,
+    pub tokens: Tokens,
+    pub retry: RetryGate,
+    pub resets: ResetGate,
+    // handshakes still in progress, so the retry gate can count them.
+    half_open: HashMap<ClientId, SocketAddr>,
+    // how many more paths each connection may open
+    paths: HashMap<ClientId, PathBudget>,
+    rng: SystemRandom,
+    // static files served to plain HTTP/3 requests
+    root: String,
+    stream_handler: StreamHandlers,
+    diag: Arc<Diagnostics>,
+    // the diagnostics generation the open connections were last checked against
+    diag_generation: u64,
+    // connections that already have a qlog
+    watched: HashSet<ClientId>,
+
+    next_client_id: ClientId,
+    clients_ids: ClientIdMap,
+    clients: ClientMap,
+
+    // datagrams for connections owned by other workers, drained by the worker loop.
+    forward: Vec<(usize, Datagram)>,
+
+    rx: RecvBatch,
+    tx: SendBatch,
+    // with UdpIo::Uring, where the kernel can
+    ring: Option<UdpRing>,
+    // with user pacing, the packet each connection has to wait to send
+    paced: HashMap<ClientId, Paced>,
+    pub udp: UdpStats,
+    buf: Box<[u8]>,
+    out: Box<[u8]>,
+    ticket_epoch: Option<u64>,
+    // advertised to tls clients while we're up
+    _alt_svc: Listening,
+}
+
+impl QuicListener {
+    // the socket is created by the worker so that it can share the port with the tcp listener.
+    pub fn new(
+        socket: UdpSocket,
+        config: &MyConfig,
+        worker: usize,
+        keys: QuicKeys,
+    ) -> Result<Self> {
+        let ring = match config.udp_io {
+            UdpIo::Mio => None,
+            UdpIo::Uring => match UdpRing::new(&socket) {
+                Ok(ring) => Some(ring),
+                Err(e) => {
+                    warn!("no io_uring for udp, using mio: {}", e);
+                    None
+                }
+            },
+        };
+        let local_addr = socket.local_addr()?;
+        let mut listener = Self {
+            local_addr,
+            rx: RecvBatch::new(&socket),
+            tx: SendBatch::new(&socket, config.pacing),
+            ring,
+            paced: HashMap::new(),
+            socket,
+            config: quic_config(config)?,
+            worker,
+            workers: config.threads,
+            tokens: Tokens::new(keys.token.clone()),
+            keys,
+            retry: RetryGate::new(config.retry),
+            resets: ResetGate::new(Instant::now()),
+            half_open: HashMap::new(),
+            paths: HashMap::new(),
+            rng: SystemRandom::new(),
+            root: config.root.clone(),
+            stream_handler: (config.stream_handler)(worker),
+            diag: config.diagnostics.clone(),
+            diag_generation: config.diagnostics.generation(),
+            watched: HashSet::new(),
+            next_client_id: 0,
+            clients_ids: ClientIdMap::new(),
+            clients: ClientMap::new(),
+            forward: Vec::new(),
+            udp: UdpStats::default(),
+            buf: vec![0; MAX_BUF_SIZE].into_boxed_slice(),
+            out: vec![0; MAX_BUF_SIZE].into_boxed_slice(),
+            ticket_epoch: None,
+            _alt_svc: config.alt_svc.listening(local_addr.port()),
+        };
+        listener.rotate_ticket_key()?;
+        Ok(listener)
+    }
+
+    // new connections get the current epoch's ticket key, the same one every worker uses.
+    fn rotate_ticket_key(&mut self) -> Result<()> {
+        let epoch = self.keys.ticket.epoch(SystemTime::now());
+        if self.ticket_epoch != Some(epoch) {
+            self.config.set_ticket_key(&self.keys.ticket.key(epoch))?;
+            self.ticket_epoch = Some(epoch);
+        }
+        Ok(())
+    }
+
+    // what the worker polls for quic: the socket, or the ring's completions.
+    pub fn register(&mut self, registry: &mio::Registry, token: mio::Token) -> Result<()> {
+        match &self.ring {
+            Some(ring) => registry.register(
+                &mut mio::unix::SourceFd(&ring.as_raw_fd()),
+                token,
+                mio::Interest::READABLE,
+            )?,
+            None => registry.register(&mut self.socket, token, mio::Interest::READABLE)?,
+        }
+        Ok(())
+    }
+
+    // a wakeup from the handlers means they have something to send, see HandlerFactory
+    pub fn set_waker(&mut self, waker: Arc<mio::Waker>) {
+        self.stream_handler.set_waker(waker);
+    }
+
+    // the timeout for the worker's poll. with a ring its timer wakes the poll instead.
+    pub fn poll_timeout(&mut self) -> Result<Option<Duration>> {
+        let timeout = self.timeout();
+        match self.ring.as_mut() {
+            Some(ring) => {
+                ring.set_timer(timeout, &mut self.udp)?;
+                Ok(None)
+            }
+            None => Ok(timeout),
+        }
+    }
+
+    // the shortest timer of all the connections, to bound the worker's poll.
+    pub fn timeout(&self) -> Option<Duration> {
+        let now = Instant::now();
+        let paced = self
+            .paced
+            .values()
+            .map(|p| p.at.saturating_duration_since(now));
+        self.clients
+            .values()
+            .filter_map(|c| c.conn.timeout())
+            .chain(paced)
+            .min()
+    }
+
+    // fire the timers that have expired
+    pub fn on_timeout(&mut self) {
+        for client in self.clients.values_mut() {
+            if client.conn.timeout() == Some(Duration::ZERO) {
+                client.conn.on_timeout();
+            }
+        }
+    }
+
+    // read until the socket would block; mio is edge triggered.
+    pub fn recv(&mut self) -> Result<()> {
+        self.rotate_ticket_key()?;
+        if let Some(mut ring) = self.ring.take() {
+            let mut udp = std::mem::take(&mut self.udp);
+            let r = ring.recv(&mut udp, |buf, segment, from| {
+                for pkt in buf.chunks_mut(segment) {
+                    self.recv_datagram(pkt, from, false)?;
+                }
+                Ok(())
+            });
+            self.udp = udp;
+            self.ring = Some(ring);
+            return r;
+        }
+        // the batch is borrowed out of self while its packets are processed.
+        let mut rx = std::mem::take(&mut self.rx);
+        let r = self.recv_batches(&mut rx);
+        self.rx = rx;
+        r
+    }
+
+    fn recv_batches(&mut self, rx: &mut RecvBatch) -> Result<()> {
+        loop {
+            let n = rx.recv(&self.socket, &mut self.udp)?;
+            if n == 0 {
+                return Ok(());
+            }
+            for i in 0..n {
+                let (buf, segment, from) = rx.get(i);
+                for pkt in buf.chunks_mut(segment) {
+                    self.recv_datagram(pkt, from, false)?;
+                }
+            }
+        }
+    }
+
+    // a datagram another worker received for one of our connections.
+    pub fn recv_forwarded(&mut self, mut dgram: Datagram) -> Result<()> {
+        self.recv_datagram(&mut dgram.data, dgram.from, true)
+    }
+
+    // (worker, datagram) to hand to the other workers.
+    pub fn take_forwarded(&mut self) -> Vec<(usize, Datagram)> {
+        std::mem::take(&mut self.forward)
+    }
+
+    fn recv_datagram(
+        &mut self,
+        pkt_buf: &mut [u8],
+        from: SocketAddr,
+        forwarded: bool,
+    ) -> Result<()> {
+        let Self {
+            socket,
+            local_addr,
+            config,
+            worker,
+            workers,
+            keys,
+            tokens,
+            retry,
+            resets,
+            half_open,
+            paths,
+            rng,
+            root,
+            stream_handler,
+            diag,
+            watched,
+            next_client_id,
+            clients_ids,
+            clients,
+            forward,
+            buf,
+            out,
+            ..
+        } = self;
+
+        let hdr = match quiche::Header::from_slice(pkt_buf, quiche::MAX_CONN_ID_LEN) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Parsing packet header failed: {:?}", e);
+                return Ok(());
+            }
+        };
+        trace!("got packet {:?}", hdr);
+
+        let conn_id: ConnectionId<'static> = keys.cid.issue(*worker, &hdr.dcid);
+
+        let client_id = match clients_ids
+            .get(&hdr.dcid)
+            .or_else(|| clients_ids.get(&conn_id))
+        {
+            Some(id) => *id,
+            None => {
+                // one of ours, but it belongs to another worker. a forwarded packet is never
+                // forwarded again, if the owner has lost it the owner resets it. so does
+                // anyone, for a worker that no longer exists.
+                if let Some(owner) = keys.cid.owner(&hdr.dcid) {
+                    if owner != *worker && owner < *workers && !forwarded {
+                        trace!("forwarding packet for {:?} to worker {}", hdr.dcid, owner);
+                        forward.push((
+                            owner,
+                            Datagram {
+                                data: pkt_buf.to_vec(),
+                                from,
+                            },
+                        ));
+                        return Ok(());
+                    }
+                }
+
+                // a connection we lost, maybe in a restart. tell the client instead of leaving
+                // it to time out.
+                if hdr.ty == quiche::Type::Short && keys.cid.owner(&hdr.dcid).is_some() {
+                    let len = pkt_buf.len();
+                    if let Some(n) =
+                        resets.reset(&keys.cid, &hdr.dcid, len, rng, out, Instant::now())
+                    {
+                        debug!("stateless reset for unknown cid {:?}", hdr.dcid);
+                        send_or_drop(socket, &out[..n], from)?;
+                    }
+                    return Ok(());
+                }
+
+                if hdr.ty != quiche::Type::Initial {
+                    error!("Packet is not Initial");
+                    return Ok(());
+                }
+
+                if !quiche::version_is_supported(hdr.version) {
+                    warn!("Doing version negotiation");
+                    let len = quiche::negotiate_version(&hdr.scid, &hdr.dcid, out)?;
+                    send_or_drop(socket, &out[..len], from)?;
+                    return Ok(());
+                }
+
+                let mut scid = [0; quiche::MAX_CONN_ID_LEN];
+                scid.copy_from_slice(&conn_id);
+                let scid = quiche::ConnectionId::from_ref(&scid);
+
+                // Token is always present in Initial packets.
+                let token = hdr.token.as_ref().unwrap();
+
+                let validated = match token.is_empty() {
+                    true => None,
+                    false => match tokens.validate(token, &from) {
+                        Ok(odcid) => Some(odcid),
+                        // The token was not valid, meaning the retry failed, so
+                        // drop the packet.
+                        Err(e) => {
+                            error!("Invalid address validation token: {}", e.as_str());
+                            return Ok(());
+                        }
+                    },
+                };
+
+                let now = Instant::now();
+                let (scid, odcid) = match validated {
+                    // without a token, only pay for the round trip if we look to be under attack.
+                    None if !retry.should_retry(&from, now) => (scid.into_owned(), None),
+                    // Do stateless retry if the client didn't send a usable token.
+                    None => {
+                        warn!("Doing stateless retry");
+                        let new_token = tokens.mint_retry(&hdr.dcid, &from);
+                        let len = quiche::retry(
+                            &hdr.scid,
+                            &hdr.dcid,
+                            &scid,
+                            &new_token,
+                            hdr.version,
+                            out,
+                        )?;
+                        send_or_drop(socket, &out[..len], from)?;
+                        return Ok(());
+                    }
+                    Some(odcid) => {
+                        if scid.len() != hdr.dcid.len() {
+                            error!("Invalid destination connection ID");
+                            return Ok(());
+                        }
+                        // Reuse the source connection ID we sent in the Retry packet,
+                        // instead of changing it again.
+                        (hdr.dcid.clone(), Some(odcid))
+                    }
+                };
+
+                debug!("New connection: dcid={:?} scid={:?}", hdr.dcid, scid);
+
+                // the token for the id the handshake uses; later ids carry theirs in
+                // NEW_CONNECTION_ID.
+                config.set_stateless_reset_token(Some(keys.cid.reset_token(&scid)));
+                let mut conn = quiche::accept(&scid, odcid.as_ref(), *local_addr, from, config)?;
+                let is_watched = diag.matches(&from, [&hdr.dcid[..], &scid[..]]);
+                if is_watched {
+                    watch(diag, &mut conn, true);
+                }
+
+                let client_id = *next_client_id;
+                *next_client_id += 1;
+                let client = Client {
+                    conn,
+                    http_conn: None,
+                    client_id,
+                    app_proto_selected: false,
+                    partial_requests: HashMap::new(),
+                    partial_responses: HashMap::new(),
+                    max_datagram_size: MAX_DATAGRAM_SIZE,
+                    loss_rate: 0.0,
+                    max_send_burst: MAX_BUF_SIZE,
+                };
+                clients_ids.insert(scid.into_owned(), client_id);
+                clients.insert(client_id, client);
+                if is_watched {
+                    watched.insert(client_id);
+                }
+                retry.on_accept(&from, now);
+                half_open.insert(client_id, from);
+                client_id
+            }
+        };
+        let client = clients.get_mut(&client_id).unwrap();
+
+        // a new address for an established connection costs it a path challenge; make sure
+        // it can't cost us one per packet.
+        let now = Instant::now();
+        if client.conn.is_established()
+            && !path::is_known_path(&client.conn, *local_addr, from)
+            && !paths
+                .entry(client_id)
+                .or_insert_with(|| PathBudget::new(now))
+                .allow(now)
+        {
+            debug!(
+                "{} too many new paths, dropping packet from {}",
+                client.conn.trace_id(),
+                from
+            );
+            return Ok(());
+        }
+
+        let recv_info = quiche::RecvInfo {
+            to: *local_addr,
+            from,
+        };
+
+        // Process potentially coalesced packets.
+        let read = match client.conn.recv(pkt_buf, recv_info) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("{} recv failed: {:?}", client.conn.trace_id(), e);
+                return Ok(());
+            }
+        };
+        debug!("{} processed {} bytes", client.conn.trace_id(), read);
+
+        if client.conn.is_established() {
+            if let Some(addr) = half_open.remove(&client_id) {
+                retry.on_handshake_done(&addr);
+            }
+        }
+
+        handle_path_events(client);
+        path::manage_cids(client, &keys.cid, *worker, rng, clients_ids);
+
+        // Create a new application protocol session as soon as the QUIC
+        // connection is established.
+        if !client.app_proto_selected
+            && (client.conn.is_in_early_data() || client.conn.is_established())
+        {
+            let app_proto = client.conn.application_proto();
+            if app_proto == b"h3" {
+                match WebTransportConn::with_conn(&mut client.conn, stream_handler.handler()) {
+                    Ok(v) => client.http_conn = Some(v),
+                    Err(e) => {
+                        error!("{} {}", client.conn.trace_id(), e);
+                        client.conn.close(false, 0x1, b"h3 setup failed").ok();
+                    }
+                }
+            }
+            client.app_proto_selected = true;
+        }
+
+        if let Some(http_conn) = client.http_conn.as_mut() {
+            let conn = &mut client.conn;
+            let partial_responses = &mut client.partial_responses;
+
+            http_conn.handle_writables(conn, partial_responses);
+
+            if let Err(e) = http_conn.handle_requests(
+                conn,
+                &mut client.partial_requests,
+                partial_responses,
+                root,
+                "index.html",
+                buf,
+            ) {
+                debug!("{} HTTP/3 error {:?}", conn.trace_id(), e);
+            }
+        }
+        Ok(())
+    }
+
+    // Generate outgoing QUIC packets for all active connections and send
+    // them on the UDP socket, until quiche reports that there are no more
+    // packets to be sent. a connection's packets go out as one GSO train where the kernel
+    // can, and all connections' trains in as few sendmmsg calls as fit.
+    pub fn send(&mut self) -> Result<()> {
+        self.watch_open_connections();
+        self.stream_handler.poll();
+        let Self {
+            socket,
+            clients,
+            tx,
+            ring,
+            paced,
+            udp,
+            ..
+        } = self;
+        let now = Instant::now();
+        for (client_id, client) in clients.iter_mut() {
+            // with user pacing a connection waits until its held packet is due.
+            if let Some(p) = paced.get(client_id) {
+                if p.at > now {
+                    continue;
+                }
+                let p = paced.remove(client_id).unwrap();
+                if !tx.has_room(p.pkt.len()) {
+                    flush(tx, socket, ring, udp)?;
+                }
+                tx.push(&p.pkt, p.to, p.at);
+            }
+            if let Some(http_conn) = client.http_conn.as_mut() {
+                http_conn.poll_pushes(&mut client.conn);
+            }
+            let max = client.conn.max_send_udp_payload_size();
+            loop {
+                if !tx.has_room(max) {
+                    flush(tx, socket, ring, udp)?;
+                }
+                let (write, send_info) = match client.conn.send(tx.space(max)) {
+                    Ok(v) => v,
+                    Err(quiche::Error::Done) => break,
+                    Err(e) => {
+                        error!("{} send failed: {:?}", client.conn.trace_id(), e);
+                        client.conn.close(false, 0x1, b"fail").ok();
+                        break;
+                    }
+                };
+                if tx.user_pacing() && send_info.at > now + PACING_GRANULARITY {
+                    paced.insert(
+                        *client_id,
+                        Paced {
+                            at: send_info.at,
+                            to: send_info.to,
+                            pkt: tx.space(max)[..write].to_vec(),
+                        },
+                    );
+                    break;
+                }
+                tx.commit(write, send_info.to, send_info.at);
+            }
+            tx.end_train();
+        }
+        flush(tx, socket, ring, udp)?;
+        Ok(())
+    }
+
+    // a filter was added: start a qlog for the open connections it now matches.
+    fn watch_open_connections(&mut self) {
+        let generation = self.diag.generation();
+        if generation == self.diag_generation {
+            return;
+        }
+        self.diag_generation = generation;
+        for (client_id, client) in self.clients.iter_mut() {
+            if self.watched.contains(client_id) {
+                continue;
+            }
+            let conn = &client.conn;
+            let cids = conn.source_ids().map(|c| c.as_ref()).collect::<Vec<_>>();
+            if conn
+                .paths_iter(self.local_addr)
+                .any(|peer| self.diag.matches(&peer, cids.iter().copied()))
+            {
+                watch(&self.diag, &mut client.conn, false);
+                self.watched.insert(*client_id);
+            }
+        }
+    }
+
+    pub fn collect_garbage(&mut self) {
+        let before = self.clients.len();
+        self.clients.retain(|_, c| {
+            if c.conn.is_closed() {
+                info!(
+                    "{} connection collected {:?}",
+                    c.conn.trace_id(),
+                    c.conn.stats()
+                );
+            }
+            !c.conn.is_closed()
+        });
+        if self.clients.len() < before {
+            debug!(
+                "udp {:?}, syscalls per packet recv {:.2} send {:.2}",
+                self.udp,
+                self.udp.recv_syscalls_per_packet(),
+                self.udp.send_syscalls_per_packet()
+            );
+        }
+        let clients = &self.clients;
+        self.clients_ids.retain(|_, id| clients.contains_key(id));
+        self.paths.retain(|id, _| clients.contains_key(id));
+        self.watched.retain(|id| clients.contains_key(id));
+        self.paced.retain(|id, _| clients.contains_key(id));
+        let retry = &mut self.retry;
+        self.half_open.retain(|id, addr| {
+            let keep = clients.contains_key(id);
+            if !keep {
+                retry.on_handshake_done(addr);
+            }
+            keep
+        });
+    }
+}
+
+// quiche can't stop a qlog, so a connection stays watched after its filter is removed.
+fn watch(diag: &Diagnostics, conn: &mut quiche::Connection, keylog: bool) {
+    let trace_id = conn.trace_id().to_string();
+    match diag.qlog(&trace_id) {
+        Ok(w) => conn.set_qlog(
+            Box::new(w),
+            "simpleweb".to_string(),
+            format!("server qlog id={trace_id}"),
+        ),
+        Err(e) => warn!("{} cannot create qlog: {}", trace_id, e),
+    }
+    if keylog {
+        match diag.keylog() {
+            Ok(w) => conn.set_keylog(Box::new(w)),
+            Err(e) => warn!("{} cannot open keylog: {}", trace_id, e),
+        }
+    }
+}
+
+fn flush(
+    tx: &mut SendBatch,
+    socket: &UdpSocket,
+    ring: &mut Option<UdpRing>,
+    udp: &mut UdpStats,
+) -> Result<()> {
+    match ring {
+        Some(ring) => tx.flush_ring(ring, udp)?,
+        None => tx.flush(socket, udp)?,
+    }
+    Ok(())
+}
+
+// version negotiation and retry are stateless, if the socket is full the client will try again.
+fn send_or_drop(socket: &UdpSocket, buf: &[u8], to: SocketAddr) -> Result<()> {
+    match socket.send_to(buf, to) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+            debug!("send() would block");
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,91 @@
+// connection migration, the server's half.
+//
+// quiche does the protocol: a packet from a new address opens a path and gets a
+// PATH_CHALLENGE, the path is held to 3x what it sent us until it answers, each path has its
+// own congestion controller, and a migration that fails validation falls back to the last
+// good path. streams don't know which path they are on, so in-flight rpcs just carry on.
+//
+// what's left for us is keeping the client stocked with connection ids, which it needs to
+// move without linking its old and new address, and not letting one connection be used to
+// make us probe an unbounded number of spoofed addresses.
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use log::*;
+use ring::rand::SystemRandom;
+
+use crate::quiche::{Client, ClientIdMap};
+
+use super::cid::CidKeys;
+
+// new paths a connection may open in a burst, and how fast the allowance comes back. a phone
+// moving between wifi and cellular needs a couple; a forwarder rewriting source addresses
+// needs many.
+const PATH_BURST: u32 = 4;
+const PATH_REFILL: Duration = Duration::from_secs(5);
+
+// how many of our ids the client may hold at once
+pub const ACTIVE_CID_LIMIT: u64 = 4;
+
+pub struct PathBudget {
+    tokens: u32,
+    last: Instant,
+}
+
+impl PathBudget {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            tokens: PATH_BURST,
+            last: now,
+        }
+    }
+
+    // may a packet from an unknown address open a path?
+    pub fn allow(&mut self, now: Instant) -> bool {
+        let refill = (now.saturating_duration_since(self.last).as_secs_f64()
+            / PATH_REFILL.as_secs_f64()) as u32;
+        if refill > 0 {
+            self.tokens = (self.tokens + refill).min(PATH_BURST);
+            self.last = now;
+        }
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
+
+// is this packet from an address the connection already has a path for?
+pub fn is_known_path(conn: &quiche::Connection, local: SocketAddr, from: SocketAddr) -> bool {
+    conn.paths_iter(local).any(|peer| peer == from)
+}
+
+// top up the ids the client holds, and forget the ones it retired.
+pub fn manage_cids(
+    client: &mut Client,
+    keys: &CidKeys,
+    worker: usize,
+    rng: &SystemRandom,
+    clients_ids: &mut ClientIdMap,
+) {
+    while let Some(cid) = client.conn.retired_scid_next() {
+        debug!("{} retired cid {:?}", client.conn.trace_id(), cid);
+        clients_ids.remove(&cid);
+    }
+    if !client.conn.is_established() {
+        return;
+    }
+    while client.conn.scids_left() > 0 {
+        let cid = keys.fresh(worker, rng);
+        let reset = keys.reset_token(&cid);
+        if let Err(e) = client.conn.new_scid(&cid, reset, false) {
+            debug!("{} cannot issue cid: {:?}", client.conn.trace_id(), e);
+            break;
+        }
+        clients_ids.insert(cid, client.client_id);
+    }
+}
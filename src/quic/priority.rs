@@ -0,0 +1,146 @@
+// RFC 9218 extensible priorities for the streams a connection writes.
+//
+// each response stream has an urgency, 0 (first) to 7 (last), and is either incremental or
+// not. lower urgency goes first; within an urgency the non-incremental streams go one at a
+// time, oldest first, and the incremental ones take turns.
+//
+// quiche already orders the packets it builds by the priority we give each stream, but that
+// only covers bytes it has been handed. a blob written all at once uses up the connection's
+// flow control window, and a small rpc result behind it can't even be buffered until the
+// client sends more credit. so we also decide the order streams are written in, and hand
+// quiche about as much as it can send right away; the rest waits in our own queue where an
+// urgent reply can still get ahead of it.
+//
+// plain HTTP/3 requests say what they want with a priority header or PRIORITY_UPDATE frames;
+// webtransport streams get theirs from the procedure that answers them.
+
+use std::collections::HashMap;
+
+use log::*;
+
+pub const DEFAULT_URGENCY: u8 = 3;
+const MAX_URGENCY: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Priority {
+    pub urgency: u8,
+    pub incremental: bool,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self {
+            urgency: DEFAULT_URGENCY,
+            incremental: false,
+        }
+    }
+}
+
+impl Priority {
+    pub fn new(urgency: u8, incremental: bool) -> Self {
+        Self {
+            urgency: urgency.min(MAX_URGENCY),
+            incremental,
+        }
+    }
+
+    // a priority field value, e.g. `u=5, i`. members we don't know and values we can't
+    // read are ignored, as the RFC asks.
+    pub fn parse(value: &[u8]) -> Self {
+        let mut p = Self::default();
+        let Ok(value) = std::str::from_utf8(value) else {
+            return p;
+        };
+        for member in value.split(',') {
+            // parameters after ';' don't mean anything for these keys
+            let member = member.split(';').next().unwrap_or("").trim();
+            let (key, val) = match member.split_once('=') {
+                Some((k, v)) => (k.trim(), Some(v.trim())),
+                None => (member, None),
+            };
+            match (key, val) {
+                ("u", Some(v)) => {
+                    if let Ok(u) = v.parse::<u8>() {
+                        if u <= MAX_URGENCY {
+                            p.urgency = u;
+                        }
+                    }
+                }
+                ("i", None) | ("i", Some("?1")) => p.incremental = true,
+                ("i", Some("?0")) => p.incremental = false,
+                _ => (),
+            }
+        }
+        p
+    }
+
+    pub fn h3(&self) -> quiche::h3::Priority {
+        quiche::h3::Priority::new(self.urgency, self.incremental)
+    }
+}
+
+// per connection
+#[derive(Default)]
+pub struct Scheduler {
+    streams: HashMap<u64, Priority>,
+    // the incremental stream that went last at each urgency, so the next one goes first
+    last: [Option<u64>; MAX_URGENCY as usize + 1],
+}
+
+impl Scheduler {
+    pub fn get(&self, stream_id: u64) -> Priority {
+        self.streams.get(&stream_id).copied().unwrap_or_default()
+    }
+
+    pub fn contains(&self, stream_id: u64) -> bool {
+        self.streams.contains_key(&stream_id)
+    }
+
+    // remembered for our writes and passed on to quiche for its packets
+    pub fn set(&mut self, conn: &mut quiche::Connection, stream_id: u64, p: Priority) {
+        if self.streams.insert(stream_id, p) == Some(p) {
+            return;
+        }
+        if let Err(e) = conn.stream_priority(stream_id, p.urgency, p.incremental) {
+            trace!(
+                "{} cannot prioritize stream {}: {:?}",
+                conn.trace_id(),
+                stream_id,
+                e
+            );
+        }
+    }
+
+    pub fn remove(&mut self, stream_id: u64) {
+        self.streams.remove(&stream_id);
+    }
+
+    // the order to write `ids` in
+    pub fn order(&self, ids: impl IntoIterator<Item = u64>) -> Vec<u64> {
+        let mut ids = ids.into_iter().collect::<Vec<_>>();
+        ids.sort_by_key(|&id| {
+            let p = self.get(id);
+            // incremental streams after the last one served come around first
+            let turn = match self.last[p.urgency as usize] {
+                Some(last) if p.incremental => id <= last,
+                _ => false,
+            };
+            (p.urgency, p.incremental, turn, id)
+        });
+        ids
+    }
+
+    // `stream_id` got to write; the next incremental stream at its urgency goes first next time
+    pub fn served(&mut self, stream_id: u64) {
+        let p = self.get(stream_id);
+        if p.incremental {
+            self.last[p.urgency as usize] = Some(stream_id);
+        }
+    }
+}
+
+// how much to hand quiche in one round: what it can send right away, and a bit more so the
+// packets that come back acked don't leave it idle until we write again.
+pub fn budget(conn: &quiche::Connection) -> usize {
+    (2 * conn.send_quantum()).max(super::MAX_DATAGRAM_SIZE)
+}
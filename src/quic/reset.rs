@@ -0,0 +1,84 @@
+// stateless reset: telling a client its connection is gone.
+//
+// after a restart (or anything else that loses connection state) clients keep sending
+// short-header packets to ids nobody knows, and without an answer they sit there until their
+// idle timeout. each id we issue comes with a reset token derived from the id and the shared
+// secret, so whoever gets such a packet can still work out the token and end it at once.
+//
+// a reset looks like any other short-header packet: random bytes ending in the token. it is
+// always shorter than the packet that caused it, so two endpoints that have both lost state
+// can't keep bouncing resets between them, and there is a budget so a flood of junk can't make
+// us send a flood back.
+
+use std::time::Instant;
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::cid::CidKeys;
+
+// the smallest a reset can be and still pass for a short-header packet (RFC 9000 10.3)
+const MIN_RESET_LEN: usize = 21;
+// long enough to look like a packet with one of our ids and a little payload
+const MAX_RESET_LEN: usize = 43;
+
+// resets a worker may send in a burst, and how many a second after that
+const RESET_BURST: u32 = 32;
+const RESET_RATE: u32 = 128;
+
+pub struct ResetGate {
+    tokens: u32,
+    last: Instant,
+    pub sent: u64,
+    pub limited: u64,
+}
+
+impl ResetGate {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            tokens: RESET_BURST,
+            last: now,
+            sent: 0,
+            limited: 0,
+        }
+    }
+
+    fn allow(&mut self, now: Instant) -> bool {
+        let refill =
+            (now.saturating_duration_since(self.last).as_secs_f64() * RESET_RATE as f64) as u32;
+        if refill > 0 {
+            self.tokens = (self.tokens + refill).min(RESET_BURST);
+            self.last = now;
+        }
+        if self.tokens == 0 {
+            self.limited += 1;
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+
+    // the reset for a packet of `pkt_len` bytes sent to `dcid`, written into `out`. None if
+    // the packet was too small to answer or we are over budget.
+    pub fn reset(
+        &mut self,
+        keys: &CidKeys,
+        dcid: &[u8],
+        pkt_len: usize,
+        rng: &SystemRandom,
+        out: &mut [u8],
+        now: Instant,
+    ) -> Option<usize> {
+        let len = pkt_len.saturating_sub(1).min(MAX_RESET_LEN);
+        if len < MIN_RESET_LEN || !self.allow(now) {
+            return None;
+        }
+        let token = keys.reset_token(dcid).to_be_bytes();
+        let (unpredictable, tail) = out[..len].split_at_mut(len - token.len());
+        rng.fill(unpredictable).expect("system rng failed");
+        // short header, fixed bit set
+        unpredictable[0] = 0b0100_0000 | (unpredictable[0] & 0b0011_1111);
+        tail.copy_from_slice(&token);
+        self.sent += 1;
+        Some(len)
+    }
+}
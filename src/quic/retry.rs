@@ -0,0 +1,201 @@
+// when to make a client prove its address with a retry.
+//
+// a retry costs every new connection a round trip, and quiche already holds unvalidated
+// addresses to the 3x anti-amplification limit, so we only retry when it looks like someone
+// is flooding us. load is tracked per source prefix (/24 for v4, /48 for v6) so one noisy
+// network doesn't make everyone pay, plus a global half-open count for spoofed floods that
+// come from everywhere at once.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+const WINDOW: Duration = Duration::from_secs(1);
+// forget prefixes that have been quiet this long
+const IDLE: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryPolicy {
+    Never,
+    Always,
+    UnderLoad {
+        // new handshakes per second from one prefix
+        prefix_rate: u32,
+        // handshakes started but not completed, from one prefix
+        prefix_half_open: u32,
+        // handshakes started but not completed, from anyone
+        half_open: u32,
+        // beyond this many tracked prefixes we assume we're being sprayed and retry everyone.
+        max_prefixes: usize,
+    },
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::UnderLoad {
+            prefix_rate: 50,
+            prefix_half_open: 100,
+            half_open: 10_000,
+            max_prefixes: 100_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Prefix {
+    V4([u8; 3]),
+    V6([u8; 6]),
+}
+impl Prefix {
+    pub fn of(addr: &SocketAddr) -> Self {
+        match addr.ip() {
+            IpAddr::V4(a) => {
+                let o = a.octets();
+                Prefix::V4([o[0], o[1], o[2]])
+            }
+            IpAddr::V6(a) => match a.to_ipv4_mapped() {
+                Some(a) => {
+                    let o = a.octets();
+                    Prefix::V4([o[0], o[1], o[2]])
+                }
+                None => {
+                    let o = a.octets();
+                    Prefix::V6([o[0], o[1], o[2], o[3], o[4], o[5]])
+                }
+            },
+        }
+    }
+}
+
+struct PrefixState {
+    window_start: Instant,
+    // handshakes in the current and the previous window, for a sliding estimate
+    current: u32,
+    previous: u32,
+    half_open: u32,
+    last_seen: Instant,
+}
+impl PrefixState {
+    fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            current: 0,
+            previous: 0,
+            half_open: 0,
+            last_seen: now,
+        }
+    }
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= WINDOW * 2 {
+            self.previous = 0;
+            self.current = 0;
+            self.window_start = now;
+        } else if elapsed >= WINDOW {
+            self.previous = self.current;
+            self.current = 0;
+            self.window_start += WINDOW;
+        }
+    }
+    fn rate(&self, now: Instant) -> u32 {
+        let into = now
+            .saturating_duration_since(self.window_start)
+            .as_secs_f64()
+            / WINDOW.as_secs_f64();
+        let carried = self.previous as f64 * (1.0 - into.min(1.0));
+        self.current + carried as u32
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct RetryStats {
+    pub retried: u64,
+    pub accepted_unvalidated: u64,
+}
+
+// per worker
+pub struct RetryGate {
+    policy: RetryPolicy,
+    prefix: HashMap<Prefix, PrefixState>,
+    half_open: u32,
+    last_sweep: Instant,
+    pub stats: RetryStats,
+}
+
+impl RetryGate {
+    pub fn new(policy: RetryPolicy) -> Self {
+        Self {
+            policy,
+            prefix: HashMap::new(),
+            half_open: 0,
+            last_sweep: Instant::now(),
+            stats: RetryStats::default(),
+        }
+    }
+
+    // an Initial arrived without a usable token; should we send a retry?
+    pub fn should_retry(&mut self, from: &SocketAddr, now: Instant) -> bool {
+        let retry = match self.policy {
+            RetryPolicy::Never => false,
+            RetryPolicy::Always => true,
+            RetryPolicy::UnderLoad {
+                prefix_rate,
+                prefix_half_open,
+                half_open,
+                max_prefixes,
+            } => {
+                self.sweep(now);
+                self.half_open >= half_open
+                    || self.prefix.len() >= max_prefixes
+                    || match self.prefix.get_mut(&Prefix::of(from)) {
+                        Some(p) => {
+                            p.roll(now);
+                            p.rate(now) >= prefix_rate || p.half_open >= prefix_half_open
+                        }
+                        None => false,
+                    }
+            }
+        };
+        match retry {
+            true => self.stats.retried += 1,
+            false => self.stats.accepted_unvalidated += 1,
+        }
+        retry
+    }
+
+    // a handshake started, validated or not.
+    pub fn on_accept(&mut self, from: &SocketAddr, now: Instant) {
+        if !matches!(self.policy, RetryPolicy::UnderLoad { .. }) {
+            return;
+        }
+        let p = self
+            .prefix
+            .entry(Prefix::of(from))
+            .or_insert_with(|| PrefixState::new(now));
+        p.roll(now);
+        p.current += 1;
+        p.half_open += 1;
+        p.last_seen = now;
+        self.half_open += 1;
+    }
+
+    // the handshake finished, or the connection went away before it could.
+    pub fn on_handshake_done(&mut self, from: &SocketAddr) {
+        if let Some(p) = self.prefix.get_mut(&Prefix::of(from)) {
+            if p.half_open > 0 {
+                p.half_open -= 1;
+                self.half_open = self.half_open.saturating_sub(1);
+            }
+        }
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.last_sweep) < IDLE {
+            return;
+        }
+        self.last_sweep = now;
+        self.prefix
+            .retain(|_, p| p.half_open > 0 || now.saturating_duration_since(p.last_seen) < IDLE);
+    }
+}
@@ -0,0 +1,229 @@
+// address validation tokens.
+//
+// a token is sealed with AES-256-GCM and bound to the client address through the AAD, so it
+// can't be forged, moved to another client, or edited. the key rotates every `rotation`; each
+// epoch's key is derived from one secret with hkdf, so every worker (or every process given
+// the same secret) agrees on the keys without talking to each other. tokens from the previous
+// epoch are still accepted.
+//
+// [kind:1][key id:1][nonce:12][seal(issued:8, odcid)][tag:16]
+//
+// retry tokens are bound to ip and port and live for seconds. there are no NEW_TOKEN tokens:
+// quiche can't send the frame, so a returning client is only spared the retry when the
+// policy doesn't ask for one. the kind byte leaves room for them.
+
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    hkdf,
+    rand::{SecureRandom, SystemRandom},
+};
+
+const HEADER_LEN: usize = 2 + NONCE_LEN;
+const TAG_LEN: usize = 16;
+const ISSUED_LEN: usize = 8;
+// how far in the future an issue time may be, for clock skew between processes.
+const MAX_SKEW: Duration = Duration::from_secs(5);
+
+const RETRY: u8 = 0;
+
+// why a token was rejected; also the index into TokenStats::failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenError {
+    Malformed = 0,
+    UnknownKey = 1,
+    // failed to open: tampered, or presented from a different address.
+    Forged = 2,
+    Expired = 3,
+}
+impl TokenError {
+    pub const COUNT: usize = 4;
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenError::Malformed => "malformed",
+            TokenError::UnknownKey => "unknown_key",
+            TokenError::Forged => "forged",
+            TokenError::Expired => "expired",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct TokenStats {
+    pub minted: u64,
+    pub validated: u64,
+    pub failed: [u64; TokenError::COUNT],
+}
+
+// shared by all workers
+pub struct TokenKeys {
+    prk: hkdf::Prk,
+    pub rotation: Duration,
+    pub retry_lifetime: Duration,
+}
+
+impl TokenKeys {
+    pub fn generate() -> std::io::Result<Self> {
+        let mut secret = [0u8; 32];
+        SystemRandom::new()
+            .fill(&mut secret)
+            .map_err(|_| std::io::Error::other("cannot generate token secret"))?;
+        Ok(Self::from_secret(&secret))
+    }
+
+    // several processes behind one address must share the secret.
+    pub fn from_secret(secret: &[u8]) -> Self {
+        Self {
+            prk: hkdf::Salt::new(hkdf::HKDF_SHA256, b"simpleweb token").extract(secret),
+            rotation: Duration::from_secs(3600),
+            retry_lifetime: Duration::from_secs(10),
+        }
+    }
+
+    fn epoch(&self, now: u64) -> u64 {
+        now / self.rotation.as_secs().max(1)
+    }
+
+    fn derive(&self, epoch: u64) -> LessSafeKey {
+        let epoch = epoch.to_be_bytes();
+        let info = [&epoch[..]];
+        let okm = self
+            .prk
+            .expand(&info, &AES_256_GCM)
+            .expect("aes key length is valid for hkdf");
+        LessSafeKey::new(UnboundKey::from(okm))
+    }
+}
+
+// per worker; caches the derived keys.
+pub struct Tokens {
+    keys: Arc<TokenKeys>,
+    rng: SystemRandom,
+    // (epoch, key), at most the current and previous epoch
+    cache: Vec<(u64, LessSafeKey)>,
+    pub stats: TokenStats,
+}
+
+impl Tokens {
+    pub fn new(keys: Arc<TokenKeys>) -> Self {
+        Self {
+            keys,
+            rng: SystemRandom::new(),
+            cache: Vec::with_capacity(2),
+            stats: TokenStats::default(),
+        }
+    }
+
+    fn key(&mut self, epoch: u64) -> &LessSafeKey {
+        let i = match self.cache.iter().position(|(e, _)| *e == epoch) {
+            Some(i) => i,
+            None => {
+                // keep only epochs that can still validate.
+                self.cache.retain(|(e, _)| *e + 1 >= epoch);
+                self.cache.push((epoch, self.keys.derive(epoch)));
+                self.cache.len() - 1
+            }
+        };
+        &self.cache[i].1
+    }
+
+    pub fn mint_retry(&mut self, odcid: &[u8], src: &SocketAddr) -> Vec<u8> {
+        self.mint(odcid, src, SystemTime::now())
+    }
+
+    fn mint(&mut self, odcid: &[u8], src: &SocketAddr, now: SystemTime) -> Vec<u8> {
+        let now = unix_secs(now);
+        let epoch = self.keys.epoch(now);
+
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng.fill(&mut nonce).expect("system rng failed");
+
+        let mut token = Vec::with_capacity(HEADER_LEN + ISSUED_LEN + odcid.len() + TAG_LEN);
+        token.push(RETRY);
+        token.push(epoch as u8);
+        token.extend_from_slice(&nonce);
+        token.extend_from_slice(&now.to_be_bytes());
+        token.extend_from_slice(odcid);
+
+        let aad = aad(epoch as u8, src);
+        let tag = self
+            .key(epoch)
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(&aad),
+                &mut token[HEADER_LEN..],
+            )
+            .expect("token is within aead limits");
+        token.extend_from_slice(tag.as_ref());
+        self.stats.minted += 1;
+        token
+    }
+
+    // the original destination connection id from before the retry
+    pub fn validate(
+        &mut self,
+        token: &[u8],
+        src: &SocketAddr,
+    ) -> Result<quiche::ConnectionId<'static>, TokenError> {
+        let r = self.open(token, src, SystemTime::now());
+        match r {
+            Ok(_) => self.stats.validated += 1,
+            Err(e) => self.stats.failed[e as usize] += 1,
+        }
+        r
+    }
+
+    fn open(
+        &mut self,
+        token: &[u8],
+        src: &SocketAddr,
+        now: SystemTime,
+    ) -> Result<quiche::ConnectionId<'static>, TokenError> {
+        if token.len() < HEADER_LEN + ISSUED_LEN + TAG_LEN || token[0] != RETRY {
+            return Err(TokenError::Malformed);
+        }
+        let key_id = token[1];
+        let now = unix_secs(now);
+        let current = self.keys.epoch(now);
+        let epoch = [current, current.wrapping_sub(1)]
+            .into_iter()
+            .find(|e| *e as u8 == key_id)
+            .ok_or(TokenError::UnknownKey)?;
+
+        let nonce = Nonce::try_assume_unique_for_key(&token[2..HEADER_LEN])
+            .map_err(|_| TokenError::Malformed)?;
+        let aad = aad(key_id, src);
+        let mut body = token[HEADER_LEN..].to_vec();
+        let plain = self
+            .key(epoch)
+            .open_in_place(nonce, Aad::from(&aad), &mut body)
+            .map_err(|_| TokenError::Forged)?;
+
+        let issued = u64::from_be_bytes(plain[..ISSUED_LEN].try_into().unwrap());
+        let lifetime = self.keys.retry_lifetime;
+        if issued > now + MAX_SKEW.as_secs() || now.saturating_sub(issued) > lifetime.as_secs() {
+            return Err(TokenError::Expired);
+        }
+
+        Ok(plain[ISSUED_LEN..].to_vec().into())
+    }
+}
+
+fn aad(key_id: u8, src: &SocketAddr) -> Vec<u8> {
+    let mut aad = vec![RETRY, key_id];
+    match src.ip() {
+        std::net::IpAddr::V4(a) => aad.extend_from_slice(&a.octets()),
+        std::net::IpAddr::V6(a) => aad.extend_from_slice(&a.octets()),
+    }
+    aad.extend_from_slice(&src.port().to_be_bytes());
+    aad
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
@@ -0,0 +1,673 @@
+// batched udp for quic.
+//
+// a quic packet is ~1350 bytes, so at any real rate the syscall per packet is the ceiling.
+// on linux we read up to RECV_BATCH datagrams per recvmmsg, and with GRO the kernel also
+// coalesces back to back packets from one sender into one buffer that we split on the segment
+// size it reports. sending, the packets quiche produces for a connection in one turn are laid
+// back to back and handed to the kernel as one GSO train, and all the trains of a turn go out
+// in one sendmmsg.
+//
+// elsewhere it is recv_from/send_to, one packet per call, behind the same interface.
+//
+// on linux a listener can also do all this through io_uring instead (UdpIo::Uring, see
+// uring.rs); it uses the same sockopts and builds the same messages.
+//
+// quiche paces: each packet comes with the time it should leave. with SO_TXTIME the kernel's
+// fq qdisc holds it until then; other qdiscs ignore the time, so unless the default qdisc is
+// fq the listener holds packets itself instead (user pacing). without pacing a bulk transfer
+// leaves in line-rate bursts that fill router queues, and interactive rpcs wait behind them.
+
+use std::{io, net::SocketAddr, ops::Range, time::Instant};
+
+use log::*;
+use mio::net::UdpSocket;
+
+use super::uring::UdpRing;
+
+const RECV_BATCH: usize = 16;
+// a GRO buffer can be as big as a udp datagram gets
+pub(super) const SLOT: usize = 65535;
+pub(super) const SEND_BATCH: usize = 32;
+const SEND_BUF: usize = 512 * 1024;
+// the kernel's limits on one GSO send
+const MAX_SEGMENTS: usize = 64;
+const MAX_TRAIN: usize = 64000;
+// packets due within this of the first in a train leave with it
+const PACING_SLACK: std::time::Duration = std::time::Duration::from_millis(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Pacing {
+    // the kernel if the qdisc will honor it, else user
+    #[default]
+    Auto,
+    Kernel,
+    User,
+    Off,
+}
+
+// what the listener's udp goes through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UdpIo {
+    // readiness from mio, then recvmmsg/sendmmsg
+    #[default]
+    Mio,
+    // completions from io_uring, falling back to mio where the kernel can't
+    Uring,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct UdpStats {
+    pub recv_syscalls: u64,
+    pub recv_packets: u64,
+    pub send_syscalls: u64,
+    pub send_packets: u64,
+}
+impl UdpStats {
+    // what batching buys; 1.0 is no batching at all
+    pub fn recv_syscalls_per_packet(&self) -> f64 {
+        self.recv_syscalls as f64 / self.recv_packets.max(1) as f64
+    }
+    pub fn send_syscalls_per_packet(&self) -> f64 {
+        self.send_syscalls as f64 / self.send_packets.max(1) as f64
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Received {
+    len: usize,
+    segment: usize,
+    from: SocketAddr,
+    // the SLOT it was read into; entries that can't be used are skipped, so not the index
+    slot: usize,
+}
+
+#[derive(Default)]
+pub struct RecvBatch {
+    buf: Box<[u8]>,
+    msgs: Vec<Received>,
+}
+
+impl RecvBatch {
+    pub fn new(socket: &UdpSocket) -> Self {
+        let gro = sys::enable_gro(socket);
+        debug!("udp gro {}", gro);
+        Self {
+            buf: vec![0; RECV_BATCH * SLOT].into_boxed_slice(),
+            msgs: Vec::with_capacity(RECV_BATCH),
+        }
+    }
+
+    // read what the socket has, up to a batch. 0 when it would block.
+    pub fn recv(&mut self, socket: &UdpSocket, stats: &mut UdpStats) -> io::Result<usize> {
+        self.msgs.clear();
+        sys::recv(socket, &mut self.buf, &mut self.msgs, stats)?;
+        for m in &self.msgs {
+            stats.recv_packets += m.len.div_ceil(m.segment.max(1)) as u64;
+        }
+        Ok(self.msgs.len())
+    }
+
+    // datagram `i` of the last recv: the buffer, its segment size and the sender. GRO only
+    // coalesces packets of equal size (the last may be shorter), so chunks of the segment
+    // size are the packets.
+    pub fn get(&mut self, i: usize) -> (&mut [u8], usize, SocketAddr) {
+        let m = self.msgs[i];
+        let start = m.slot * SLOT;
+        (
+            &mut self.buf[start..start + m.len],
+            m.segment.max(1),
+            m.from,
+        )
+    }
+}
+
+pub struct Train {
+    pub(super) range: Range<usize>,
+    pub(super) to: SocketAddr,
+    pub(super) segment: usize,
+    pub(super) count: usize,
+    pub(super) at: Instant,
+}
+
+pub struct SendBatch {
+    buf: Box<[u8]>,
+    used: usize,
+    msgs: Vec<Train>,
+    // whether the next packet may join the last train
+    open: bool,
+    gso: bool,
+    pacing: Pacing,
+}
+
+impl SendBatch {
+    pub fn new(socket: &UdpSocket, pacing: Pacing) -> Self {
+        let gso = sys::detect_gso(socket);
+        let pacing = match pacing {
+            Pacing::Auto | Pacing::Kernel => {
+                let kernel = (pacing == Pacing::Kernel || sys::qdisc_paces())
+                    && crate::quiche::set_txtime_sockopt(socket).is_ok();
+                match kernel {
+                    true => Pacing::Kernel,
+                    false => Pacing::User,
+                }
+            }
+            p => p,
+        };
+        debug!("udp gso {} pacing {:?}", gso, pacing);
+        Self {
+            buf: vec![0; SEND_BUF].into_boxed_slice(),
+            used: 0,
+            msgs: Vec::with_capacity(SEND_BATCH),
+            open: false,
+            gso,
+            pacing,
+        }
+    }
+
+    // is there room for another packet of up to `max` bytes, or must we flush first?
+    pub fn has_room(&self, max: usize) -> bool {
+        self.msgs.len() < SEND_BATCH && SEND_BUF - self.used >= max
+    }
+
+    // whether the caller has to hold packets until they are due
+    pub fn user_pacing(&self) -> bool {
+        self.pacing == Pacing::User
+    }
+
+    // where quiche writes the next packet
+    pub fn space(&mut self, max: usize) -> &mut [u8] {
+        &mut self.buf[self.used..self.used + max]
+    }
+
+    // a packet that was held back
+    pub fn push(&mut self, pkt: &[u8], to: SocketAddr, at: Instant) {
+        self.space(pkt.len()).copy_from_slice(pkt);
+        self.commit(pkt.len(), to, at);
+    }
+
+    // the packet written to space() is `len` bytes for `to`, due to leave at `at`.
+    pub fn commit(&mut self, len: usize, to: SocketAddr, at: Instant) {
+        let start = self.used;
+        self.used += len;
+        if let Some(t) = self.msgs.last_mut() {
+            if self.open
+                && self.gso
+                && t.to == to
+                && at.saturating_duration_since(t.at) <= PACING_SLACK
+                && t.range.end == start
+                && len <= t.segment
+                && t.count < MAX_SEGMENTS
+                && t.range.len() + len <= MAX_TRAIN
+            {
+                t.range.end = self.used;
+                t.count += 1;
+                // a short packet has to be the last of its train
+                self.open = len == t.segment;
+                return;
+            }
+        }
+        self.msgs.push(Train {
+            range: start..self.used,
+            to,
+            segment: len,
+            count: 1,
+            at,
+        });
+        self.open = true;
+    }
+
+    // packets from different connections never share a train
+    pub fn end_train(&mut self) {
+        self.open = false;
+    }
+
+    // a packet that doesn't go out is lost like on the wire, quiche will retransmit it.
+    pub fn flush(&mut self, socket: &UdpSocket, stats: &mut UdpStats) -> io::Result<()> {
+        let txtime = self.pacing == Pacing::Kernel;
+        let r = sys::send(socket, &self.buf, &self.msgs, &mut self.gso, txtime, stats);
+        self.msgs.clear();
+        self.used = 0;
+        self.open = false;
+        r
+    }
+
+    // the same through the ring, which keeps the buffer until the kernel is done with it and
+    // leaves another in its place.
+    pub fn flush_ring(&mut self, ring: &mut UdpRing, stats: &mut UdpStats) -> io::Result<()> {
+        let txtime = self.pacing == Pacing::Kernel;
+        if self.gso && ring.take_gso_failed() {
+            warn!("udp gso failed, sending packets one by one from now on");
+            self.gso = false;
+        }
+        let r = ring.send(&mut self.buf, &self.msgs, txtime, stats);
+        self.msgs.clear();
+        self.used = 0;
+        self.open = false;
+        r
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(super) mod sys {
+    use std::{
+        io, mem,
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+        os::fd::AsRawFd,
+        ptr,
+        time::Instant,
+    };
+
+    use log::*;
+    use mio::net::UdpSocket;
+
+    use super::{Received, Train, UdpStats, RECV_BATCH, SEND_BATCH, SLOT};
+
+    // room for two small control messages, aligned for cmsghdr
+    pub type Cmsg = [u64; 8];
+
+    pub fn enable_gro(socket: &UdpSocket) -> bool {
+        let one: libc::c_int = 1;
+        unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_UDP,
+                libc::UDP_GRO,
+                &one as *const _ as *const libc::c_void,
+                mem::size_of_val(&one) as libc::socklen_t,
+            ) == 0
+        }
+    }
+
+    // reading the option works only where the kernel knows it. setting it would make it the
+    // default for every send.
+    pub fn detect_gso(socket: &UdpSocket) -> bool {
+        let mut v: libc::c_int = 0;
+        let mut len = mem::size_of_val(&v) as libc::socklen_t;
+        unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_UDP,
+                libc::UDP_SEGMENT,
+                &mut v as *mut _ as *mut libc::c_void,
+                &mut len,
+            ) == 0
+        }
+    }
+
+    // fq is the qdisc that honors SO_TXTIME. this only sees the default, an interface
+    // configured otherwise fools it.
+    pub fn qdisc_paces() -> bool {
+        std::fs::read_to_string("/proc/sys/net/core/default_qdisc")
+            .map(|q| q.trim() == "fq")
+            .unwrap_or(false)
+    }
+
+    pub(super) fn recv(
+        socket: &UdpSocket,
+        buf: &mut [u8],
+        out: &mut Vec<Received>,
+        stats: &mut UdpStats,
+    ) -> io::Result<()> {
+        let mut names: [libc::sockaddr_storage; RECV_BATCH] = unsafe { mem::zeroed() };
+        let mut cmsgs: [Cmsg; RECV_BATCH] = [[0; 8]; RECV_BATCH];
+        let mut iovs: [libc::iovec; RECV_BATCH] = unsafe { mem::zeroed() };
+        let mut hdrs: [libc::mmsghdr; RECV_BATCH] = unsafe { mem::zeroed() };
+        for i in 0..RECV_BATCH {
+            iovs[i] = libc::iovec {
+                iov_base: buf[i * SLOT..].as_mut_ptr() as *mut libc::c_void,
+                iov_len: SLOT,
+            };
+            let h = &mut hdrs[i].msg_hdr;
+            h.msg_name = &mut names[i] as *mut _ as *mut libc::c_void;
+            h.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            h.msg_iov = &mut iovs[i];
+            h.msg_iovlen = 1;
+            h.msg_control = cmsgs[i].as_mut_ptr() as *mut libc::c_void;
+            h.msg_controllen = mem::size_of::<Cmsg>() as _;
+        }
+
+        let n = loop {
+            let n = unsafe {
+                libc::recvmmsg(
+                    socket.as_raw_fd(),
+                    hdrs.as_mut_ptr(),
+                    RECV_BATCH as _,
+                    libc::MSG_DONTWAIT as _,
+                    ptr::null_mut(),
+                )
+            };
+            stats.recv_syscalls += 1;
+            if n >= 0 {
+                break n as usize;
+            }
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::Interrupted => continue,
+                io::ErrorKind::WouldBlock => return Ok(()),
+                _ => return Err(e),
+            }
+        };
+
+        received(&hdrs[..n], &names, out);
+        Ok(())
+    }
+
+    // the entries recvmmsg filled, less those from an address we can't reply to
+    pub(super) fn received(
+        hdrs: &[libc::mmsghdr],
+        names: &[libc::sockaddr_storage],
+        out: &mut Vec<Received>,
+    ) {
+        for (slot, (h, name)) in hdrs.iter().zip(names).enumerate() {
+            let len = h.msg_len as usize;
+            let Some(from) = to_socket_addr(name) else {
+                continue;
+            };
+            let segment = gro_segment(&h.msg_hdr, len);
+            out.push(Received {
+                len,
+                segment,
+                from,
+                slot,
+            });
+        }
+    }
+
+    // the segment size GRO reports in the control messages of `h`, `len` without GRO
+    pub fn gro_segment(h: &libc::msghdr, len: usize) -> usize {
+        let mut segment = len;
+        unsafe {
+            let mut c = libc::CMSG_FIRSTHDR(h);
+            while !c.is_null() {
+                if (*c).cmsg_level == libc::SOL_UDP && (*c).cmsg_type == libc::UDP_GRO {
+                    segment =
+                        ptr::read_unaligned(libc::CMSG_DATA(c) as *const libc::c_int) as usize;
+                }
+                c = libc::CMSG_NXTHDR(h, c);
+            }
+        }
+        segment
+    }
+
+    pub(super) fn send(
+        socket: &UdpSocket,
+        buf: &[u8],
+        msgs: &[Train],
+        gso: &mut bool,
+        txtime: bool,
+        stats: &mut UdpStats,
+    ) -> io::Result<()> {
+        if msgs.is_empty() {
+            return Ok(());
+        }
+        // SCM_TXTIME wants CLOCK_MONOTONIC nanoseconds, which an Instant doesn't expose.
+        let (now, now_ns) = (Instant::now(), monotonic_ns());
+        let mut names: [libc::sockaddr_storage; SEND_BATCH] = unsafe { mem::zeroed() };
+        let mut cmsgs: [Cmsg; SEND_BATCH] = [[0; 8]; SEND_BATCH];
+        let mut iovs: [libc::iovec; SEND_BATCH] = unsafe { mem::zeroed() };
+        let mut hdrs: [libc::mmsghdr; SEND_BATCH] = unsafe { mem::zeroed() };
+        for (i, t) in msgs.iter().enumerate() {
+            let txtime = txtime.then_some((now, now_ns));
+            fill_send(
+                &mut hdrs[i].msg_hdr,
+                buf,
+                t,
+                &mut names[i],
+                &mut iovs[i],
+                &mut cmsgs[i],
+                txtime,
+            );
+        }
+
+        let mut sent = 0;
+        while sent < msgs.len() {
+            let n = unsafe {
+                libc::sendmmsg(
+                    socket.as_raw_fd(),
+                    hdrs[sent..].as_mut_ptr(),
+                    (msgs.len() - sent) as _,
+                    libc::MSG_DONTWAIT as _,
+                )
+            };
+            stats.send_syscalls += 1;
+            if n >= 0 {
+                for t in &msgs[sent..sent + n as usize] {
+                    stats.send_packets += t.count as u64;
+                }
+                sent += n as usize;
+                continue;
+            }
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::EAGAIN) => {
+                    debug!("send() would block, {} datagrams lost", msgs.len() - sent);
+                    return Ok(());
+                }
+                // the device can't do the checksums GSO needs
+                Some(libc::EIO) if *gso => {
+                    warn!("udp gso failed, sending packets one by one from now on");
+                    *gso = false;
+                    return Ok(());
+                }
+                // one unreachable peer shouldn't cost everyone else their packets
+                _ => {
+                    debug!("send to {} failed: {:?}", msgs[sent].to, e);
+                    sent += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // the message for one train: its bytes, where to, and in control messages the GSO segment
+    // size and, given the clock as (now, CLOCK_MONOTONIC ns), when it should leave. `h` points
+    // into `name`, `iov` and `cmsg`, they have to stay put while it is used.
+    pub fn fill_send(
+        h: &mut libc::msghdr,
+        buf: &[u8],
+        t: &Train,
+        name: &mut libc::sockaddr_storage,
+        iov: &mut libc::iovec,
+        cmsg: &mut Cmsg,
+        txtime: Option<(Instant, u64)>,
+    ) {
+        *iov = libc::iovec {
+            iov_base: buf[t.range.clone()].as_ptr() as *mut libc::c_void,
+            iov_len: t.range.len(),
+        };
+        h.msg_namelen = from_socket_addr(&t.to, name);
+        h.msg_name = name as *mut _ as *mut libc::c_void;
+        h.msg_iov = iov;
+        h.msg_iovlen = 1;
+        h.msg_control = cmsg.as_mut_ptr() as *mut libc::c_void;
+        let mut controllen = 0;
+        unsafe {
+            if t.count > 1 {
+                let c = (h.msg_control as *mut u8).add(controllen) as *mut libc::cmsghdr;
+                (*c).cmsg_level = libc::SOL_UDP;
+                (*c).cmsg_type = libc::UDP_SEGMENT;
+                (*c).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+                ptr::write_unaligned(libc::CMSG_DATA(c) as *mut u16, t.segment as u16);
+                controllen += libc::CMSG_SPACE(mem::size_of::<u16>() as u32) as usize;
+            }
+            if let Some((now, now_ns)) = txtime {
+                let at = match t.at.checked_duration_since(now) {
+                    Some(d) => now_ns + d.as_nanos() as u64,
+                    None => now_ns,
+                };
+                let c = (h.msg_control as *mut u8).add(controllen) as *mut libc::cmsghdr;
+                (*c).cmsg_level = libc::SOL_SOCKET;
+                (*c).cmsg_type = libc::SCM_TXTIME;
+                (*c).cmsg_len = libc::CMSG_LEN(mem::size_of::<u64>() as u32) as _;
+                ptr::write_unaligned(libc::CMSG_DATA(c) as *mut u64, at);
+                controllen += libc::CMSG_SPACE(mem::size_of::<u64>() as u32) as usize;
+            }
+        }
+        match controllen {
+            0 => {
+                h.msg_control = ptr::null_mut();
+                h.msg_controllen = 0;
+            }
+            n => h.msg_controllen = n as _,
+        }
+    }
+
+    pub fn monotonic_ns() -> u64 {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+        ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+    }
+
+    pub fn to_socket_addr(s: &libc::sockaddr_storage) -> Option<SocketAddr> {
+        match s.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let a = unsafe { &*(s as *const _ as *const libc::sockaddr_in) };
+                Some(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from_be(a.sin_addr.s_addr)),
+                    u16::from_be(a.sin_port),
+                )))
+            }
+            libc::AF_INET6 => {
+                let a = unsafe { &*(s as *const _ as *const libc::sockaddr_in6) };
+                Some(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(a.sin6_addr.s6_addr),
+                    u16::from_be(a.sin6_port),
+                    a.sin6_flowinfo,
+                    a.sin6_scope_id,
+                )))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn from_socket_addr(addr: &SocketAddr, s: &mut libc::sockaddr_storage) -> libc::socklen_t {
+        match addr {
+            SocketAddr::V4(a) => {
+                let sin = unsafe { &mut *(s as *mut _ as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = a.port().to_be();
+                sin.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+                mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+            }
+            SocketAddr::V6(a) => {
+                let sin6 = unsafe { &mut *(s as *mut _ as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = a.port().to_be();
+                sin6.sin6_flowinfo = a.flowinfo();
+                sin6.sin6_addr.s6_addr = a.ip().octets();
+                sin6.sin6_scope_id = a.scope_id();
+                mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::io;
+
+    use mio::net::UdpSocket;
+
+    use super::{Received, Train, UdpStats, RECV_BATCH, SLOT};
+
+    pub fn enable_gro(_: &UdpSocket) -> bool {
+        false
+    }
+
+    pub fn detect_gso(_: &UdpSocket) -> bool {
+        false
+    }
+
+    pub fn qdisc_paces() -> bool {
+        false
+    }
+
+    pub fn recv(
+        socket: &UdpSocket,
+        buf: &mut [u8],
+        out: &mut Vec<Received>,
+        stats: &mut UdpStats,
+    ) -> io::Result<()> {
+        let slots = buf.len() / SLOT;
+        let mut slot = 0;
+        while slot < RECV_BATCH.min(slots) {
+            stats.recv_syscalls += 1;
+            match socket.recv_from(&mut buf[slot * SLOT..(slot + 1) * SLOT]) {
+                Ok((len, from)) => {
+                    out.push(Received {
+                        len,
+                        segment: len,
+                        from,
+                        slot,
+                    });
+                    slot += 1;
+                }
+                // the slot is still free
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn send(
+        socket: &UdpSocket,
+        buf: &[u8],
+        msgs: &[Train],
+        _gso: &mut bool,
+        _txtime: bool,
+        stats: &mut UdpStats,
+    ) -> io::Result<()> {
+        for t in msgs {
+            for pkt in buf[t.range.clone()].chunks(t.segment) {
+                stats.send_syscalls += 1;
+                match socket.send_to(pkt, t.to) {
+                    Ok(_) => stats.send_packets += 1,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::mem;
+
+    use super::*;
+
+    // an entry recvmmsg filled from an address that isn't ip is left out, and the ones after
+    // it still get their own bytes
+    #[test]
+    fn skipped_entry() {
+        let from = "192.0.2.1:4433".parse::<SocketAddr>().unwrap();
+        let mut names: [libc::sockaddr_storage; 3] = unsafe { mem::zeroed() };
+        let mut hdrs: [libc::mmsghdr; 3] = unsafe { mem::zeroed() };
+        for (i, (h, name)) in hdrs.iter_mut().zip(&mut names).enumerate() {
+            if i != 1 {
+                sys::from_socket_addr(&from, name);
+            }
+            h.msg_len = 10 + i as u32;
+        }
+        let mut batch = RecvBatch {
+            buf: vec![0; 3 * SLOT].into_boxed_slice(),
+            msgs: Vec::new(),
+        };
+        for (i, slot) in batch.buf.chunks_mut(SLOT).enumerate() {
+            slot.fill(i as u8);
+        }
+        sys::received(&hdrs, &names, &mut batch.msgs);
+        assert_eq!(batch.msgs.len(), 2);
+        let (buf, segment, addr) = batch.get(0);
+        assert_eq!((&buf[..], segment, addr), (&[0; 10][..], 10, from));
+        let (buf, segment, addr) = batch.get(1);
+        assert_eq!((&buf[..], segment, addr), (&[2; 12][..], 12, from));
+    }
+}
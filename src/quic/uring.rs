@@ -0,0 +1,593 @@
+// the quic datagram path on io_uring, for listeners configured with UdpIo::Uring.
+//
+// receiving is one multishot recvmsg that stays armed: for each datagram (or GRO train) the
+// kernel takes a buffer from a ring of provided buffers and posts a completion, so a busy
+// socket is read without a syscall until the buffers run out and it has to be armed again.
+// sending, a flush is one SendMsg per train with the same GSO and txtime cmsgs as sendmmsg,
+// all submitted with one io_uring_enter; the batch's buffer stays with the ring until the
+// kernel is done with it. the listener's timer is an IORING_OP_TIMEOUT, so the ring's fd is
+// the only thing the worker polls for quic.
+//
+// multishot recvmsg needs linux 6.0. where the ring can't be set up the listener stays on mio.
+
+#[cfg(target_os = "linux")]
+pub use imp::UdpRing;
+#[cfg(not(target_os = "linux"))]
+pub use stub::UdpRing;
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::{
+        alloc::{self, Layout},
+        collections::VecDeque,
+        io, mem,
+        net::SocketAddr,
+        os::fd::{AsRawFd, RawFd},
+        sync::atomic::{AtomicU16, Ordering},
+        time::{Duration, Instant},
+    };
+
+    use io_uring::{cqueue, opcode, squeue, types, IoUring};
+    use log::*;
+    use mio::net::UdpSocket;
+    use slab::Slab;
+
+    use super::super::udp::{
+        sys::{self, Cmsg},
+        Train, UdpStats, SEND_BATCH, SLOT,
+    };
+
+    const ENTRIES: u32 = 256;
+    // provided buffers, a power of two. each can hold a whole GRO train.
+    const BUFS: u16 = 64;
+    const BUF_GROUP: u16 = 0;
+    // a received buffer is the kernel's io_uring_recvmsg_out, the sender's address, the
+    // control messages, then the datagram. rounded up so the next one's cmsgs are aligned.
+    const NAME_LEN: usize = mem::size_of::<libc::sockaddr_storage>();
+    const CONTROL_LEN: usize = mem::size_of::<Cmsg>();
+    const RECVMSG_OUT: usize = 16;
+    const BUF_LEN: usize = (RECVMSG_OUT + NAME_LEN + CONTROL_LEN + SLOT).next_multiple_of(64);
+    // send batches in flight at once
+    const FLIGHTS: usize = 4;
+
+    // user data; a send is its flight's key and the train's index in it.
+    const RECV: u64 = u64::MAX;
+    const TIMER: u64 = u64::MAX - 1;
+    const TIMER_UPDATE: u64 = u64::MAX - 2;
+    // cancelling the recvmsg or the timer
+    const CANCEL: u64 = u64::MAX - 3;
+    // a timer this close to the one wanted is left alone, rather than updated every turn
+    const TIMER_SLACK: Duration = Duration::from_millis(1);
+
+    // one flush: the buffer and the messages pointing into it, which the kernel reads until
+    // every send completed. boxed, so the pointers survive the flight moving about.
+    struct Flight {
+        buf: Box<[u8]>,
+        hdrs: [libc::msghdr; SEND_BATCH],
+        iovs: [libc::iovec; SEND_BATCH],
+        names: [libc::sockaddr_storage; SEND_BATCH],
+        cmsgs: [Cmsg; SEND_BATCH],
+        // packets in each train
+        counts: [usize; SEND_BATCH],
+        // sends not completed yet
+        left: usize,
+    }
+
+    pub struct UdpRing {
+        // first, so the ring is gone before the memory it was given
+        ring: IoUring,
+        fd: RawFd,
+        bufs: Box<[u8]>,
+        // the ring of provided buffers the kernel picks from, and where we put them back
+        entries: *mut types::BufRingEntry,
+        tail: u16,
+        // what the recvmsg is armed with: only the name and control lengths matter
+        msghdr: Box<libc::msghdr>,
+        // recvmsg stops when it runs out of buffers or fails
+        armed: bool,
+        // recv completions not handed to the listener yet, (result, flags)
+        received: VecDeque<(i32, u32)>,
+        flights: Slab<Box<Flight>>,
+        spare: Vec<Box<Flight>>,
+        timer: Box<types::Timespec>,
+        // when the armed timer fires
+        deadline: Option<Instant>,
+        gso_failed: bool,
+        // packets sent since the stats last got them
+        sent: u64,
+    }
+
+    impl UdpRing {
+        pub fn new(socket: &UdpSocket) -> io::Result<Self> {
+            let ring = IoUring::new(ENTRIES)?;
+            let entries = unsafe { alloc::alloc_zeroed(entries_layout()) };
+            if entries.is_null() {
+                return Err(io::ErrorKind::OutOfMemory.into());
+            }
+            let mut msghdr: Box<libc::msghdr> = Box::new(unsafe { mem::zeroed() });
+            msghdr.msg_namelen = NAME_LEN as _;
+            msghdr.msg_controllen = CONTROL_LEN as _;
+            let mut this = Self {
+                ring,
+                fd: socket.as_raw_fd(),
+                bufs: vec![0; BUFS as usize * BUF_LEN].into_boxed_slice(),
+                entries: entries as *mut types::BufRingEntry,
+                tail: 0,
+                msghdr,
+                armed: false,
+                received: VecDeque::new(),
+                flights: Slab::with_capacity(FLIGHTS),
+                spare: Vec::new(),
+                timer: Box::new(types::Timespec::new()),
+                deadline: None,
+                gso_failed: false,
+                sent: 0,
+            };
+            // before 5.19 this fails and the listener stays on mio
+            unsafe {
+                this.ring.submitter().register_buf_ring_with_flags(
+                    this.entries as u64,
+                    BUFS,
+                    BUF_GROUP,
+                    0,
+                )?;
+            }
+            for bid in 0..BUFS {
+                this.recycle(bid);
+            }
+            // a kernel without multishot recvmsg rejects it, one with it keeps it armed and
+            // says nothing while no datagram comes. so the probe is cancelled, and its last
+            // completion says which it was.
+            let mut stats = UdpStats::default();
+            this.arm_recv(&mut stats)?;
+            let sqe = opcode::AsyncCancel::new(RECV).build().user_data(CANCEL);
+            push(&mut this.ring, &sqe, &mut stats)?;
+            this.settle(|this| !this.armed)?;
+            match this.received.back() {
+                Some(&(res, _)) if res == -libc::EINVAL => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "no multishot recvmsg",
+                    ));
+                }
+                // what came before it are datagrams for the listener
+                Some(&(res, _)) if res == -libc::ECANCELED => _ = this.received.pop_back(),
+                _ => (),
+            }
+            this.arm_recv(&mut stats)?;
+            Ok(this)
+        }
+
+        // hand each datagram received to `f` as (buffer, segment size, sender). GRO only
+        // coalesces packets of equal size, so chunks of the segment size are the packets.
+        pub fn recv<E: From<io::Error>>(
+            &mut self,
+            stats: &mut UdpStats,
+            mut f: impl FnMut(&mut [u8], usize, SocketAddr) -> Result<(), E>,
+        ) -> Result<(), E> {
+            self.complete();
+            stats.send_packets += mem::take(&mut self.sent);
+            while let Some((res, flags)) = self.received.pop_front() {
+                let bid = cqueue::buffer_select(flags);
+                let r = match (res, bid) {
+                    (res, Some(bid)) if res >= 0 => self.datagram(bid, res as usize, stats, &mut f),
+                    (res, _) if res == -libc::ENOBUFS => {
+                        debug!("udp ring out of buffers, armed again");
+                        Ok(())
+                    }
+                    (res, _) => {
+                        debug!("recvmsg failed: {}", io::Error::from_raw_os_error(-res));
+                        Ok(())
+                    }
+                };
+                if let Some(bid) = bid {
+                    self.recycle(bid);
+                }
+                r?;
+            }
+            if !self.armed {
+                self.arm_recv(stats)?;
+            }
+            Ok(())
+        }
+
+        fn datagram<E>(
+            &mut self,
+            bid: u16,
+            len: usize,
+            stats: &mut UdpStats,
+            f: &mut impl FnMut(&mut [u8], usize, SocketAddr) -> Result<(), E>,
+        ) -> Result<(), E> {
+            let start = bid as usize * BUF_LEN;
+            let buf = &mut self.bufs[start..start + len];
+            let Ok(out) = types::RecvMsgOut::parse(buf, &self.msghdr) else {
+                return Ok(());
+            };
+            if out.is_payload_truncated() || out.is_name_data_truncated() {
+                return Ok(());
+            }
+            let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+            let name_data = out.name_data();
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    name_data.as_ptr(),
+                    &mut name as *mut _ as *mut u8,
+                    name_data.len(),
+                );
+            }
+            let Some(from) = sys::to_socket_addr(&name) else {
+                return Ok(());
+            };
+            let payload = out.payload_data().len();
+            let segment = {
+                // walked with the libc macros, which want a msghdr around the cmsgs
+                let control = out.control_data();
+                let mut h: libc::msghdr = unsafe { mem::zeroed() };
+                h.msg_control = control.as_ptr() as *mut libc::c_void;
+                h.msg_controllen = control.len() as _;
+                sys::gro_segment(&h, payload).max(1)
+            };
+            stats.recv_packets += payload.div_ceil(segment) as u64;
+            let offset = RECVMSG_OUT + NAME_LEN + CONTROL_LEN;
+            f(&mut buf[offset..offset + payload], segment, from)
+        }
+
+        // send the trains, taking `buf` along until they are out and leaving a spare in its
+        // place. as with sendmmsg, a packet the socket has no room for is lost.
+        pub fn send(
+            &mut self,
+            buf: &mut Box<[u8]>,
+            msgs: &[Train],
+            txtime: bool,
+            stats: &mut UdpStats,
+        ) -> io::Result<()> {
+            if msgs.is_empty() {
+                return Ok(());
+            }
+            let mut flight = self.spare_flight(buf.len(), stats)?;
+            stats.send_packets += mem::take(&mut self.sent);
+            mem::swap(&mut flight.buf, buf);
+            let txtime = txtime.then(|| (Instant::now(), sys::monotonic_ns()));
+            let Flight {
+                buf,
+                hdrs,
+                iovs,
+                names,
+                cmsgs,
+                counts,
+                left,
+            } = &mut *flight;
+            for (i, t) in msgs.iter().enumerate() {
+                sys::fill_send(
+                    &mut hdrs[i],
+                    buf,
+                    t,
+                    &mut names[i],
+                    &mut iovs[i],
+                    &mut cmsgs[i],
+                    txtime,
+                );
+                counts[i] = t.count;
+            }
+            *left = msgs.len();
+            let entry = self.flights.vacant_entry();
+            let key = entry.key() as u64;
+            let flight = entry.insert(flight);
+            for i in 0..msgs.len() {
+                let sqe = opcode::SendMsg::new(types::Fd(self.fd), &flight.hdrs[i])
+                    .flags(libc::MSG_DONTWAIT as u32)
+                    .build()
+                    .user_data(key << 8 | i as u64);
+                push(&mut self.ring, &sqe, stats)?;
+            }
+            submit(&self.ring, &mut stats.send_syscalls)
+        }
+
+        // reported by a send that completed since; the caller stops using GSO.
+        pub fn take_gso_failed(&mut self) -> bool {
+            mem::take(&mut self.gso_failed)
+        }
+
+        // fire after `timeout`, so the worker's poll wakes for the listener's timers. a timer
+        // that isn't needed anymore is left to fire once for nothing.
+        pub fn set_timer(
+            &mut self,
+            timeout: Option<Duration>,
+            stats: &mut UdpStats,
+        ) -> io::Result<()> {
+            let Some(timeout) = timeout else {
+                return Ok(());
+            };
+            let deadline = Instant::now() + timeout;
+            if let Some(d) = self.deadline {
+                if d <= deadline + TIMER_SLACK && deadline <= d + TIMER_SLACK {
+                    return Ok(());
+                }
+            }
+            *self.timer = types::Timespec::from(timeout);
+            let sqe = match self.deadline {
+                Some(_) => opcode::TimeoutUpdate::new(TIMER, &*self.timer)
+                    .build()
+                    .user_data(TIMER_UPDATE),
+                None => opcode::Timeout::new(&*self.timer).build().user_data(TIMER),
+            };
+            self.deadline = Some(deadline);
+            push(&mut self.ring, &sqe, stats)?;
+            submit(&self.ring, &mut stats.send_syscalls)
+        }
+
+        fn arm_recv(&mut self, stats: &mut UdpStats) -> io::Result<()> {
+            let sqe = opcode::RecvMsgMulti::new(types::Fd(self.fd), &*self.msghdr, BUF_GROUP)
+                .build()
+                .user_data(RECV);
+            push(&mut self.ring, &sqe, stats)?;
+            self.armed = true;
+            submit(&self.ring, &mut stats.recv_syscalls)
+        }
+
+        // give buffer `bid` back to the kernel
+        fn recycle(&mut self, bid: u16) {
+            let mask = BUFS - 1;
+            unsafe {
+                let e = &mut *self.entries.add((self.tail & mask) as usize);
+                e.set_addr(self.bufs[bid as usize * BUF_LEN..].as_ptr() as u64);
+                e.set_len(BUF_LEN as u32);
+                e.set_bid(bid);
+                self.tail = self.tail.wrapping_add(1);
+                let tail = types::BufRingEntry::tail(self.entries) as *const AtomicU16;
+                (*tail).store(self.tail, Ordering::Release);
+            }
+        }
+
+        // wait for completions until `done`
+        fn settle(&mut self, done: impl Fn(&Self) -> bool) -> io::Result<()> {
+            loop {
+                self.complete();
+                if done(self) {
+                    return Ok(());
+                }
+                match self.ring.submit_and_wait(1) {
+                    Ok(_) => (),
+                    Err(e) if e.raw_os_error() == Some(libc::EINTR) => (),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        // cancel what is in flight and wait for it: until then the kernel may write into
+        // the buffers or read a flight.
+        fn shut_down(&mut self) -> io::Result<()> {
+            let mut stats = UdpStats::default();
+            if self.armed {
+                let sqe = opcode::AsyncCancel::new(RECV).build().user_data(CANCEL);
+                push(&mut self.ring, &sqe, &mut stats)?;
+            }
+            if self.deadline.is_some() {
+                let sqe = opcode::TimeoutRemove::new(TIMER).build().user_data(CANCEL);
+                push(&mut self.ring, &sqe, &mut stats)?;
+            }
+            // sends don't wait for room in the socket, so they finish on their own
+            self.settle(|this| !this.armed && this.deadline.is_none() && this.flights.is_empty())
+        }
+
+        // a flight to send with, waiting for one to come back if all are out
+        fn spare_flight(&mut self, len: usize, stats: &mut UdpStats) -> io::Result<Box<Flight>> {
+            loop {
+                self.complete();
+                if let Some(f) = self.spare.pop() {
+                    return Ok(f);
+                }
+                if self.flights.len() < FLIGHTS {
+                    return Ok(Box::new(Flight {
+                        buf: vec![0; len].into_boxed_slice(),
+                        hdrs: unsafe { mem::zeroed() },
+                        iovs: unsafe { mem::zeroed() },
+                        names: unsafe { mem::zeroed() },
+                        cmsgs: [[0; 8]; SEND_BATCH],
+                        counts: [0; SEND_BATCH],
+                        left: 0,
+                    }));
+                }
+                stats.send_syscalls += 1;
+                match self.ring.submit_and_wait(1) {
+                    Ok(_) => (),
+                    Err(e) if e.raw_os_error() == Some(libc::EINTR) => (),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        // take the completions the kernel posted: recvs are queued for `recv`, sends free
+        // their flight once all of them are in.
+        fn complete(&mut self) {
+            let Self {
+                ring,
+                armed,
+                received,
+                flights,
+                spare,
+                deadline,
+                gso_failed,
+                sent,
+                ..
+            } = self;
+            for cqe in ring.completion() {
+                let (res, flags) = (cqe.result(), cqe.flags());
+                match cqe.user_data() {
+                    RECV => {
+                        if !cqueue::more(flags) {
+                            *armed = false;
+                        }
+                        received.push_back((res, flags));
+                    }
+                    TIMER => *deadline = None,
+                    // the timer had already fired, its completion says so
+                    TIMER_UPDATE => (),
+                    // the cancelled operation's own completion says when it's done
+                    CANCEL => (),
+                    ud => {
+                        let (key, i) = ((ud >> 8) as usize, (ud & 0xff) as usize);
+                        let Some(f) = flights.get_mut(key) else {
+                            continue;
+                        };
+                        match res {
+                            res if res >= 0 => *sent += f.counts[i] as u64,
+                            res if res == -libc::EAGAIN => {
+                                debug!("send() would block, {} packets lost", f.counts[i]);
+                            }
+                            // the device can't do the checksums GSO needs
+                            res if res == -libc::EIO && f.counts[i] > 1 => *gso_failed = true,
+                            res => debug!("send failed: {:?}", io::Error::from_raw_os_error(-res)),
+                        }
+                        f.left -= 1;
+                        if f.left == 0 {
+                            spare.push(flights.remove(key));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    impl AsRawFd for UdpRing {
+        fn as_raw_fd(&self) -> RawFd {
+            self.ring.as_raw_fd()
+        }
+    }
+
+    impl Drop for UdpRing {
+        fn drop(&mut self) {
+            if let Err(e) = self.shut_down() {
+                // can't tell when the kernel is done with them, so they are never freed
+                error!("udp ring can't shut down, leaking its buffers: {}", e);
+                mem::forget(mem::take(&mut self.bufs));
+                mem::forget(mem::take(&mut self.flights));
+                return;
+            }
+            _ = self.ring.submitter().unregister_buf_ring(BUF_GROUP);
+            unsafe { alloc::dealloc(self.entries as *mut u8, entries_layout()) };
+        }
+    }
+
+    // the kernel wants the buffer ring page aligned
+    fn entries_layout() -> Layout {
+        Layout::from_size_align(BUFS as usize * mem::size_of::<types::BufRingEntry>(), 4096)
+            .unwrap()
+    }
+
+    // a full submission queue is submitted to make room
+    fn push(ring: &mut IoUring, sqe: &squeue::Entry, stats: &mut UdpStats) -> io::Result<()> {
+        loop {
+            if unsafe { ring.submission().push(sqe) }.is_ok() {
+                return Ok(());
+            }
+            submit(ring, &mut stats.send_syscalls)?;
+        }
+    }
+
+    fn submit(ring: &IoUring, syscalls: &mut u64) -> io::Result<()> {
+        loop {
+            *syscalls += 1;
+            match ring.submit() {
+                Ok(_) => return Ok(()),
+                Err(e) if e.raw_os_error() == Some(libc::EINTR) => continue,
+                // the completion queue is full; what we just reaped makes room next time
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+
+    use mio::net::UdpSocket;
+
+    use super::super::udp::UdpStats;
+    use super::UdpRing;
+
+    // a datagram waiting when the probe runs is handed over like any other, and a ring with
+    // its recvmsg and timer armed shuts down cleanly
+    #[test]
+    fn probe_and_drop() {
+        let socket = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let peer = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let to = socket.local_addr().unwrap();
+        peer.send_to(b"early", to).unwrap();
+        let mut ring = match UdpRing::new(&socket) {
+            Ok(ring) => ring,
+            Err(e) => return eprintln!("no udp ring here: {}", e),
+        };
+        peer.send_to(b"late", to).unwrap();
+
+        let mut stats = UdpStats::default();
+        let mut got = Vec::new();
+        let until = Instant::now() + Duration::from_secs(5);
+        while got.len() < 2 && Instant::now() < until {
+            ring.recv(&mut stats, |buf: &mut [u8], _, from: SocketAddr| {
+                assert_eq!(from, peer.local_addr().unwrap());
+                got.push(buf.to_vec());
+                Ok::<_, std::io::Error>(())
+            })
+            .unwrap();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(got, [&b"early"[..], &b"late"[..]]);
+        assert_eq!(stats.recv_packets, 2);
+
+        ring.set_timer(Some(Duration::from_secs(60)), &mut stats)
+            .unwrap();
+        drop(ring);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod stub {
+    use std::{io, net::SocketAddr, os::fd::AsRawFd, time::Duration};
+
+    use mio::net::UdpSocket;
+
+    use super::super::udp::{Train, UdpStats};
+
+    // never made: new always fails and the listener stays on mio
+    pub enum UdpRing {}
+
+    impl UdpRing {
+        pub fn new(_: &UdpSocket) -> io::Result<Self> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+        pub fn recv<E: From<io::Error>>(
+            &mut self,
+            _: &mut UdpStats,
+            _: impl FnMut(&mut [u8], usize, SocketAddr) -> Result<(), E>,
+        ) -> Result<(), E> {
+            match *self {}
+        }
+        pub fn send(
+            &mut self,
+            _: &mut Box<[u8]>,
+            _: &[Train],
+            _: bool,
+            _: &mut UdpStats,
+        ) -> io::Result<()> {
+            match *self {}
+        }
+        pub fn take_gso_failed(&mut self) -> bool {
+            match *self {}
+        }
+        pub fn set_timer(&mut self, _: Option<Duration>, _: &mut UdpStats) -> io::Result<()> {
+            match *self {}
+        }
+    }
+
+    impl AsRawFd for UdpRing {
+        fn as_raw_fd(&self) -> std::os::fd::RawFd {
+            match *self {}
+        }
+    }
+}
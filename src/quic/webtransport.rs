@@ -0,0 +1,899 @@
+// WebTransport over HTTP/3, the browser's way in.
+//
+// a session is an extended CONNECT (:protocol = webtransport) on a request stream. after
+// that the client opens streams that start with a signal and the session id
+//
+//   bidi: [0x41][session id][data...]
+//   uni:  [0x54][session id][data...]
+//
+// and sends datagrams that start with the session id / 4. every stream's data goes to the
+// connection's StreamHandler, which is where rpc dispatch plugs in.
+//
+// quiche's h3 knows nothing about this and reads every readable stream when polled, so we
+// claim webtransport streams before it gets to them. that is only unambiguous if the
+// connection is dedicated to one session: we advertise WEBTRANSPORT_MAX_SESSIONS = 1, and once
+// the session is up every new bidi stream is treated as a webtransport stream. plain HTTP/3
+// requests before that are served as static files like before.
+//
+// uni streams are h3's too: the client may open its QPACK encoder and decoder streams late,
+// after the session is up. quiche can't look at a stream without reading it, so we read the
+// type of every new uni stream and keep the 0x54 ones; the rest are drained, which is what
+// h3 would do with them. they carry nothing, since the dynamic table is off, see h3_config,
+// and they are never shut down, which would close the connection.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use log::*;
+use quiche::h3::NameValue;
+
+use crate::quiche::{
+    hdrs_to_strings, send_h3_dgram, Http3Conn, HttpConn, PartialRequest, PartialResponse,
+};
+
+use super::priority::{self, Priority, Scheduler};
+
+const WEBTRANSPORT_BIDI: u64 = 0x41;
+const WEBTRANSPORT_UNI: u64 = 0x54;
+
+// h3_streams past this are pruned of the streams that are done; what's left is bounded by
+// the transport's stream limits.
+const H3_STREAMS_PRUNE: usize = 256;
+
+// draft-07 and later
+const SETTINGS_WEBTRANSPORT_MAX_SESSIONS: u64 = 0xc671706a;
+// draft-02, still what chrome looks for
+const SETTINGS_ENABLE_WEBTRANSPORT: u64 = 0x2b603742;
+
+// WEBTRANSPORT_SESSION_GONE, for streams whose session went away
+const SESSION_GONE: u64 = 0x170d7b68;
+const H3_REQUEST_REJECTED: u64 = 0x10B;
+
+pub fn h3_config() -> quiche::h3::Config {
+    let mut config = quiche::h3::Config::new().unwrap();
+    config.enable_extended_connect(true);
+    // the client's QPACK streams may be read by us instead of h3, see the top
+    config.set_qpack_max_table_capacity(0);
+    config.set_qpack_blocked_streams(0);
+    config
+        .set_additional_settings(vec![
+            (SETTINGS_WEBTRANSPORT_MAX_SESSIONS, 1),
+            (SETTINGS_ENABLE_WEBTRANSPORT, 1),
+        ])
+        .unwrap();
+    config
+}
+
+// the rpc side of a connection's streams.
+pub trait StreamHandler {
+    // data arrived on a client stream. the stream id is the quic stream id; replies go back
+    // on the same stream when it is bidirectional. `replayable` means some of the stream came
+    // in 0-rtt data and may be a replay, see early.rs.
+    fn handle_read(
+        &mut self,
+        stream_id: u64,
+        buf: &[u8],
+        fin: bool,
+        replayable: bool,
+        out: &mut Replies,
+    );
+
+    // the handshake completed; nothing read after this is replayable, and nothing before it
+    // was a replay.
+    fn handshake_done(&mut self, _out: &mut Replies) {}
+
+    // the client reset or abandoned the stream.
+    fn handle_reset(&mut self, _stream_id: u64) {}
+
+    fn handle_datagram(&mut self, _session_id: u64, _buf: &[u8], _out: &mut Replies) {}
+
+    // the client asked for a session with these CONNECT headers. this is where the protocol
+    // is negotiated: add what to answer with to `response`, or return the status to refuse
+    // the session with.
+    fn open_session(
+        &mut self,
+        _request: &[quiche::h3::Header],
+        _response: &mut Vec<quiche::h3::Header>,
+    ) -> Result<(), u16> {
+        Ok(())
+    }
+
+    // called before the connection sends, for results nobody asked for this time around:
+    // subscriptions, presence, telemetry.
+    fn poll(&mut self, _out: &mut Replies) {}
+}
+
+// what a handler wants sent.
+#[derive(Default)]
+pub struct Replies {
+    pub stream: Vec<(u64, Vec<u8>, bool)>,
+    pub datagram: Vec<Unreliable>,
+    // (stream id, application error code)
+    pub reset: Vec<(u64, u32)>,
+    pub priority: Vec<(u64, Priority)>,
+}
+
+// a reply that may be lost. when datagrams weren't negotiated, or this one is too big for a
+// packet, it goes on the fallback stream if there is one and is dropped otherwise. the bytes
+// are the same either way, so they must make sense on a stream: framing is up to the rpc layer.
+pub struct Unreliable {
+    pub session_id: u64,
+    pub fallback: Option<u64>,
+    pub data: Vec<u8>,
+}
+
+impl Replies {
+    pub fn send(&mut self, stream_id: u64, buf: &[u8], fin: bool) {
+        self.stream.push((stream_id, buf.to_vec(), fin));
+    }
+    // fire and forget
+    pub fn send_datagram(&mut self, session_id: u64, buf: &[u8]) {
+        self.datagram.push(Unreliable {
+            session_id,
+            fallback: None,
+            data: buf.to_vec(),
+        });
+    }
+    // a datagram if it fits, else reliably on `stream_id`
+    pub fn send_datagram_or_stream(&mut self, session_id: u64, stream_id: u64, buf: &[u8]) {
+        self.datagram.push(Unreliable {
+            session_id,
+            fallback: Some(stream_id),
+            data: buf.to_vec(),
+        });
+    }
+    // abandon a stream in both directions
+    pub fn reset(&mut self, stream_id: u64, code: u32) {
+        self.reset.push((stream_id, code));
+    }
+    // where the stream's replies go relative to the connection's others, see priority.rs.
+    // a procedure sets this before its first reply; unset streams get the RFC default, u=3.
+    pub fn prioritize(&mut self, stream_id: u64, urgency: u8, incremental: bool) {
+        self.priority
+            .push((stream_id, Priority::new(urgency, incremental)));
+    }
+    fn is_empty(&self) -> bool {
+        self.stream.is_empty()
+            && self.datagram.is_empty()
+            && self.reset.is_empty()
+            && self.priority.is_empty()
+    }
+}
+
+// makes the handler of each connection on one worker, see MyConfig::stream_handler. it stays
+// on its worker, so it may own what that worker's connections share. a closure will do when
+// that's all it does.
+pub trait HandlerFactory {
+    fn handler(&mut self) -> Box<dyn StreamHandler>;
+
+    // how to wake the worker from elsewhere, e.g. when work finished on another thread
+    fn set_waker(&mut self, _waker: Arc<mio::Waker>) {}
+
+    // called before the worker's connections are polled for sending, with none of their
+    // handlers borrowed: for the worker's own work, whose results the handlers then send.
+    fn poll(&mut self) {}
+}
+
+impl<F: FnMut() -> Box<dyn StreamHandler>> HandlerFactory for F {
+    fn handler(&mut self) -> Box<dyn StreamHandler> {
+        self()
+    }
+}
+
+pub type StreamHandlers = Box<dyn HandlerFactory>;
+
+// the default when nothing is registered: refuse every stream.
+pub struct NoHandler;
+impl StreamHandler for NoHandler {
+    fn handle_read(
+        &mut self,
+        stream_id: u64,
+        _buf: &[u8],
+        _fin: bool,
+        _replayable: bool,
+        out: &mut Replies,
+    ) {
+        out.send(stream_id, &[], true);
+    }
+}
+
+struct Session {
+    // not every client waits for the settings before sending CONNECT; we can't answer until
+    // we know whether they speak datagrams.
+    accepted: bool,
+    // what the handler answered the CONNECT with
+    refused: Option<u16>,
+    headers: Vec<quiche::h3::Header>,
+}
+
+enum WtStream {
+    // still reading the signal and session id
+    Header(Vec<u8>),
+    Open { session_id: u64 },
+    // a uni stream of h3's, read before we knew, see the top
+    Drain,
+}
+
+// bytes a stream couldn't take yet
+struct Outgoing {
+    buf: Vec<u8>,
+    fin: bool,
+}
+
+pub struct WebTransportConn {
+    h3_conn: quiche::h3::Connection,
+    handler: Box<dyn StreamHandler>,
+    largest_processed_request: u64,
+    // keyed by the CONNECT stream id
+    sessions: HashMap<u64, Session>,
+    streams: HashMap<u64, WtStream>,
+    // client streams that were handed to h3 and must be left alone
+    h3_streams: HashSet<u64>,
+    outgoing: HashMap<u64, Outgoing>,
+    // the order outgoing bytes and responses are written in
+    priorities: Scheduler,
+    // streams that started in 0-rtt data
+    replayable: HashSet<u64>,
+    established: bool,
+}
+
+impl WebTransportConn {
+    pub fn with_conn(
+        conn: &mut quiche::Connection,
+        handler: Box<dyn StreamHandler>,
+    ) -> std::result::Result<Box<dyn HttpConn>, String> {
+        let h3_conn = quiche::h3::Connection::with_transport(conn, &h3_config())
+            .map_err(|e| format!("Unable to create HTTP/3 connection: {e}"))?;
+        Ok(Box::new(Self {
+            h3_conn,
+            handler,
+            largest_processed_request: 0,
+            sessions: HashMap::new(),
+            streams: HashMap::new(),
+            h3_streams: HashSet::new(),
+            outgoing: HashMap::new(),
+            priorities: Scheduler::default(),
+            replayable: HashSet::new(),
+            established: false,
+        }))
+    }
+
+    fn end_stream(&mut self, stream_id: u64) {
+        self.streams.remove(&stream_id);
+        self.replayable.remove(&stream_id);
+    }
+
+    // nothing more will be written to the stream
+    fn end_write(&mut self, stream_id: u64) {
+        self.outgoing.remove(&stream_id);
+        self.priorities.remove(stream_id);
+    }
+
+    fn session_up(&self) -> bool {
+        self.sessions.values().any(|s| s.accepted)
+    }
+
+    // read the client streams that belong to webtransport before h3 sees them.
+    fn claim_streams(&mut self, conn: &mut quiche::Connection, buf: &mut [u8]) {
+        let mut out = Replies::default();
+        for stream_id in conn.readable() {
+            // server initiated streams are never readable by a client's hand
+            if stream_id & 1 == 1 || self.h3_streams.contains(&stream_id) {
+                continue;
+            }
+            if !self.streams.contains_key(&stream_id) {
+                if !self.session_up() {
+                    if self.h3_streams.len() >= H3_STREAMS_PRUNE {
+                        self.h3_streams.retain(|&id| !conn.stream_finished(id));
+                    }
+                    self.h3_streams.insert(stream_id);
+                    continue;
+                }
+                self.streams.insert(stream_id, WtStream::Header(Vec::new()));
+            }
+            self.read_stream(conn, stream_id, buf, &mut out);
+        }
+        self.flush(conn, out);
+    }
+
+    fn read_stream(
+        &mut self,
+        conn: &mut quiche::Connection,
+        stream_id: u64,
+        buf: &mut [u8],
+        out: &mut Replies,
+    ) {
+        loop {
+            let (len, fin) = match conn.stream_recv(stream_id, buf) {
+                Ok(v) => v,
+                Err(quiche::Error::Done) => return,
+                Err(e) => {
+                    debug!(
+                        "{} webtransport stream {} gone: {:?}",
+                        conn.trace_id(),
+                        stream_id,
+                        e
+                    );
+                    self.end_stream(stream_id);
+                    self.end_write(stream_id);
+                    self.handler.handle_reset(stream_id);
+                    return;
+                }
+            };
+            if conn.is_in_early_data() {
+                self.replayable.insert(stream_id);
+            }
+            let replayable = self.replayable.contains(&stream_id);
+            let Some(stream) = self.streams.get_mut(&stream_id) else {
+                return;
+            };
+            let data = match stream {
+                WtStream::Open { .. } => &buf[..len],
+                WtStream::Drain => {
+                    if fin {
+                        self.end_stream(stream_id);
+                        return;
+                    }
+                    continue;
+                }
+                WtStream::Header(prefix) => {
+                    prefix.extend_from_slice(&buf[..len]);
+                    let mut b = octets::Octets::with_slice(prefix);
+                    let uni = stream_id & 2 != 0;
+                    let signal = b.get_varint();
+                    if uni && signal.is_ok_and(|s| s != WEBTRANSPORT_UNI) {
+                        trace!("{} uni stream {} is h3's", conn.trace_id(), stream_id);
+                        *stream = WtStream::Drain;
+                        if fin {
+                            self.end_stream(stream_id);
+                            return;
+                        }
+                        continue;
+                    }
+                    let (signal, session_id) = match (signal, b.get_varint()) {
+                        (Ok(signal), Ok(session_id)) => (signal, session_id),
+                        _ if fin => {
+                            self.end_stream(stream_id);
+                            return;
+                        }
+                        _ => continue,
+                    };
+                    let expected = match uni {
+                        false => WEBTRANSPORT_BIDI,
+                        true => WEBTRANSPORT_UNI,
+                    };
+                    if signal != expected || !self.sessions.contains_key(&session_id) {
+                        debug!(
+                            "{} stream {} is not for a live session",
+                            conn.trace_id(),
+                            stream_id
+                        );
+                        self.end_stream(stream_id);
+                        _ = conn.stream_shutdown(stream_id, quiche::Shutdown::Read, SESSION_GONE);
+                        return;
+                    }
+                    let rest = prefix[b.off()..].to_vec();
+                    *stream = WtStream::Open { session_id };
+                    self.handler
+                        .handle_read(stream_id, &rest, fin, replayable, out);
+                    if fin {
+                        self.end_stream(stream_id);
+                        return;
+                    }
+                    continue;
+                }
+            };
+            self.handler
+                .handle_read(stream_id, data, fin, replayable, out);
+            if fin {
+                self.end_stream(stream_id);
+                return;
+            }
+        }
+    }
+
+    fn handle_connect(
+        &mut self,
+        conn: &mut quiche::Connection,
+        stream_id: u64,
+        headers: &[quiche::h3::Header],
+    ) -> bool {
+        let mut protocol = None;
+        let mut method = None;
+        for hdr in headers {
+            match hdr.name() {
+                b":protocol" => protocol = Some(hdr.value()),
+                b":method" => method = Some(hdr.value()),
+                _ => (),
+            }
+        }
+        if method != Some(b"CONNECT") || protocol.is_none() {
+            return false;
+        }
+        if protocol != Some(b"webtransport") || !self.sessions.is_empty() {
+            // one session per connection, see above.
+            _ = conn.stream_shutdown(stream_id, quiche::Shutdown::Write, H3_REQUEST_REJECTED);
+            return true;
+        }
+        let mut response = Vec::new();
+        let refused = self.handler.open_session(headers, &mut response).err();
+        self.sessions.insert(
+            stream_id,
+            Session {
+                accepted: false,
+                refused,
+                headers: response,
+            },
+        );
+        self.accept_sessions(conn);
+        true
+    }
+
+    fn accept_sessions(&mut self, conn: &mut quiche::Connection) {
+        if self.h3_conn.peer_settings_raw().is_none() {
+            return;
+        }
+        let mut refused = Vec::new();
+        for (&stream_id, session) in self.sessions.iter_mut() {
+            if session.accepted {
+                continue;
+            }
+            let status = session.refused.unwrap_or(200).to_string();
+            let mut headers = vec![
+                quiche::h3::Header::new(b":status", status.as_bytes()),
+                quiche::h3::Header::new(b"sec-webtransport-http3-draft", b"draft02"),
+            ];
+            headers.extend(session.headers.iter().cloned());
+            let fin = session.refused.is_some();
+            match self.h3_conn.send_response(conn, stream_id, &headers, fin) {
+                Ok(()) if fin => {
+                    info!(
+                        "{} webtransport session {} refused with {}",
+                        conn.trace_id(),
+                        stream_id,
+                        status
+                    );
+                    refused.push(stream_id);
+                }
+                Ok(()) => {
+                    info!(
+                        "{} webtransport session {} open",
+                        conn.trace_id(),
+                        stream_id
+                    );
+                    session.accepted = true;
+                }
+                Err(quiche::h3::Error::StreamBlocked) => (),
+                Err(e) => error!("{} webtransport accept failed {:?}", conn.trace_id(), e),
+            }
+        }
+        // the client may try again on the same connection
+        for stream_id in refused {
+            self.sessions.remove(&stream_id);
+        }
+    }
+
+    fn close_session(&mut self, conn: &mut quiche::Connection, session_id: u64) {
+        if self.sessions.remove(&session_id).is_none() {
+            return;
+        }
+        info!(
+            "{} webtransport session {} closed",
+            conn.trace_id(),
+            session_id
+        );
+        let gone = self
+            .streams
+            .iter()
+            .filter(|(_, s)| matches!(s, WtStream::Open { session_id: id } if *id == session_id))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for stream_id in gone {
+            self.end_stream(stream_id);
+            self.end_write(stream_id);
+            self.handler.handle_reset(stream_id);
+            _ = conn.stream_shutdown(stream_id, quiche::Shutdown::Read, SESSION_GONE);
+            _ = conn.stream_shutdown(stream_id, quiche::Shutdown::Write, SESSION_GONE);
+        }
+        _ = conn.stream_send(session_id, &[], true);
+    }
+
+    fn recv_datagrams(&mut self, conn: &mut quiche::Connection, buf: &mut [u8]) {
+        let mut out = Replies::default();
+        while let Ok(len) = conn.dgram_recv(buf) {
+            // a datagram can be lost anyway, so one that might be a replay is simply dropped.
+            if conn.is_in_early_data() {
+                continue;
+            }
+            let mut b = octets::Octets::with_slice(&buf[..len]);
+            let Ok(quarter) = b.get_varint() else {
+                continue;
+            };
+            let session_id = quarter * 4;
+            if !self.sessions.contains_key(&session_id) {
+                continue;
+            }
+            self.handler
+                .handle_datagram(session_id, &buf[b.off()..len], &mut out);
+        }
+        self.flush(conn, out);
+    }
+
+    fn flush(&mut self, conn: &mut quiche::Connection, out: Replies) {
+        if out.is_empty() {
+            return;
+        }
+        for (stream_id, p) in out.priority {
+            self.priorities.set(conn, stream_id, p);
+        }
+        for (stream_id, code) in out.reset {
+            self.end_stream(stream_id);
+            self.end_write(stream_id);
+            _ = conn.stream_shutdown(stream_id, quiche::Shutdown::Read, app_error(code));
+            _ = conn.stream_shutdown(stream_id, quiche::Shutdown::Write, app_error(code));
+        }
+        for (stream_id, data, fin) in out.stream {
+            self.queue(conn, stream_id, data, fin);
+        }
+        for d in out.datagram {
+            if self.send_datagram(conn, &d) {
+                continue;
+            }
+            if let Some(stream_id) = d.fallback {
+                self.queue(conn, stream_id, d.data, false);
+            }
+        }
+        self.write_streams(conn, None);
+    }
+
+    // behind whatever the stream already has waiting
+    fn queue(&mut self, conn: &mut quiche::Connection, stream_id: u64, data: Vec<u8>, fin: bool) {
+        if !self.priorities.contains(stream_id) {
+            self.priorities.set(conn, stream_id, Priority::default());
+        }
+        match self.outgoing.get_mut(&stream_id) {
+            Some(o) => {
+                o.buf.extend_from_slice(&data);
+                o.fin |= fin;
+            }
+            None => {
+                self.outgoing.insert(stream_id, Outgoing { buf: data, fin });
+            }
+        }
+    }
+
+    // write what is waiting, most urgent first, until this round's budget is used up.
+    fn write_streams(
+        &mut self,
+        conn: &mut quiche::Connection,
+        mut partial_responses: Option<&mut HashMap<u64, PartialResponse>>,
+    ) {
+        let responses = partial_responses.iter().flat_map(|r| r.keys().copied());
+        let ids = self
+            .priorities
+            .order(self.outgoing.keys().copied().chain(responses));
+        let mut budget = priority::budget(conn);
+        for stream_id in ids {
+            if budget == 0 {
+                break;
+            }
+            let written = match partial_responses.as_deref_mut() {
+                Some(r) if r.contains_key(&stream_id) => {
+                    self.write_response(conn, r, stream_id, budget)
+                }
+                _ => self.send_outgoing(conn, stream_id, budget),
+            };
+            if written > 0 {
+                self.priorities.served(stream_id);
+            }
+            budget = budget.saturating_sub(written);
+        }
+    }
+
+    // false if it should go on a stream instead. a full send queue is not a reason to fall
+    // back: the datagram is just lost, like it could have been on the wire.
+    fn send_datagram(&mut self, conn: &mut quiche::Connection, d: &Unreliable) -> bool {
+        let len = octets::varint_len(d.session_id / 4) + d.data.len();
+        let fits = match conn.dgram_max_writable_len() {
+            Some(max) => len <= max,
+            None => false,
+        };
+        if !fits || !self.h3_conn.dgram_enabled_by_peer(conn) {
+            trace!(
+                "{} datagram of {} bytes goes on a stream",
+                conn.trace_id(),
+                len
+            );
+            return false;
+        }
+        if let Err(e) = send_h3_dgram(conn, d.session_id / 4, &d.data) {
+            debug!("{} datagram dropped: {:?}", conn.trace_id(), e);
+        }
+        true
+    }
+
+    // up to `limit` bytes of what the stream has waiting
+    fn send_outgoing(
+        &mut self,
+        conn: &mut quiche::Connection,
+        stream_id: u64,
+        limit: usize,
+    ) -> usize {
+        let Some(o) = self.outgoing.get_mut(&stream_id) else {
+            return 0;
+        };
+        let len = o.buf.len().min(limit);
+        let fin = o.fin && len == o.buf.len();
+        let written = match conn.stream_send(stream_id, &o.buf[..len], fin) {
+            Ok(v) => v,
+            Err(quiche::Error::Done) => return 0,
+            Err(e) => {
+                debug!(
+                    "{} stream {} send failed {:?}",
+                    conn.trace_id(),
+                    stream_id,
+                    e
+                );
+                self.end_write(stream_id);
+                return 0;
+            }
+        };
+        o.buf.drain(..written);
+        if o.buf.is_empty() {
+            if o.fin {
+                self.end_write(stream_id);
+            } else {
+                self.outgoing.remove(&stream_id);
+            }
+        }
+        written
+    }
+
+    // plain HTTP/3, same as Http3Conn
+    fn handle_request(
+        &mut self,
+        conn: &mut quiche::Connection,
+        stream_id: u64,
+        list: &[quiche::h3::Header],
+        partial_responses: &mut HashMap<u64, PartialResponse>,
+        root: &str,
+        index: &str,
+    ) {
+        _ = conn.stream_shutdown(stream_id, quiche::Shutdown::Read, 0);
+        let (headers, body, priority) = match Http3Conn::build_h3_response(root, index, list) {
+            Ok(v) => v,
+            Err((error_code, _)) => {
+                _ = conn.stream_shutdown(stream_id, quiche::Shutdown::Write, error_code);
+                return;
+            }
+        };
+        // a PRIORITY_UPDATE that got here first wins over the header
+        let priority = match self.h3_conn.take_last_priority_update(stream_id) {
+            Ok(v) => Priority::parse(&v),
+            Err(_) => Priority::parse(&priority),
+        };
+        self.priorities.set(conn, stream_id, priority);
+        let headers = match self.h3_conn.send_response_with_priority(
+            conn,
+            stream_id,
+            &headers,
+            &priority.h3(),
+            false,
+        ) {
+            Ok(()) => None,
+            Err(quiche::h3::Error::StreamBlocked) => Some(headers),
+            Err(e) => {
+                error!("{} stream send failed {:?}", conn.trace_id(), e);
+                return;
+            }
+        };
+        partial_responses.insert(
+            stream_id,
+            PartialResponse {
+                priority: headers.as_ref().map(|_| priority.h3()),
+                headers,
+                body,
+                written: 0,
+            },
+        );
+    }
+
+    // the rest of a static file response, up to `limit` bytes of body
+    fn write_response(
+        &mut self,
+        conn: &mut quiche::Connection,
+        partial_responses: &mut HashMap<u64, PartialResponse>,
+        stream_id: u64,
+        limit: usize,
+    ) -> usize {
+        let Some(resp) = partial_responses.get_mut(&stream_id) else {
+            return 0;
+        };
+        if let Some(headers) = &resp.headers {
+            let priority = self.priorities.get(stream_id).h3();
+            match self
+                .h3_conn
+                .send_response_with_priority(conn, stream_id, headers, &priority, false)
+            {
+                Ok(()) => (),
+                Err(quiche::h3::Error::StreamBlocked) => return 0,
+                Err(e) => {
+                    error!("{} stream send failed {:?}", conn.trace_id(), e);
+                    partial_responses.remove(&stream_id);
+                    self.priorities.remove(stream_id);
+                    return 0;
+                }
+            }
+        }
+        resp.headers = None;
+        resp.priority = None;
+
+        let body = &resp.body[resp.written..];
+        let len = body.len().min(limit);
+        let fin = len == body.len();
+        let written = match self.h3_conn.send_body(conn, stream_id, &body[..len], fin) {
+            Ok(v) => v,
+            Err(quiche::h3::Error::Done) => 0,
+            Err(e) => {
+                partial_responses.remove(&stream_id);
+                self.priorities.remove(stream_id);
+                error!("{} stream send failed {:?}", conn.trace_id(), e);
+                return 0;
+            }
+        };
+        resp.written += written;
+        if resp.written == resp.body.len() {
+            partial_responses.remove(&stream_id);
+            self.priorities.remove(stream_id);
+        }
+        written
+    }
+}
+
+impl HttpConn for WebTransportConn {
+    fn send_requests(&mut self, _conn: &mut quiche::Connection, _target_path: &Option<String>) {
+        unreachable!("server only");
+    }
+
+    fn handle_responses(
+        &mut self,
+        _conn: &mut quiche::Connection,
+        _buf: &mut [u8],
+        _req_start: &std::time::Instant,
+    ) {
+        unreachable!("server only");
+    }
+
+    fn report_incomplete(&self, _start: &std::time::Instant) -> bool {
+        false
+    }
+
+    fn handle_requests(
+        &mut self,
+        conn: &mut quiche::Connection,
+        _partial_requests: &mut HashMap<u64, PartialRequest>,
+        partial_responses: &mut HashMap<u64, PartialResponse>,
+        root: &str,
+        index: &str,
+        buf: &mut [u8],
+    ) -> quiche::h3::Result<()> {
+        if !self.established && conn.is_established() {
+            self.established = true;
+            let mut out = Replies::default();
+            self.handler.handshake_done(&mut out);
+            self.flush(conn, out);
+        }
+        self.claim_streams(conn, buf);
+
+        loop {
+            match self.h3_conn.poll(conn) {
+                Ok((stream_id, quiche::h3::Event::Headers { list, .. })) => {
+                    info!(
+                        "{} got request {:?} on stream id {}",
+                        conn.trace_id(),
+                        hdrs_to_strings(&list),
+                        stream_id
+                    );
+                    self.largest_processed_request =
+                        std::cmp::max(self.largest_processed_request, stream_id);
+                    if !self.handle_connect(conn, stream_id, &list) {
+                        self.handle_request(conn, stream_id, &list, partial_responses, root, index);
+                    }
+                }
+
+                // capsules on the session stream; nothing in them we act on.
+                Ok((stream_id, quiche::h3::Event::Data)) => {
+                    while self.h3_conn.recv_body(conn, stream_id, buf).is_ok() {}
+                }
+
+                Ok((stream_id, quiche::h3::Event::Finished)) => {
+                    self.close_session(conn, stream_id);
+                }
+
+                Ok((stream_id, quiche::h3::Event::Reset(_))) => {
+                    self.close_session(conn, stream_id);
+                }
+
+                // only for responses still being written; a request that hasn't come yet
+                // picks its update up in handle_request.
+                Ok((stream_id, quiche::h3::Event::PriorityUpdate)) => {
+                    if self.priorities.contains(stream_id) {
+                        if let Ok(v) = self.h3_conn.take_last_priority_update(stream_id) {
+                            self.priorities.set(conn, stream_id, Priority::parse(&v));
+                        }
+                    }
+                }
+
+                Ok((goaway_id, quiche::h3::Event::GoAway)) => {
+                    trace!("{} got GOAWAY with ID {} ", conn.trace_id(), goaway_id);
+                    self.h3_conn
+                        .send_goaway(conn, self.largest_processed_request)?;
+                }
+
+                Err(quiche::h3::Error::Done) => break,
+
+                Err(e) => {
+                    error!("{} HTTP/3 error {:?}", conn.trace_id(), e);
+                    return Err(e);
+                }
+            }
+        }
+
+        // the peer's settings may have arrived in this batch.
+        self.accept_sessions(conn);
+        self.recv_datagrams(conn, buf);
+        self.write_streams(conn, Some(partial_responses));
+        Ok(())
+    }
+
+    fn poll_pushes(&mut self, conn: &mut quiche::Connection) {
+        if self.session_up() {
+            let mut out = Replies::default();
+            self.handler.poll(&mut out);
+            self.flush(conn, out);
+        }
+        // whatever the last round's budget held back
+        self.write_streams(conn, None);
+    }
+
+    fn handle_writables(
+        &mut self,
+        conn: &mut quiche::Connection,
+        partial_responses: &mut HashMap<u64, PartialResponse>,
+    ) {
+        self.write_streams(conn, Some(partial_responses));
+    }
+
+    fn handle_writable(
+        &mut self,
+        conn: &mut quiche::Connection,
+        partial_responses: &mut HashMap<u64, PartialResponse>,
+        stream_id: u64,
+    ) {
+        if self.outgoing.contains_key(&stream_id) {
+            self.send_outgoing(conn, stream_id, usize::MAX);
+        } else {
+            self.write_response(conn, partial_responses, stream_id, usize::MAX);
+        }
+    }
+}
+
+// webtransport application error codes live in a reserved h3 range that skips the grease
+// values.
+fn app_error(code: u32) -> u64 {
+    let code = code as u64;
+    APP_ERROR_FIRST + code + code / 0x1e
+}
+
+// the other way, for the client. None for codes outside the range and for grease.
+pub fn app_error_code(h3: u64) -> Option<u32> {
+    let shifted = h3.checked_sub(APP_ERROR_FIRST)?;
+    if shifted % 0x1f == 0x1e {
+        return None;
+    }
+    u32::try_from(shifted - shifted / 0x1f).ok()
+}
+
+const APP_ERROR_FIRST: u64 = 0x52e4a40fa8db;
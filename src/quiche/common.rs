@@ -154,19 +154,12 @@ pub fn make_qlog_writer(
     dir: &std::ffi::OsStr,
     role: &str,
     id: &str,
-) -> std::io::BufWriter<std::fs::File> {
+) -> std::io::Result<std::io::BufWriter<std::fs::File>> {
     let mut path = std::path::PathBuf::from(dir);
     let filename = format!("{role}-{id}.sqlog");
     path.push(filename);
 
-    match std::fs::File::create(&path) {
-        Ok(f) => std::io::BufWriter::new(f),
-
-        Err(e) => panic!(
-            "Error creating qlog file attempted path was {:?}: {}",
-            path, e
-        ),
-    }
+    std::fs::File::create(&path).map(std::io::BufWriter::new)
 }
 
 fn dump_json(reqs: &[Http3Request], output_sink: &mut dyn FnMut(String)) {
@@ -318,7 +311,7 @@ pub fn priority_from_query_string(url: &url::Url) -> Option<Priority> {
     }
 }
 
-fn send_h3_dgram(
+pub fn send_h3_dgram(
     conn: &mut quiche::Connection,
     flow_id: u64,
     dgram_content: &[u8],
@@ -368,6 +361,20 @@ pub trait HttpConn {
         partial_responses: &mut HashMap<u64, PartialResponse>,
         stream_id: u64,
     );
+
+    // a chance to send something no request asked for, just before the connection sends.
+    fn poll_pushes(&mut self, _conn: &mut quiche::Connection) {}
+
+    // write whatever the writable streams have waiting, in the order the connection wants.
+    fn handle_writables(
+        &mut self,
+        conn: &mut quiche::Connection,
+        partial_responses: &mut HashMap<u64, PartialResponse>,
+    ) {
+        for stream_id in writable_response_streams(conn) {
+            self.handle_writable(conn, partial_responses, stream_id);
+        }
+    }
 }
 
 pub fn writable_response_streams(conn: &quiche::Connection) -> impl Iterator<Item = u64> {
@@ -899,7 +906,7 @@ impl Http3Conn {
     }
 
     /// Builds an HTTP/3 response given a request.
-    fn build_h3_response(
+    pub(crate) fn build_h3_response(
         root: &str,
         index: &str,
         request: &[quiche::h3::Header],
@@ -1,4 +1,4 @@
-use std::{future::Future, net};
+use std::future::Future;
 use log::*;
 
 use super::Client;
@@ -6,31 +6,6 @@ use super::Client;
 
 
 
-pub fn validate_token<'a>(
-    src: &net::SocketAddr, token: &'a [u8],
-) -> Option<quiche::ConnectionId<'a>> {
-    if token.len() < 6 {
-        return None;
-    }
-
-    if &token[..6] != b"quiche" {
-        return None;
-    }
-
-    let token = &token[6..];
-
-    let addr = match src.ip() {
-        std::net::IpAddr::V4(a) => a.octets().to_vec(),
-        std::net::IpAddr::V6(a) => a.octets().to_vec(),
-    };
-
-    if token.len() < addr.len() || &token[..addr.len()] != addr.as_slice() {
-        return None;
-    }
-
-    Some(quiche::ConnectionId::from_ref(&token[addr.len()..]))
-}
-
 pub fn handle_path_events(client: &mut Client) {
     while let Some(qe) = client.conn.path_event_next() {
         match qe {
@@ -42,11 +17,8 @@ pub fn handle_path_events(client: &mut Client) {
                     peer_addr
                 );
 
-                // Directly probe the new path.
-                client
-                    .conn
-                    .probe_path(local_addr, peer_addr)
-                    .expect("cannot probe");
+                // quiche has already queued a PATH_CHALLENGE for it, and holds it to the
+                // anti-amplification limit until the peer answers.
             },
 
             quiche::PathEvent::Validated(local_addr, peer_addr) => {
@@ -105,7 +77,7 @@ pub fn handle_path_events(client: &mut Client) {
 ///
 /// Note that this socket option is set only on linux platforms.
 #[cfg(target_os = "linux")]
-pub fn set_txtime_sockopt(sock: &mio::net::UdpSocket) -> io::Result<()> {
+pub fn set_txtime_sockopt(sock: &mio::net::UdpSocket) -> std::io::Result<()> {
     use nix::sys::socket::setsockopt;
     use nix::sys::socket::sockopt::TxTime;
     use std::os::unix::io::AsRawFd;
@@ -126,27 +98,3 @@ pub fn set_txtime_sockopt(_: &mio::net::UdpSocket) -> std::io::Result<()> {
 
     Err(Error::other("Not supported on this platform"))
 }
-
-/// Generate a stateless retry token.
-///
-/// The token includes the static string `"quiche"` followed by the IP address
-/// of the client and by the original destination connection ID generated by the
-/// client.
-///
-/// Note that this function is only an example and doesn't do any cryptographic
-/// authenticate of the token. *It should not be used in production system*.
-pub fn mint_token(hdr: &quiche::Header, src: &net::SocketAddr) -> Vec<u8> {
-    let mut token = Vec::new();
-
-    token.extend_from_slice(b"quiche");
-
-    let addr = match src.ip() {
-        std::net::IpAddr::V4(a) => a.octets().to_vec(),
-        std::net::IpAddr::V6(a) => a.octets().to_vec(),
-    };
-
-    token.extend_from_slice(&addr);
-    token.extend_from_slice(&hdr.dcid);
-
-    token
-}
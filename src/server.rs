@@ -1,6 +1,13 @@
-use std::{future::Future, net::SocketAddr, path::Path, pin::Pin, sync::Arc};
+use std::{
+    future::Future,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+};
 
 use crate::error::Result;
+use log::*;
 use mio::{
     net::{TcpListener, UdpSocket},
     Events, Interest, Poll, Token,
@@ -9,13 +16,54 @@ use rustls::ServerConfig;
 //use s2n_quic::provider::dc::Path;
 use slab::Slab;
 
+use crate::admin::Admin;
+use crate::channel::Endpoint;
+use crate::diag::Diagnostics;
+use crate::quic::{
+    altsvc::AltSvc,
+    retry::RetryPolicy,
+    udp::{Pacing, UdpIo},
+    webtransport::{NoHandler, StreamHandler, StreamHandlers},
+    Datagram, QuicKeys, QuicListener,
+};
 use crate::tls::TlsClient;
 const SERVER_TOKEN: Token = Token(usize::MAX);
 const UDP_TOKEN: Token = Token(usize::MAX - 1);
+const CHANNEL_TOKEN: Token = Token(usize::MAX - 2);
+const WAKER_TOKEN: Token = Token(usize::MAX - 3);
 
 pub struct MyConfig {
     pub threads: usize,
     pub host: String,
+    // shared by the tls listener and quic
+    pub cert: String,
+    pub key: String,
+    // serve quic on the same port as tls
+    pub quic: bool,
+    // static files for plain HTTP/3 requests
+    pub root: String,
+    // when new quic connections must prove their address with a retry
+    pub retry: RetryPolicy,
+    // let quic clients move to a new address without reconnecting
+    pub migration: bool,
+    // cubic, reno or bbr2
+    pub congestion: quiche::CongestionControlAlgorithm,
+    pub pacing: Pacing,
+    // how the quic listener reads and writes its socket
+    pub udp_io: UdpIo,
+    // what the quic keys are derived from. set it so connection ids, tokens, tickets and reset
+    // tokens outlive a restart (and agree across processes on one address); without it every
+    // start has fresh keys, and clients of the old process wait out their idle timeout.
+    pub quic_secret: Option<Vec<u8>>,
+    // how tls clients learn about the quic listener
+    pub alt_svc: Arc<AltSvc>,
+    // qlog and keylog for the clients an admin is watching
+    pub diagnostics: Arc<Diagnostics>,
+    // where the admin socket goes, if there is one; see admin
+    pub admin_socket: Option<PathBuf>,
+    // called on each worker, with its index, for what makes the handlers of its webtransport
+    // connections. this is where rpc plugs in, see exec::serve.
+    pub stream_handler: Arc<dyn Fn(usize) -> StreamHandlers + Send + Sync>,
 }
 impl Default for MyConfig {
     fn default() -> Self {
@@ -23,6 +71,22 @@ impl Default for MyConfig {
         MyConfig {
             threads: cpu_count,
             host: "127.0.0.1:8444".to_string(),
+            cert: "cert.pem".to_string(),
+            key: "key.pem".to_string(),
+            quic: true,
+            root: "examples/root".to_string(),
+            retry: RetryPolicy::default(),
+            migration: true,
+            congestion: quiche::CongestionControlAlgorithm::CUBIC,
+            pacing: Pacing::Auto,
+            udp_io: UdpIo::Mio,
+            quic_secret: None,
+            alt_svc: Arc::new(AltSvc::default()),
+            diagnostics: Arc::new(Diagnostics::default()),
+            admin_socket: None,
+            stream_handler: Arc::new(|_| {
+                Box::new(|| -> Box<dyn StreamHandler> { Box::new(NoHandler) })
+            }),
         }
     }
 }
@@ -32,11 +96,20 @@ struct Task {
     woken: bool, // optionally, to dedup wakeups
 }
 
+// sent between workers over the channel matrix
+pub enum WorkerMessage {
+    // a quic packet for a connection the receiving worker owns.
+    Datagram(Datagram),
+}
+
 // this is shared worker state; there is more thread local state in the run functions
 pub struct WorkerThread {
     // executor
     // tasks: Slab<Task>,
     // cpu_socket: usize,
+
+    // taken by the worker when it starts; it is the only producer/consumer for its mailboxes.
+    endpoint: std::sync::Mutex<Option<Endpoint<WorkerMessage>>>,
 }
 unsafe impl Sync for WorkerThread {}
 unsafe impl Send for WorkerThread {}
@@ -48,6 +121,8 @@ pub struct Server {
     config: MyConfig,
     worker: Box<[WorkerThread]>,
     tls_config: Arc<ServerConfig>,
+    // shared so a token or connection id minted on one worker is understood by any.
+    quic_keys: QuicKeys,
 }
 static mut SERVER: *const Server = std::ptr::null();
 pub fn get_server() -> &'static Server {
@@ -58,6 +133,11 @@ pub struct Supervisor {
 }
 pub fn init_server(config: MyConfig) -> Supervisor {
     let server = Arc::new(Server::new(config).unwrap());
+    if let Some(path) = &server.config.admin_socket {
+        let config = &server.config;
+        let admin = Admin::new(config.diagnostics.clone(), config.alt_svc.clone());
+        Arc::new(admin).serve(path).expect("admin socket");
+    }
     let mut join_handle = Vec::with_capacity(server.worker.len());
     for id in 0..server.worker.len() {
         let server = server.clone();
@@ -96,24 +176,33 @@ impl Server {
         todo!()
     }
     pub fn new(config: MyConfig) -> std::io::Result<Self> {
-        let worker = (0..config.threads)
-            .map(|_| {
+        // quic connection ids have one byte for the owning worker.
+        assert!(config.threads <= 256, "at most 256 worker threads");
+        let worker = crate::channel::matrix(config.threads)?
+            .into_iter()
+            .map(|endpoint| {
                 WorkerThread {
                     // tasks: Slab::new(),
                     // cpu_socket: 0,                            // This will be set later
                     // clients: slab::Slab::with_capacity(1024), // Adjust capacity as needed
+                    endpoint: std::sync::Mutex::new(Some(endpoint)),
                 }
             })
             .collect::<Vec<_>>()
             .into_boxed_slice();
 
-        let tls_config = crate::crypto::pki::load_tls_config();
+        let tls_config = crate::crypto::pki::load_tls_config_from(&config.cert, &config.key);
+        let quic_keys = match &config.quic_secret {
+            Some(secret) => QuicKeys::from_secret(secret),
+            None => QuicKeys::generate()?,
+        };
         let o = Server {
             // for now assume one cpu socket.
             cores_per_socket: config.threads,
             config,
             worker,
             tls_config,
+            quic_keys,
         };
         Ok(o)
     }
@@ -158,7 +247,11 @@ impl Server {
                 let conn_fd = cqe.result();
                 if conn_fd >= 0 {
                     let stream = unsafe { std::net::TcpStream::from_raw_fd(conn_fd) };
-                    crate::linux::uring::uring_handle_tls(stream, self.tls_config.clone());
+                    crate::linux::uring::uring_handle_tls(
+                        stream,
+                        self.tls_config.clone(),
+                        &self.config.alt_svc,
+                    );
                 }
             }
         }
@@ -172,7 +265,12 @@ impl Server {
         use socket2::{Domain, Socket, Type};
         use std::io::ErrorKind::Interrupted;
         use std::io::ErrorKind::WouldBlock;
-        _ = &self.worker[thread];
+        let mut endpoint = self.worker[thread]
+            .endpoint
+            .lock()
+            .unwrap()
+            .take()
+            .expect("worker started twice");
 
         // let tls = s2n_quic::provider::tls::default::Server::builder()
         //     .with_certificate(Path::new("cert.pem"), Path::new("key.pem"))?
@@ -183,21 +281,44 @@ impl Server {
         //     // .with_io(Mio::builder(poll.registry(), "0.0.0.0:4433")?)?
         //     .start()?;
 
+        // every worker binds the same port, the kernel spreads connections and datagrams.
         let addr: SocketAddr = self.config.host.parse().unwrap();
-        let socket = Socket::new(Domain::IPV4, Type::STREAM, None)?;
-        //socket.set_reuse_address(true)?;
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        socket.set_reuse_port(true)?;
         socket.bind(&addr.into())?;
         socket.listen(128)?;
         let mut listener = TcpListener::from_std(socket.into());
-        let mut udp_socket = UdpSocket::bind(addr)?;
 
         let mut poll = Poll::new()?;
         poll.registry()
             .register(&mut listener, SERVER_TOKEN, Interest::READABLE)?;
+
+        let mut quic = match self.config.quic {
+            true => {
+                let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+                socket.set_reuse_port(true)?;
+                socket.set_nonblocking(true)?;
+                socket.bind(&addr.into())?;
+                let udp_socket = UdpSocket::from_std(socket.into());
+                let mut quic = QuicListener::new(
+                    udp_socket,
+                    &self.config,
+                    thread,
+                    self.quic_keys.clone(),
+                )?;
+                quic.register(poll.registry(), UDP_TOKEN)?;
+                quic.set_waker(Arc::new(mio::Waker::new(poll.registry(), WAKER_TOKEN)?));
+                info!("QUIC server listening on https://{}", addr);
+                Some(quic)
+            }
+            false => None,
+        };
+        let channel_fd = endpoint.raw_fd();
         poll.registry().register(
-            &mut udp_socket,
-            UDP_TOKEN,
-            Interest::READABLE.add(Interest::WRITABLE),
+            &mut mio::unix::SourceFd(&channel_fd),
+            CHANNEL_TOKEN,
+            Interest::READABLE,
         )?;
 
         let mut events = Events::with_capacity(2048);
@@ -207,23 +328,45 @@ impl Server {
         println!("TLS server listening on https://{}", addr);
 
         loop {
-            match poll.poll(&mut events, None) {
+            // don't go to sleep if a peer managed to send something since we last looked.
+            let timeout = match endpoint.prepare_sleep() {
+                true => match quic.as_mut() {
+                    Some(quic) => quic.poll_timeout()?,
+                    None => None,
+                },
+                false => Some(std::time::Duration::ZERO),
+            };
+            let polled = poll.poll(&mut events, timeout);
+            endpoint.wakeup();
+            match polled {
                 Ok(_) => {}
                 Err(ref e) if e.kind() == Interrupted => continue,
                 Err(e) => return Err(e.into()),
             }
+            if let Some(quic) = quic.as_mut() {
+                quic.on_timeout();
+            }
+
+            while let Some((_, msg)) = endpoint.try_recv() {
+                match msg {
+                    WorkerMessage::Datagram(dgram) => {
+                        if let Some(quic) = quic.as_mut() {
+                            quic.recv_forwarded(dgram)?;
+                        }
+                    }
+                }
+            }
 
             for event in events.iter() {
                 println!("Got event: {:?}", event);
                 match event.token() {
+                    // the mailboxes were drained above.
+                    CHANNEL_TOKEN => {}
+                    // the handlers have something to send, which happens below
+                    WAKER_TOKEN => {}
                     UDP_TOKEN => {
-                        let mut buf = [0u8; 1500];
-                        match udp_socket.recv_from(&mut buf) {
-                            Ok((len, src)) => {
-                                // Process incoming QUIC packet, etc.
-                            }
-                            Err(ref e) if e.kind() == WouldBlock => {}
-                            Err(e) => return Err(e.into()),
+                        if let Some(quic) = quic.as_mut() {
+                            quic.recv()?;
                         }
                     }
                     SERVER_TOKEN => match listener.accept() {
@@ -236,7 +379,10 @@ impl Server {
                                 token,
                                 Interest::READABLE.add(Interest::WRITABLE),
                             )?;
-                            let client = TlsClient::new(stream, self.tls_config.clone());
+                            let tls_config =
+                                self.config.diagnostics.tls_config(&self.tls_config, &addr);
+                            let alt_svc = self.config.alt_svc.clone();
+                            let client = TlsClient::new(stream, tls_config, alt_svc);
                             entry.insert(client);
                         }
                         Err(ref e) if e.kind() == Interrupted => break,
@@ -265,6 +411,16 @@ impl Server {
                     }
                 }
             }
+
+            // flush whatever the timers and the packets we just read produced.
+            if let Some(quic) = quic.as_mut() {
+                // a full mailbox drops the packet, same as a full socket buffer would.
+                for (to, dgram) in quic.take_forwarded() {
+                    _ = endpoint.try_send(to, WorkerMessage::Datagram(dgram));
+                }
+                quic.send()?;
+                quic.collect_garbage();
+            }
         }
     }
 }
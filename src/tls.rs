@@ -10,9 +10,12 @@ use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 
+use crate::quic::altsvc::AltSvc;
+
 pub struct TlsClient {
     pub conn: ServerConnection,
     pub socket: TcpStream,
+    alt_svc: Arc<AltSvc>,
 }
 
 impl TlsClient {
@@ -21,15 +24,22 @@ impl TlsClient {
     //     TlsClient { conn, socket }
     // }
 
-    pub fn new(socket: TcpStream, config: Arc<ServerConfig>) -> Self {
+    pub fn new(socket: TcpStream, config: Arc<ServerConfig>, alt_svc: Arc<AltSvc>) -> Self {
         let conn = ServerConnection::new(config).unwrap();
-        TlsClient { conn, socket }
+        TlsClient {
+            conn,
+            socket,
+            alt_svc,
+        }
     }
 
     pub fn write_page(&mut self) -> std::io::Result<bool> {
-        let resp = b"HTTP/1.1 200 OK\r\nContent-Length: 13\r\n\r\nHello, world!";
+        let resp = format!(
+            "HTTP/1.1 200 OK\r\n{}Content-Length: 13\r\n\r\nHello, world!",
+            self.alt_svc.header()
+        );
         let writer = &mut self.conn.writer();
-        writer.write_all(resp)?;
+        writer.write_all(resp.as_bytes())?;
         writer.flush()?;
         _ = self.conn.write_tls(&mut self.socket)?;
         Ok(false) // Close after writing
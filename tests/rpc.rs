@@ -0,0 +1,279 @@
+// a procedure called end to end: the client, a quic listener on a worker, and dispatch.
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use mio::{net::UdpSocket, Events, Poll, Token, Waker};
+use quiche::h3::{Header, NameValue};
+use simpleweb::{
+    client::{Client, ClientConfig},
+    exec::{
+        self, registry::Registry, Connection, Db, DbThread, Iface, ParameterBlock, ProcFuture,
+        ProcResult, Ptr, StreamHeader, AUTOCOMMIT, CATALOG_HEADER, ERR_NO_PROC, VERSION_HEADER,
+    },
+    param::{IntEncoding, ParamBlockBuilder, ParamBlockSchema},
+    quic::{
+        early::{MAX_HELD, TOO_EARLY},
+        webtransport::Replies,
+        QuicKeys, QuicListener,
+    },
+    server::MyConfig,
+};
+
+const SCHEMA: ParamBlockSchema = ParamBlockSchema {
+    binary: 0,
+    varlen: 1,
+    stored: 0,
+    temp: 0,
+    integer: 1,
+};
+
+// the string, n times
+fn repeat(
+    _db: Ptr<Db>,
+    _thread: Ptr<DbThread>,
+    _connection: Ptr<Connection>,
+    pm: &ParameterBlock,
+) -> ProcResult {
+    let p = pm.params();
+    let n = p.u64(0).unwrap_or(0) as usize;
+    Ok(p.varlen.get(0).unwrap_or_default().repeat(n))
+}
+
+fn registry() -> (u32, Box<[Iface]>, u16, u16) {
+    let mut registry = Registry::new();
+    let (iface, proc) = registry
+        .register_procedure("test.text.repeat", SCHEMA, repeat)
+        .unwrap();
+    (registry.catalog(), registry.build(), iface, proc)
+}
+
+// one worker's quic listener, on a thread of its own
+fn listen(config: MyConfig) -> SocketAddr {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_nonblocking(true).unwrap();
+    let addr = socket.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let keys = QuicKeys::generate().unwrap();
+        let mut quic = QuicListener::new(UdpSocket::from_std(socket), &config, 0, keys).unwrap();
+        let mut poll = Poll::new().unwrap();
+        quic.register(poll.registry(), Token(0)).unwrap();
+        quic.set_waker(Arc::new(Waker::new(poll.registry(), Token(1)).unwrap()));
+        let mut events = Events::with_capacity(64);
+        loop {
+            let timeout = quic.poll_timeout().unwrap();
+            poll.poll(&mut events, timeout).unwrap();
+            quic.on_timeout();
+            quic.recv().unwrap();
+            quic.send().unwrap();
+            quic.collect_garbage();
+        }
+    });
+    addr
+}
+
+#[test]
+fn call_over_webtransport() {
+    let (catalog, iface, iface_id, proc_id) = registry();
+    let addr = listen(MyConfig {
+        threads: 1,
+        stream_handler: exec::serve(catalog, iface),
+        ..MyConfig::default()
+    });
+    let mut client = Client::connect(
+        addr,
+        &ClientConfig {
+            verify: false,
+            call_timeout: Duration::from_secs(5),
+            ..ClientConfig::default()
+        },
+    )
+    .unwrap();
+    let mut params = client.builder(&SCHEMA).unwrap();
+    params.u64(3).unwrap().str("ab").unwrap();
+    let call = client
+        .send_block(
+            StreamHeader::with(0, iface_id, proc_id, AUTOCOMMIT),
+            &params,
+        )
+        .unwrap();
+    let result: String = client.wait(call).unwrap();
+    assert_eq!(result, "ababab");
+    client.close();
+}
+
+// the statement, header and all
+fn statement(iface: u16, proc: u16) -> Vec<u8> {
+    let mut b = ParamBlockBuilder::new(&SCHEMA, IntEncoding::Fixed).unwrap();
+    b.u64(2).unwrap().str("x").unwrap();
+    let mut buf = StreamHeader::with(0, iface, proc, AUTOCOMMIT)
+        .to_bytes()
+        .to_vec();
+    b.write(&mut buf).unwrap();
+    buf
+}
+
+// the block is complete before fin, which comes on its own
+#[test]
+fn fin_after_the_block() {
+    let (catalog, iface, iface_id, proc_id) = registry();
+    let mut handlers = exec::serve(catalog, iface)(0);
+    let mut handler = handlers.handler();
+    let buf = statement(iface_id, proc_id);
+
+    let mut out = Replies::default();
+    handler.handle_read(0, &buf, false, false, &mut out);
+    handler.handle_read(0, &[], true, false, &mut out);
+    assert_eq!(out.stream, vec![(0, b"xx".to_vec(), true)]);
+    assert!(out.reset.is_empty());
+
+    // anything but fin after the block is an error
+    let mut out = Replies::default();
+    handler.handle_read(4, &buf, false, false, &mut out);
+    handler.handle_read(4, b"?", true, false, &mut out);
+    assert_eq!(out.stream, vec![(4, b"xx".to_vec(), true)]);
+    assert_eq!(out.reset.len(), 1);
+    assert_eq!(out.reset[0].0, 4);
+}
+
+// 0-rtt data: idempotent procedures run at once, the others wait for the handshake, and too
+// much waiting gets the stream rejected
+#[test]
+fn early_data() {
+    let mut registry = Registry::new();
+    let (iface, write) = registry
+        .register_procedure("test.text.write", SCHEMA, repeat)
+        .unwrap();
+    let (_, read) = registry
+        .register_procedure("test.text.read", SCHEMA, repeat)
+        .unwrap();
+    registry.set_idempotent("test.text.read").unwrap();
+    let mut handlers = exec::serve(registry.catalog(), registry.build())(0);
+    let mut handler = handlers.handler();
+
+    let mut out = Replies::default();
+    handler.handle_read(0, &statement(iface, read), true, true, &mut out);
+    assert_eq!(out.stream, vec![(0, b"xx".to_vec(), true)]);
+
+    let mut out = Replies::default();
+    let held = statement(iface, write);
+    handler.handle_read(4, &held[..3], false, true, &mut out);
+    handler.handle_read(4, &held[3..], true, true, &mut out);
+    assert!(out.stream.is_empty());
+
+    let big = vec![0; MAX_HELD];
+    handler.handle_read(8, &big, false, true, &mut out);
+    assert_eq!(out.reset, vec![(8, TOO_EARLY)]);
+
+    let mut out = Replies::default();
+    handler.handshake_done(&mut out);
+    assert_eq!(out.stream, vec![(4, b"xx".to_vec(), true)]);
+}
+
+// a procedure that is gone keeps its id, and a call to it fails as such whatever it sends
+#[test]
+fn removed_procedure() {
+    let mut old = Registry::new();
+    let (iface, gone) = old
+        .register_procedure("test.text.gone", SCHEMA, repeat)
+        .unwrap();
+    old.register_procedure("test.text.repeat", SCHEMA, repeat)
+        .unwrap();
+    let mut registry = Registry::with_ids(&old.export());
+    registry
+        .register_procedure("test.text.repeat", SCHEMA, repeat)
+        .unwrap();
+    let mut handlers = exec::serve(registry.catalog(), registry.build())(0);
+    let mut handler = handlers.handler();
+
+    let mut out = Replies::default();
+    handler.handle_read(0, &statement(iface, gone), true, false, &mut out);
+    assert!(out.stream.is_empty());
+    assert_eq!(out.reset, vec![(0, ERR_NO_PROC as u32)]);
+}
+
+// a client whose ids are from a later export than ours is refused, and told our catalogue
+#[test]
+fn newer_catalog() {
+    let (catalog, iface, _, _) = registry();
+    let mut handlers = exec::serve(catalog, iface)(0);
+    let mut handler = handlers.handler();
+    let header = |name: &str, value: &str| Header::new(name.as_bytes(), value.as_bytes());
+    let ours = catalog.to_string();
+    let newer = (catalog + 1).to_string();
+    for (theirs, status) in [
+        (None, Ok(())),
+        (Some(&*ours), Ok(())),
+        (Some(&*newer), Err(409)),
+    ] {
+        let mut request = vec![header(VERSION_HEADER, "1,2,3")];
+        request.extend(theirs.map(|c| header(CATALOG_HEADER, c)));
+        let mut response = Vec::new();
+        assert_eq!(handler.open_session(&request, &mut response), status);
+        let answered = response
+            .iter()
+            .find(|h| h.name() == CATALOG_HEADER.as_bytes());
+        assert_eq!(answered.map(|h| h.value()), Some(ours.as_bytes()));
+    }
+}
+
+// opened from another thread, and the waker of whoever waits for that
+static GATE: Mutex<(bool, Option<std::task::Waker>)> = Mutex::new((false, None));
+
+// its varlen parameter, once the gate is open
+fn gated(
+    _db: Ptr<Db>,
+    _thread: Ptr<DbThread>,
+    _connection: Ptr<Connection>,
+    pm: ParameterBlock,
+) -> ProcFuture {
+    let answer = pm.params().varlen.get(0).unwrap_or_default().to_vec();
+    Box::pin(std::future::poll_fn(move |cx| {
+        let mut gate = GATE.lock().unwrap();
+        if gate.0 {
+            return std::task::Poll::Ready(Ok(answer.clone()));
+        }
+        gate.1 = Some(cx.waker().clone());
+        std::task::Poll::Pending
+    }))
+}
+
+// a task woken from another thread wakes the worker's poll, and its result goes out when the
+// worker next polls its handlers
+#[test]
+fn task_wakes_the_worker() {
+    let mut registry = Registry::new();
+    let (iface, proc) = registry
+        .register_async_procedure("test.text.gated", SCHEMA, gated)
+        .unwrap();
+    let mut handlers = exec::serve(registry.catalog(), registry.build())(0);
+    let mut poll = Poll::new().unwrap();
+    handlers.set_waker(Arc::new(Waker::new(poll.registry(), Token(7)).unwrap()));
+    let mut handler = handlers.handler();
+
+    let mut out = Replies::default();
+    handler.handle_read(0, &statement(iface, proc), true, false, &mut out);
+    handlers.poll();
+    handler.poll(&mut out);
+    assert!(out.stream.is_empty());
+
+    std::thread::spawn(|| {
+        let mut gate = GATE.lock().unwrap();
+        gate.0 = true;
+        gate.1.take().unwrap().wake();
+    })
+    .join()
+    .unwrap();
+    let mut events = Events::with_capacity(4);
+    poll.poll(&mut events, Some(Duration::from_secs(5)))
+        .unwrap();
+    let tokens = events.iter().map(|e| e.token()).collect::<Vec<_>>();
+    assert_eq!(tokens, [Token(7)]);
+
+    handlers.poll();
+    handler.poll(&mut out);
+    assert_eq!(out.stream, vec![(0, b"x".to_vec(), true)]);
+}